@@ -1,8 +1,7 @@
 #![no_std]
-#![feature(asm, async_await, generators)]
+#![feature(async_await, generators)]
 #![allow(unused_must_use)]
 
-use core::cell::UnsafeCell;
 use core::fmt::Write;
 use core::future::Future;
 use core::str;
@@ -24,14 +23,12 @@ use tock::{
     console_read::ConsoleRead,
     console_write::{ConsoleWrite, ConsoleWriteStr},
     futures::{
-        AllocedFor, ButtonFutureAlloc, ConsoleReadFutureAlloc, ConsoleWriteFutureAlloc, FutureBox,
+        ButtonFutureAlloc, ConsoleReadFutureAlloc, ConsoleWriteFutureAlloc, Fallback, FutureBox,
     },
     led::Led,
-    syscalls,
 };
 
 use embrio_async::embrio_async;
-use embrio_executor;
 
 #[used]
 #[no_mangle]
@@ -44,6 +41,13 @@ pub static mut BSS: [u32; 64] = [0x0; 64];
 #[derive(Debug)]
 pub struct Error;
 
+// Console writes spill over into the read arena once their own is full
+type WriteFutureAlloc = Fallback<ConsoleWriteFutureAlloc, ConsoleReadFutureAlloc>;
+
+fn write_future_alloc() -> WriteFutureAlloc {
+    Fallback::new(ConsoleWriteFutureAlloc, ConsoleReadFutureAlloc)
+}
+
 #[embrio_async]
 async fn run3() -> Result<(), Error> {
     let button = Button::new();
@@ -62,8 +66,8 @@ async fn run3() -> Result<(), Error> {
             .write("Hello world\n".as_bytes())
             .map_err(|_| Error)?;
 
-        // FutureBox<impl Future, ConsoleWriteFutureAlloc>
-        let cw_fut_box = FutureBox::new(cw_fut, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite);
+        // FutureBox<impl Future, WriteFutureAlloc>
+        let cw_fut_box = FutureBox::try_new(cw_fut, write_future_alloc()).map_err(|_| Error)?;
 
         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
         let cw_local_future_obj = LocalFutureObj::new(cw_fut_box);
@@ -75,7 +79,7 @@ async fn run3() -> Result<(), Error> {
         (pinned_cw_local_future_obj.await).map_err(|_| Error)?;
 
         // FutureBox<StreamFuture<tock::button::Button>, ButtonFutureAlloc>
-        let b_fut_box = FutureBox::new(b_fut, ButtonFutureAlloc, AllocedFor::Button);
+        let b_fut_box = FutureBox::try_new(b_fut, ButtonFutureAlloc).map_err(|_| Error)?;
 
         // LocalFutureObj<'_, (Option<tock::button::ButtonEventData>, tock::button::Button)>
         let b_local_future_obj = LocalFutureObj::new(b_fut_box);
@@ -109,8 +113,8 @@ async fn run4() -> Result<(), Error> {
             .write("Enter 5 characters: ".as_bytes())
             .map_err(|_| Error)?;
 
-        // FutureBox<impl Future, ConsoleWriteFutureAlloc>
-        let cw_fut_box = FutureBox::new(cw_fut, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite);
+        // FutureBox<impl Future, WriteFutureAlloc>
+        let cw_fut_box = FutureBox::try_new(cw_fut, write_future_alloc()).map_err(|_| Error)?;
 
         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
         let cw_local_future_obj = LocalFutureObj::new(cw_fut_box);
@@ -125,7 +129,7 @@ async fn run4() -> Result<(), Error> {
         let cr_fut = console_read.read(5).map_err(|_| Error)?;
 
         // FutureBox<impl Future, ConsoleReadFutureAlloc>
-        let cr_fut_box = FutureBox::new(cr_fut, ConsoleReadFutureAlloc, AllocedFor::ConsoleRead);
+        let cr_fut_box = FutureBox::try_new(cr_fut, ConsoleReadFutureAlloc).map_err(|_| Error)?;
 
         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
         let cr_local_future_obj = LocalFutureObj::new(cr_fut_box);
@@ -145,9 +149,8 @@ async fn run4() -> Result<(), Error> {
         // impl Future
         let cw1_fut = console_write.write(&w_buf[..w_offset]).map_err(|_| Error)?;
 
-        // FutureBox<impl Future, ConsoleWriteFutureAlloc>
-        let cw1_fut_box =
-            FutureBox::new(cw1_fut, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite);
+        // FutureBox<impl Future, WriteFutureAlloc>
+        let cw1_fut_box = FutureBox::try_new(cw1_fut, write_future_alloc()).map_err(|_| Error)?;
 
         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
         let cw1_local_future_obj = LocalFutureObj::new(cw1_fut_box);
@@ -185,9 +188,8 @@ async fn run5() -> Result<(), Error> {
         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
         let cw_local_future_obj = console_write
             .write("\nEnter 5 characters or press button: ".as_bytes())
-            .map(|f| FutureBox::new(f, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite))
-            .map(|fb| LocalFutureObj::new(fb))
-            .map_err(|_| Error)?;
+            .map_err(|_| Error)
+            .and_then(|f| FutureBox::try_local(f, write_future_alloc()).map_err(|_| Error))?;
 
         pin_mut!(cw_local_future_obj);
         // Pin<&mut LocalFutureObj<'_, Result<usize, tock::result::Error>>>
@@ -202,9 +204,8 @@ async fn run5() -> Result<(), Error> {
             // LocalFutureObj<'_, Result<usize, tock::result::Error>>
             console_read
                 .read(5)
-                .map(|f| FutureBox::new(f, ConsoleReadFutureAlloc, AllocedFor::ConsoleRead))
-                .map(|fb| LocalFutureObj::new(fb))
-                .map_err(|_| Error)?
+                .map_err(|_| Error)
+                .and_then(|f| FutureBox::try_local(f, ConsoleReadFutureAlloc).map_err(|_| Error))?
         };
 
         // We are passing `b_fut` directly here. Previously, we converted a
@@ -230,9 +231,10 @@ async fn run5() -> Result<(), Error> {
                 // LocalFutureObj<'_, Result<usize, tock::result::Error>>
                 let cw1_local_future_obj = console_write
                     .write(&w_buf[..w_offset])
-                    .map(|f| FutureBox::new(f, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite))
-                    .map(|fb| LocalFutureObj::new(fb))
-                    .map_err(|_| Error)?;
+                    .map_err(|_| Error)
+                    .and_then(|f| {
+                        FutureBox::try_local(f, write_future_alloc()).map_err(|_| Error)
+                    })?;
 
                 pin_mut!(cw1_local_future_obj);
                 // Pin<&mut LocalFutureObj<'_, Result<usize, tock::result::Error>>>
@@ -258,11 +260,10 @@ async fn run5() -> Result<(), Error> {
                         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
                         let cw1_local_future_obj = console_write
                             .write("\nReceived button press. Turning ON LED.\n".as_bytes())
-                            .map(|f| {
-                                FutureBox::new(f, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite)
-                            })
-                            .map(|fb| LocalFutureObj::new(fb))
-                            .map_err(|_| Error)?;
+                            .map_err(|_| Error)
+                            .and_then(|f| {
+                                FutureBox::try_local(f, write_future_alloc()).map_err(|_| Error)
+                            })?;
 
                         pin_mut!(cw1_local_future_obj);
                         // Pin<&mut LocalFutureObj<'_, Result<usize, tock::result::Error>>>
@@ -275,11 +276,10 @@ async fn run5() -> Result<(), Error> {
                         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
                         let cw1_local_future_obj = console_write
                             .write("\nReceived button release. Turning OFF LED.\n".as_bytes())
-                            .map(|f| {
-                                FutureBox::new(f, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite)
-                            })
-                            .map(|fb| LocalFutureObj::new(fb))
-                            .map_err(|_| Error)?;
+                            .map_err(|_| Error)
+                            .and_then(|f| {
+                                FutureBox::try_local(f, write_future_alloc()).map_err(|_| Error)
+                            })?;
 
                         pin_mut!(cw1_local_future_obj);
                         // Pin<&mut LocalFutureObj<'_, Result<usize, tock::result::Error>>>
@@ -320,9 +320,8 @@ async fn run6() -> Result<(), Error> {
         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
         let cw_local_future_obj = console_write
             .write("\nEnter 5 characters and press button: ".as_bytes())
-            .map(|f| FutureBox::new(f, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite))
-            .map(|fb| LocalFutureObj::new(fb))
-            .map_err(|_| Error)?;
+            .map_err(|_| Error)
+            .and_then(|f| FutureBox::try_local(f, write_future_alloc()).map_err(|_| Error))?;
 
         pin_mut!(cw_local_future_obj);
         // Pin<&mut LocalFutureObj<'_, Result<usize, tock::result::Error>>>
@@ -332,9 +331,8 @@ async fn run6() -> Result<(), Error> {
         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
         let cr_local_future_obj = console_read
             .read(5)
-            .map(|f| FutureBox::new(f, ConsoleReadFutureAlloc, AllocedFor::ConsoleRead))
-            .map(|fb| LocalFutureObj::new(fb))
-            .map_err(|_| Error)?;
+            .map_err(|_| Error)
+            .and_then(|f| FutureBox::try_local(f, ConsoleReadFutureAlloc).map_err(|_| Error))?;
 
         // Here again, we are not pinning before calling `.await`
         let (cr, (b, b_orig)) = join(cr_local_future_obj, b_fut).await;
@@ -357,9 +355,8 @@ async fn run6() -> Result<(), Error> {
         // LocalFutureObj<'_, Result<usize, tock::result::Error>>
         let cw1_local_future_obj = console_write
             .write(&w_buf[..w_offset])
-            .map(|f| FutureBox::new(f, ConsoleWriteFutureAlloc, AllocedFor::ConsoleWrite))
-            .map(|fb| LocalFutureObj::new(fb))
-            .map_err(|_| Error)?;
+            .map_err(|_| Error)
+            .and_then(|f| FutureBox::try_local(f, write_future_alloc()).map_err(|_| Error))?;
 
         pin_mut!(cw1_local_future_obj);
         // Pin<&mut LocalFutureObj<'_, Result<usize, tock::result::Error>>>
@@ -368,39 +365,7 @@ async fn run6() -> Result<(), Error> {
     }
 }
 
-#[inline(never)]
-fn main() {
-    unsafe {
-        asm!("bkpt" :::: "volatile");
-
-        // // generate hardfault
-        // asm!("
-        //      mov r0, #0
-        //      ldr r1, [r0, #0]
-        //      " :::: "volatile");
-    }
-
-    struct RacyCell<T>(UnsafeCell<T>);
-    impl<T> RacyCell<T> {
-        const fn new(value: T) -> Self {
-            RacyCell(UnsafeCell::new(value))
-        }
-        unsafe fn get_mut_unchecked(&self) -> &mut T {
-            &mut *self.0.get()
-        }
-    }
-    unsafe impl<T> Sync for RacyCell<T> {}
-    static EXECUTOR: RacyCell<embrio_executor::Executor> =
-        RacyCell::new(embrio_executor::Executor::new());
-
-    unsafe {
-        EXECUTOR.get_mut_unchecked().block_on(run6());
-    }
-
-    unsafe {
-        asm!("bkpt" :::: "volatile");
-    }
-
-    // Return to the kernel instead!
-    syscalls::yieldk();
+#[tock::main]
+async fn main() -> Result<(), Error> {
+    run6().await
 }
@@ -5,6 +5,8 @@ use std::{env, error::Error};
 fn main() -> Result<(), Box<dyn Error>> {
     let target = env::var("TARGET")?;
 
+    println!("cargo:rustc-check-cfg=cfg(armv6m, armv7m, armv7r)");
+
     if target.starts_with("thumbv6m-") {
         println!("cargo:rustc-cfg=armv6m");
     } else if target.starts_with("thumbv7m-") {
@@ -472,9 +472,9 @@ where
     type Item = (K, V);
     type IntoIter = IntoIter<K, V, N>;
 
-    fn into_iter(mut self) -> Self::IntoIter {
+    fn into_iter(self) -> Self::IntoIter {
         // FIXME this may result in a memcpy at runtime
-        let lm = mem::replace(&mut self.0, unsafe { mem::uninitialized() });
+        let lm = unsafe { ptr::read(&self.0) };
         mem::forget(self);
 
         Self::IntoIter {
@@ -1,4 +1,4 @@
-use core::{fmt, fmt::Write, hash, mem, ops, str, str::Utf8Error};
+use core::{fmt, fmt::Write, hash, mem, ops, ptr, str, str::Utf8Error};
 
 use generic_array::{
     typenum::{consts::*, IsGreaterOrEqual},
@@ -89,7 +89,7 @@ where
     /// assert!(String::from_utf8(v).is_err());
     /// ```
     #[inline]
-    pub fn from_utf8(vec: Vec<u8, N>) -> Result<(String<N>), Utf8Error> {
+    pub fn from_utf8(vec: Vec<u8, N>) -> Result<String<N>, Utf8Error> {
         // validate input
         str::from_utf8(&*vec)?;
 
@@ -101,9 +101,9 @@ where
     ///
     /// See the safe version, `from_utf8`, for more details.
     #[inline]
-    pub unsafe fn from_utf8_unchecked(mut vec: Vec<u8, N>) -> String<N> {
+    pub unsafe fn from_utf8_unchecked(vec: Vec<u8, N>) -> String<N> {
         // FIXME this may result in a memcpy at runtime
-        let vec_ = mem::replace(&mut vec.0, mem::uninitialized());
+        let vec_ = ptr::read(&vec.0);
         mem::forget(vec);
         String(crate::i::String { vec: vec_ })
     }
@@ -0,0 +1,42 @@
+#!/bin/sh
+# Builds libtock (tests included) with each feature on its own and with the
+# combinations that change which code is compiled, denying warnings, so that a
+# driver left out by its feature cannot break the others. The library is also
+# checked for the Tock targets, where the runtime and the syscall assembly are
+# compiled instead of the host backend.
+#
+# Usage: ./check-features.sh [cargo toolchain, e.g. +nightly]
+
+set -e
+
+TOOLCHAIN=${1:-+nightly}
+
+check() {
+    echo "== $*"
+    cargo "$TOOLCHAIN" clippy --all-targets "$@" -- -D warnings
+}
+
+# The tests run on the host, so only the library is checked for a Tock target.
+check_target() {
+    echo "== $*"
+    cargo "$TOOLCHAIN" clippy "$@" -- -D warnings
+}
+
+check --no-default-features
+
+for feature in button console led futures-alloc tock2 crypto-soft trace; do
+    check --no-default-features --features "$feature"
+done
+
+check
+check --features tock2
+check --features crypto-soft
+check --features trace
+check --features trace,tock2
+check --all-features
+
+for target in thumbv7em-none-eabi riscv32imac-unknown-none-elf; do
+    check_target --target "$target"
+    check_target --target "$target" --features tock2
+    check_target --target "$target" --all-features
+done
@@ -0,0 +1,97 @@
+extern crate proc_macro;
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+
+use syn::{parse_macro_input, Ident, ItemFn, ReturnType};
+
+// `#[tock::main]` turns
+//
+//     #[tock::main]
+//     async fn main() -> Result<(), Error> { ... }
+//
+// into a synchronous `main` that statically allocates an
+// `embrio_executor::Executor` and blocks on the `embrio_async` transformed
+// body. The returned value is handed back to the `start` lang item in
+// `tock::lang_items`, which routes it through `Termination`.
+//
+// The expansion refers to `embrio_async` and `embrio_executor` by name, so the
+// application must depend on both crates and must not rename them.
+#[proc_macro_attribute]
+pub fn main(
+    attr: proc_macro::TokenStream,
+    body: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(Span::call_site(), "`tock::main` takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    match main_impl(parse_macro_input!(body)) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn main_impl(item: ItemFn) -> syn::Result<TokenStream> {
+    if item.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            item.decl.fn_token,
+            "`tock::main` must be applied to an `async fn`",
+        ));
+    }
+
+    if item.ident != "main" {
+        return Err(syn::Error::new_spanned(
+            item.ident,
+            "`tock::main` must be applied to a function named `main`",
+        ));
+    }
+
+    if !item.decl.inputs.is_empty() {
+        return Err(syn::Error::new_spanned(
+            item.decl.inputs,
+            "`tock::main` function cannot take arguments",
+        ));
+    }
+
+    if !item.decl.generics.params.is_empty() || item.decl.generics.where_clause.is_some() {
+        return Err(syn::Error::new_spanned(
+            item.decl.generics,
+            "`tock::main` function cannot be generic",
+        ));
+    }
+
+    let attrs = &item.attrs;
+    let vis = &item.vis;
+    let block = &item.block;
+    let output = match &item.decl.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+
+    let async_main = Ident::new("_tock_async_main", Span::call_site());
+
+    Ok(quote! {
+        #(#attrs)*
+        #[inline(never)]
+        #vis fn main() -> #output {
+            // `embrio_async` names `Future` unqualified in the return type it
+            // generates.
+            use ::core::future::Future;
+
+            #[embrio_async::embrio_async]
+            async fn #async_main() -> #output #block
+
+            static mut EXECUTOR: ::embrio_executor::Executor = ::embrio_executor::Executor::new();
+
+            // Safety: `main` is only ever entered once, from `rust_start`, and
+            // nothing else can name `EXECUTOR`. So this is the only mutable
+            // reference to the executor that will ever exist.
+            let executor = unsafe { &mut EXECUTOR };
+
+            executor.block_on(#async_main())
+        }
+    })
+}
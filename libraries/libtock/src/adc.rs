@@ -0,0 +1,331 @@
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+use futures_core::stream::Stream;
+
+use crate::cell::RacyCell;
+use crate::result::{Error, Result};
+use crate::syscalls::{allow, command, subscribe, with_reallowed, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = 5;
+
+mod allow_num {
+    pub const BUFFER: usize = 0;
+    pub const BUFFER_ALT: usize = 1;
+}
+
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const NUM_CHANNELS: usize = 0;
+    pub const SINGLE_SAMPLE: usize = 1;
+    pub const CONTINUOUS_BUFFERED_SAMPLE: usize = 4;
+    pub const STOP_SAMPLING: usize = 5;
+}
+
+// Passed by the capsule in `arg0` of the callback
+mod sample_mode {
+    pub const SINGLE_SAMPLE: usize = 0;
+    pub const CONTINUOUS_BUFFERED_SAMPLE: usize = 3;
+}
+
+/// Number of samples delivered per chunk by `Adc::continuous`.
+pub const CHUNK_LEN: usize = 32;
+
+static ADC_SAMPLE: RacyCell<Option<u16>> = RacyCell::new(None);
+
+// The chunk that has been filled by the kernel but not yet taken by the
+// stream, and the number of samples that were dropped since the last chunk
+// was taken.
+static ADC_CHUNK: RacyCell<Option<SampleChunk>> = RacyCell::new(None);
+
+static ADC_DROPPED_SAMPLES: RacyCell<usize> = RacyCell::new(0);
+
+static ADC_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+// The kernel fills one of these while the other is being copied out. They
+// correspond to allow numbers `BUFFER` and `BUFFER_ALT`.
+static ADC_BUF: RacyCell<[u16; CHUNK_LEN]> = RacyCell::new([0; CHUNK_LEN]);
+static ADC_BUF_ALT: RacyCell<[u16; CHUNK_LEN]> = RacyCell::new([0; CHUNK_LEN]);
+
+// Length of each of them in bytes
+const BUF_LEN: usize = CHUNK_LEN * mem::size_of::<u16>();
+
+extern "C" fn adc_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    match cb_data.get_arg0() {
+        sample_mode::SINGLE_SAMPLE => {
+            ADC_SAMPLE.set(Some(cb_data.get_arg2() as u16));
+        }
+        sample_mode::CONTINUOUS_BUFFERED_SAMPLE => {
+            // `arg1` packs the number of samples and the channel, `arg2` is
+            // the buffer that was just filled. Copy the samples out right
+            // away, as the kernel is going to refill this buffer next.
+            let len = core::cmp::min(cb_data.get_arg1() >> 8, CHUNK_LEN);
+            let buf = cb_data.get_arg2() as *mut u16;
+            let allow_slot = if buf == ADC_BUF.as_ptr() as *mut u16 {
+                allow_num::BUFFER
+            } else {
+                allow_num::BUFFER_ALT
+            };
+
+            if ADC_CHUNK.is_some() {
+                // The stream has not caught up. Keep the older chunk and
+                // report the samples we could not deliver.
+                ADC_DROPPED_SAMPLES.with(|dropped| *dropped += len);
+            } else {
+                let mut chunk = SampleChunk {
+                    samples: [0; CHUNK_LEN],
+                    len,
+                    dropped: ADC_DROPPED_SAMPLES.replace(0),
+                };
+                unsafe {
+                    with_reallowed(DRIVER_NUM, allow_slot, buf as *mut u8, BUF_LEN, || {
+                        ptr::copy_nonoverlapping(buf, chunk.samples.as_mut_ptr(), len)
+                    });
+                }
+                ADC_CHUNK.set(Some(chunk));
+            }
+        }
+        _ => return,
+    }
+
+    ADC_WAKER.wake();
+}
+
+// Indicates what the ADC is being used for. Only one single-sample future or
+// continuous stream can be active at a time.
+#[derive(Copy, Clone, PartialEq)]
+enum AdcState {
+    Single,
+    Continuous,
+    Nothing,
+}
+
+static ADC_STATE: RacyCell<AdcState> = RacyCell::new(AdcState::Nothing);
+
+pub struct Adc;
+
+impl Adc {
+    pub fn new() -> Adc {
+        Adc
+    }
+
+    pub fn get_num_channels(&self) -> Result<usize> {
+        unsafe { command(DRIVER_NUM, command_num::NUM_CHANNELS, 0, 0) }
+    }
+
+    fn check_channel(&self, channel: usize) -> Result<()> {
+        if channel >= self.get_num_channels()? {
+            Err(Error::EINVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn sample(&self, channel: usize) -> Result<impl Future<Output = u16>> {
+        if ADC_STATE.get() != AdcState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        self.check_channel(channel)?;
+
+        ADC_SAMPLE.set(None);
+
+        let _ = unsafe {
+            subscribe(
+                DRIVER_NUM,
+                subscribe_num::CALLBACK,
+                adc_callback as *const _,
+                0,
+            )
+            .and_then(|_| command(DRIVER_NUM, command_num::SINGLE_SAMPLE, channel, 0))?
+        };
+
+        ADC_STATE.set(AdcState::Single);
+
+        Ok(AdcSampler)
+    }
+
+    // Sample `channel` at `frequency` Hz, delivering `CHUNK_LEN` samples at a
+    // time. Sampling stops when the stream is dropped.
+    pub fn continuous(&self, channel: usize, frequency: usize) -> Result<SampleStream> {
+        if ADC_STATE.get() != AdcState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        self.check_channel(channel)?;
+
+        ADC_CHUNK.set(None);
+        ADC_DROPPED_SAMPLES.set(0);
+
+        unsafe {
+            let _ = allow(
+                DRIVER_NUM,
+                allow_num::BUFFER,
+                ADC_BUF.as_ptr() as *mut u8,
+                BUF_LEN,
+            )
+            .and_then(|_| {
+                allow(
+                    DRIVER_NUM,
+                    allow_num::BUFFER_ALT,
+                    ADC_BUF_ALT.as_ptr() as *mut u8,
+                    BUF_LEN,
+                )
+            })
+            .and_then(|_| {
+                subscribe(
+                    DRIVER_NUM,
+                    subscribe_num::CALLBACK,
+                    adc_callback as *const _,
+                    0,
+                )
+            })
+            .and_then(|_| {
+                command(
+                    DRIVER_NUM,
+                    command_num::CONTINUOUS_BUFFERED_SAMPLE,
+                    channel,
+                    frequency,
+                )
+            })?;
+        }
+
+        ADC_STATE.set(AdcState::Continuous);
+
+        Ok(SampleStream)
+    }
+}
+
+// Future returned by Adc::sample
+struct AdcSampler;
+
+impl Future for AdcSampler {
+    type Output = u16;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(sample) = ADC_SAMPLE.take() {
+            ADC_STATE.set(AdcState::Nothing);
+            Poll::Ready(sample)
+        } else {
+            ADC_WAKER.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+// Dropped before the sample came in: stop the conversion so that the ADC can be
+// used again.
+impl Drop for AdcSampler {
+    fn drop(&mut self) {
+        if ADC_STATE.get() == AdcState::Single {
+            unsafe {
+                let _ = command(DRIVER_NUM, command_num::STOP_SAMPLING, 0, 0);
+            }
+
+            ADC_STATE.set(AdcState::Nothing);
+        }
+    }
+}
+
+/// Stream returned by `Adc::continuous`.
+pub struct SampleStream;
+
+impl Stream for SampleStream {
+    type Item = SampleChunk;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(chunk) = ADC_CHUNK.take() {
+            Poll::Ready(Some(chunk))
+        } else {
+            ADC_WAKER.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for SampleStream {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = command(DRIVER_NUM, command_num::STOP_SAMPLING, 0, 0);
+            let _ = allow(DRIVER_NUM, allow_num::BUFFER, ptr::null_mut(), 0);
+            let _ = allow(DRIVER_NUM, allow_num::BUFFER_ALT, ptr::null_mut(), 0);
+        }
+
+        ADC_STATE.set(AdcState::Nothing);
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct SampleChunk {
+    samples: [u16; CHUNK_LEN],
+    len: usize,
+    dropped: usize,
+}
+
+impl SampleChunk {
+    pub fn get_samples(&self) -> &[u16] {
+        &self.samples[..self.len]
+    }
+
+    // Number of samples that were dropped between the previous chunk and this
+    // one, because the stream was not polled fast enough.
+    pub fn get_dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::host;
+
+    static STOPS: RacyCell<usize> = RacyCell::new(0);
+
+    // Two channels, and single samples that only come in when the test says
+    // so. Stopping cancels the conversion, so a stopped sample never comes in.
+    struct FakeAdc;
+
+    impl host::Driver for FakeAdc {
+        fn command(&mut self, minor: usize, _channel: usize, _frequency: usize) -> isize {
+            if minor == command_num::STOP_SAMPLING {
+                STOPS.with(|stops| *stops += 1);
+            }
+            2
+        }
+    }
+
+    // The ADC can only be reused at once because the dropped sample is
+    // stopped, so that no stale sample can come in for the next one
+    #[test]
+    fn dropped_sample_is_stopped() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeAdc)));
+        STOPS.set(0);
+
+        let adc = Adc::new();
+        let sample = adc.sample(0).unwrap();
+        assert_eq!(adc.sample(1).err(), Some(Error::EBUSY));
+
+        drop(sample);
+        assert_eq!(STOPS.get(), 1);
+
+        let sample = adc.sample(1).unwrap();
+        host::schedule(
+            DRIVER_NUM,
+            subscribe_num::CALLBACK,
+            sample_mode::SINGLE_SAMPLE,
+            1,
+            0x123,
+        );
+        assert_eq!(host::run(sample), 0x123);
+        assert_eq!(STOPS.get(), 1);
+        assert_eq!(host::scheduled(), 0);
+    }
+}
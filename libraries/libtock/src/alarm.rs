@@ -0,0 +1,337 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use futures_core::stream::Stream;
+
+use crate::cell::{register_waker, RacyCell};
+use crate::futures::block_on;
+use crate::result::{Error, Result};
+use crate::syscalls::{command, subscribe, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = 0;
+
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const FREQUENCY: usize = 1;
+    pub const NOW: usize = 2;
+    pub const STOP: usize = 3;
+    pub const SET_ABSOLUTE: usize = 4;
+}
+
+// The kernel gives each app a single alarm. We multiplex it in userspace: each
+// `sleep` future or `Interval` stream owns one of these slots, and the kernel
+// alarm is always set to the earliest pending expiration.
+const MAX_ALARMS: usize = 8;
+
+#[derive(Copy, Clone)]
+struct AlarmSlot {
+    // Expiration is `reference + dt`, kept apart so that the comparison in
+    // `is_expired` stays correct when the tick counter wraps.
+    reference: usize,
+    dt: usize,
+    fired: bool,
+}
+
+impl AlarmSlot {
+    fn is_expired(&self, now: usize) -> bool {
+        now.wrapping_sub(self.reference) >= self.dt
+    }
+
+    fn expiration(&self) -> usize {
+        self.reference.wrapping_add(self.dt)
+    }
+}
+
+static ALARM_SLOTS: RacyCell<[Option<AlarmSlot>; MAX_ALARMS]> = RacyCell::new([None; MAX_ALARMS]);
+
+static ALARM_WAKERS: RacyCell<[Option<Waker>; MAX_ALARMS]> =
+    RacyCell::new([None, None, None, None, None, None, None, None]);
+
+extern "C" fn alarm_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+    let now = cb_data.get_arg0();
+
+    let mut fired = [false; MAX_ALARMS];
+    ALARM_SLOTS.with(|slots| {
+        for (slot, fired) in slots.iter_mut().zip(fired.iter_mut()) {
+            if let Some(s) = slot {
+                if !s.fired && s.is_expired(now) {
+                    s.fired = true;
+                    *fired = true;
+                }
+            }
+        }
+    });
+
+    for index in (0..MAX_ALARMS).filter(|&index| fired[index]) {
+        if let Some(waker) = ALARM_WAKERS.with(|wakers| wakers[index].clone()) {
+            waker.wake();
+        }
+    }
+
+    rearm();
+}
+
+// Point the kernel alarm at the earliest slot that has not fired yet, or stop
+// it if there is none.
+fn rearm() {
+    let now = match unsafe { command(DRIVER_NUM, command_num::NOW, 0, 0) } {
+        Ok(now) => now,
+        Err(_) => return,
+    };
+
+    let earliest = ALARM_SLOTS.with(|slots| {
+        slots
+            .iter()
+            .filter_map(|slot| *slot)
+            .filter(|s| !s.fired)
+            .min_by_key(|s| s.expiration().wrapping_sub(now))
+    });
+
+    let _ = unsafe {
+        match earliest {
+            Some(s) if s.is_expired(now) => {
+                // Already expired while we were busy. Fire it on the next callback
+                // rather than looping here.
+                command(
+                    DRIVER_NUM,
+                    command_num::SET_ABSOLUTE,
+                    now.wrapping_add(1),
+                    0,
+                )
+            }
+            Some(s) => command(DRIVER_NUM, command_num::SET_ABSOLUTE, s.expiration(), 0),
+            None => command(DRIVER_NUM, command_num::STOP, 0, 0),
+        }
+    };
+}
+
+pub struct Alarm;
+
+impl Alarm {
+    pub fn new() -> Alarm {
+        Alarm
+    }
+
+    pub fn get_frequency(&self) -> Result<usize> {
+        unsafe { command(DRIVER_NUM, command_num::FREQUENCY, 0, 0) }
+    }
+
+    pub fn get_ticks(&self) -> Result<usize> {
+        unsafe { command(DRIVER_NUM, command_num::NOW, 0, 0) }
+    }
+
+    pub fn duration_to_ticks(&self, duration: Duration) -> Result<usize> {
+        let frequency = self.get_frequency()? as u64;
+
+        let ticks = duration.as_secs() * frequency
+            + (duration.subsec_nanos() as u64) * frequency / 1_000_000_000;
+
+        // Anything longer than half the counter range cannot be told apart
+        // from an expiration in the past.
+        if ticks > (usize::MAX / 2) as u64 {
+            return Err(Error::EINVAL);
+        }
+
+        Ok(ticks as usize)
+    }
+
+    // Claims a free slot, expiring `dt` ticks after `reference`.
+    fn start(&self, reference: usize, dt: usize) -> Result<usize> {
+        let index = ALARM_SLOTS
+            .with(|slots| slots.iter().position(|slot| slot.is_none()))
+            .ok_or(Error::ENOMEM)?;
+
+        unsafe {
+            subscribe(
+                DRIVER_NUM,
+                subscribe_num::CALLBACK,
+                alarm_callback as *const _,
+                0,
+            )?;
+        }
+
+        ALARM_SLOTS.with(|slots| {
+            slots[index] = Some(AlarmSlot {
+                reference,
+                dt,
+                fired: false,
+            })
+        });
+        ALARM_WAKERS.with(|wakers| wakers[index] = None);
+
+        rearm();
+
+        Ok(index)
+    }
+
+    pub fn sleep(&self, duration: Duration) -> Result<Sleep> {
+        let dt = self.duration_to_ticks(duration)?;
+        let now = self.get_ticks()?;
+
+        Ok(Sleep {
+            index: self.start(now, dt)?,
+        })
+    }
+
+    // A stream that yields once every `period`. Expirations are computed from
+    // the previous expiration rather than from when the stream was polled, so
+    // the stream does not drift.
+    pub fn interval(&self, period: Duration) -> Result<Interval> {
+        let dt = self.duration_to_ticks(period)?;
+        let now = self.get_ticks()?;
+
+        Ok(Interval {
+            index: self.start(now, dt)?,
+        })
+    }
+}
+
+fn set_alarm_waker(index: usize, cx: &mut Context<'_>) {
+    ALARM_WAKERS.with(|wakers| register_waker(&mut wakers[index], cx.waker()));
+}
+
+fn release(index: usize) {
+    ALARM_SLOTS.with(|slots| slots[index] = None);
+    ALARM_WAKERS.with(|wakers| wakers[index] = None);
+
+    rearm();
+}
+
+/// Future returned by `Alarm::sleep`. The alarm slot is released when it is
+/// dropped.
+pub struct Sleep {
+    index: usize,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match ALARM_SLOTS.with(|slots| slots[self.index]) {
+            Some(AlarmSlot { fired: true, .. }) => Poll::Ready(()),
+            _ => {
+                set_alarm_waker(self.index, cx);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        release(self.index);
+    }
+}
+
+/// Stream returned by `Alarm::interval`. The alarm slot is released when it is
+/// dropped.
+pub struct Interval {
+    index: usize,
+}
+
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let fired = ALARM_SLOTS.with(|slots| match &mut slots[self.index] {
+            Some(s) if s.fired => {
+                s.reference = s.expiration();
+                s.fired = false;
+                true
+            }
+            _ => false,
+        });
+
+        if fired {
+            rearm();
+
+            Poll::Ready(Some(()))
+        } else {
+            set_alarm_waker(self.index, cx);
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) {
+        release(self.index);
+    }
+}
+
+// Longest sleep a blocking delay is split into, well within the range
+// `duration_to_ticks` accepts at common alarm frequencies.
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+impl Alarm {
+    // Sleeps for `duration`, spinning on `yieldk`. The `embedded-hal` delays
+    // cannot report errors, so if no alarm slot is free this busy-waits on the
+    // counter instead.
+    fn block_for(&self, mut duration: Duration) {
+        while duration > Duration::from_secs(0) {
+            let step = core::cmp::min(duration, MAX_DELAY);
+
+            match self.sleep(step) {
+                Ok(sleep) => block_on(sleep),
+                Err(_) => self.spin_for(step),
+            }
+
+            duration -= step;
+        }
+    }
+
+    fn spin_for(&self, duration: Duration) {
+        let (dt, start) = match (self.duration_to_ticks(duration), self.get_ticks()) {
+            (Ok(dt), Ok(start)) => (dt, start),
+            _ => return,
+        };
+
+        while let Ok(now) = self.get_ticks() {
+            if now.wrapping_sub(start) >= dt {
+                break;
+            }
+        }
+    }
+}
+
+impl DelayMs<u32> for Alarm {
+    fn delay_ms(&mut self, ms: u32) {
+        self.block_for(Duration::from_millis(ms as u64));
+    }
+}
+
+impl DelayMs<u16> for Alarm {
+    fn delay_ms(&mut self, ms: u16) {
+        self.delay_ms(ms as u32);
+    }
+}
+
+impl DelayMs<u8> for Alarm {
+    fn delay_ms(&mut self, ms: u8) {
+        self.delay_ms(ms as u32);
+    }
+}
+
+impl DelayUs<u32> for Alarm {
+    fn delay_us(&mut self, us: u32) {
+        self.block_for(Duration::from_micros(us as u64));
+    }
+}
+
+impl DelayUs<u16> for Alarm {
+    fn delay_us(&mut self, us: u16) {
+        self.delay_us(us as u32);
+    }
+}
+
+impl DelayUs<u8> for Alarm {
+    fn delay_us(&mut self, us: u8) {
+        self.delay_us(us as u32);
+    }
+}
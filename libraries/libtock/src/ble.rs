@@ -0,0 +1,498 @@
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_core::stream::Stream;
+use heapless::consts::U31;
+use heapless::Vec;
+
+use crate::cell::RacyCell;
+use crate::result::{Error, Result, UsizeError};
+use crate::syscalls::{allow, allow_readonly, command, subscribe, with_reallowed};
+
+pub(crate) const DRIVER_NUM: usize = 0x30000;
+
+mod allow_num {
+    pub const ADVERTISING: usize = 0;
+    pub const SCAN: usize = 1;
+}
+
+mod subscribe_num {
+    pub const SCAN: usize = 0;
+}
+
+mod command_num {
+    pub const START_ADVERTISING: usize = 0;
+    // Stops passive scanning as well
+    pub const STOP: usize = 1;
+    pub const PASSIVE_SCAN: usize = 5;
+}
+
+/// Longest advertising payload, in bytes.
+pub const MAX_ADVERTISING_LEN: usize = 31;
+
+/// Bits of the flags AD structure.
+pub mod flags {
+    pub const LE_LIMITED_DISCOVERABLE: u8 = 0x01;
+    pub const LE_GENERAL_DISCOVERABLE: u8 = 0x02;
+    pub const BR_EDR_NOT_SUPPORTED: u8 = 0x04;
+}
+
+/// AD structure types, from the Bluetooth assigned numbers.
+pub mod ad_type {
+    pub const FLAGS: u8 = 0x01;
+    pub const INCOMPLETE_LIST_16BIT_SERVICE_UUIDS: u8 = 0x02;
+    pub const COMPLETE_LIST_16BIT_SERVICE_UUIDS: u8 = 0x03;
+    pub const INCOMPLETE_LIST_128BIT_SERVICE_UUIDS: u8 = 0x06;
+    pub const COMPLETE_LIST_128BIT_SERVICE_UUIDS: u8 = 0x07;
+    pub const SHORTENED_LOCAL_NAME: u8 = 0x08;
+    pub const COMPLETE_LOCAL_NAME: u8 = 0x09;
+    pub const MANUFACTURER_SPECIFIC_DATA: u8 = 0xff;
+}
+
+/// Advertising payload, a sequence of AD structures. Build one with
+/// `Advertisement::builder()`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Advertisement {
+    data: Vec<u8, U31>,
+}
+
+impl Advertisement {
+    pub fn builder() -> AdvertisementBuilder {
+        AdvertisementBuilder { data: Vec::new() }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Appends AD structures one at a time. Each method fails with `ESIZE` if
+/// the structure does not fit in what is left of the 31 bytes, so that calls
+/// can be chained with `?`.
+pub struct AdvertisementBuilder {
+    data: Vec<u8, U31>,
+}
+
+impl AdvertisementBuilder {
+    // Append an AD structure: length, type, then the data
+    pub fn ad_structure(mut self, ad_type: u8, data: &[u8]) -> Result<AdvertisementBuilder> {
+        if self.data.len() + 2 + data.len() > MAX_ADVERTISING_LEN {
+            return Err(Error::ESIZE);
+        }
+
+        // Cannot fail after the check above
+        let _ = self.data.push(data.len() as u8 + 1);
+        let _ = self.data.push(ad_type);
+        let _ = self.data.extend_from_slice(data);
+
+        Ok(self)
+    }
+
+    pub fn flags(self, flags: u8) -> Result<AdvertisementBuilder> {
+        self.ad_structure(ad_type::FLAGS, &[flags])
+    }
+
+    pub fn complete_local_name(self, name: &str) -> Result<AdvertisementBuilder> {
+        self.ad_structure(ad_type::COMPLETE_LOCAL_NAME, name.as_bytes())
+    }
+
+    pub fn shortened_local_name(self, name: &str) -> Result<AdvertisementBuilder> {
+        self.ad_structure(ad_type::SHORTENED_LOCAL_NAME, name.as_bytes())
+    }
+
+    // `data` follows the company identifier, which is sent little-endian
+    pub fn manufacturer_data(self, company_id: u16, data: &[u8]) -> Result<AdvertisementBuilder> {
+        let mut buf = [0; MAX_ADVERTISING_LEN];
+
+        if 2 + data.len() > buf.len() {
+            return Err(Error::ESIZE);
+        }
+
+        buf[..2].copy_from_slice(&company_id.to_le_bytes());
+        buf[2..2 + data.len()].copy_from_slice(data);
+
+        self.ad_structure(ad_type::MANUFACTURER_SPECIFIC_DATA, &buf[..2 + data.len()])
+    }
+
+    pub fn service_uuids16(self, uuids: &[u16]) -> Result<AdvertisementBuilder> {
+        let mut buf = [0; MAX_ADVERTISING_LEN];
+
+        if 2 * uuids.len() > buf.len() {
+            return Err(Error::ESIZE);
+        }
+
+        for (chunk, uuid) in buf.chunks_mut(2).zip(uuids) {
+            chunk.copy_from_slice(&uuid.to_le_bytes());
+        }
+
+        self.ad_structure(
+            ad_type::COMPLETE_LIST_16BIT_SERVICE_UUIDS,
+            &buf[..2 * uuids.len()],
+        )
+    }
+
+    // Each UUID is given in the little-endian order it is sent in
+    pub fn service_uuids128(self, uuids: &[[u8; 16]]) -> Result<AdvertisementBuilder> {
+        let mut buf = [0; MAX_ADVERTISING_LEN];
+
+        if 16 * uuids.len() > buf.len() {
+            return Err(Error::ESIZE);
+        }
+
+        for (chunk, uuid) in buf.chunks_mut(16).zip(uuids) {
+            chunk.copy_from_slice(uuid);
+        }
+
+        self.ad_structure(
+            ad_type::COMPLETE_LIST_128BIT_SERVICE_UUIDS,
+            &buf[..16 * uuids.len()],
+        )
+    }
+
+    pub fn build(self) -> Advertisement {
+        Advertisement { data: self.data }
+    }
+}
+
+/// Iterator over the AD structures of a payload, as `(type, data)`. It stops
+/// at the first malformed structure.
+pub struct AdStructures<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> AdStructures<'a> {
+    pub fn new(data: &'a [u8]) -> AdStructures<'a> {
+        AdStructures { data }
+    }
+}
+
+impl<'a> Iterator for AdStructures<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = *self.data.first()? as usize;
+
+        // A zero length marks the end of the significant part
+        if len == 0 || len >= self.data.len() {
+            self.data = &[];
+            return None;
+        }
+
+        let ad_type = self.data[1];
+        let data = &self.data[2..len + 1];
+        self.data = &self.data[len + 1..];
+
+        Some((ad_type, data))
+    }
+}
+
+// The radio does one thing at a time: advertising and scanning exclude each
+// other.
+#[derive(Copy, Clone, PartialEq)]
+enum BleState {
+    Advertising,
+    Scanning,
+    Nothing,
+}
+
+static BLE_STATE: RacyCell<BleState> = RacyCell::new(BleState::Nothing);
+
+// Has to stay allowed for as long as we advertise
+static BLE_ADVERTISING_BUF: RacyCell<[u8; MAX_ADVERTISING_LEN]> =
+    RacyCell::new([0; MAX_ADVERTISING_LEN]);
+
+// A received advertising PDU: 2 bytes of header, the 6 byte advertiser
+// address, then the payload
+const SCAN_PDU_LEN: usize = 2 + 6 + MAX_ADVERTISING_LEN;
+
+static BLE_SCAN_BUF: RacyCell<[u8; SCAN_PDU_LEN]> = RacyCell::new([0; SCAN_PDU_LEN]);
+
+// Copied out of the scan buffer by the callback
+#[derive(Copy, Clone)]
+struct RawReport {
+    len: usize,
+    pdu: [u8; SCAN_PDU_LEN],
+}
+
+// Reports that arrived before the scan stream was polled. When it is full,
+// new reports are dropped and counted.
+const MAX_PENDING: usize = 4;
+
+static BLE_PENDING: RacyCell<[Option<RawReport>; MAX_PENDING]> = RacyCell::new([None; MAX_PENDING]);
+
+static BLE_PENDING_HEAD: RacyCell<usize> = RacyCell::new(0);
+
+static BLE_PENDING_LEN: RacyCell<usize> = RacyCell::new(0);
+
+static BLE_DROPPED_REPORTS: RacyCell<usize> = RacyCell::new(0);
+
+static BLE_SCAN_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+extern "C" fn scan_callback(arg0: usize, arg1: usize, _arg2: usize, _userdata: usize) {
+    let x: UsizeError = arg0.into();
+    if x.0.is_some() {
+        return;
+    }
+
+    let pending_len = BLE_PENDING_LEN.get();
+    if pending_len == MAX_PENDING {
+        BLE_DROPPED_REPORTS.with(|dropped| *dropped += 1);
+        return;
+    }
+
+    let len = core::cmp::min(arg1, SCAN_PDU_LEN);
+    let mut report = RawReport {
+        len,
+        pdu: [0; SCAN_PDU_LEN],
+    };
+    unsafe {
+        with_reallowed(
+            DRIVER_NUM,
+            allow_num::SCAN,
+            BLE_SCAN_BUF.as_ptr() as *mut u8,
+            SCAN_PDU_LEN,
+            || BLE_SCAN_BUF.with(|buf| report.pdu[..len].copy_from_slice(&buf[..len])),
+        );
+    }
+
+    let index = (BLE_PENDING_HEAD.get() + pending_len) % MAX_PENDING;
+    BLE_PENDING.with(|pending| pending[index] = Some(report));
+    BLE_PENDING_LEN.set(pending_len + 1);
+
+    BLE_SCAN_WAKER.wake();
+}
+
+fn pop_pending() -> Option<RawReport> {
+    if BLE_PENDING_LEN.get() == 0 {
+        return None;
+    }
+
+    let head = BLE_PENDING_HEAD.get();
+    let report = BLE_PENDING.with(|pending| pending[head].take());
+    BLE_PENDING_HEAD.set((head + 1) % MAX_PENDING);
+    BLE_PENDING_LEN.with(|len| *len -= 1);
+
+    report
+}
+
+pub struct Ble;
+
+impl Ble {
+    pub fn new() -> Ble {
+        Ble
+    }
+
+    // Advertise `advertisement` once every `interval` until
+    // `stop_advertising`. The interval has to be between 20 ms and 10.24 s.
+    pub fn start_advertising(
+        &self,
+        interval: Duration,
+        advertisement: &Advertisement,
+    ) -> Result<()> {
+        let interval_ms = interval.as_millis();
+        if !(20..=10_240).contains(&interval_ms) {
+            return Err(Error::EINVAL);
+        }
+
+        let data = advertisement.as_bytes();
+
+        if BLE_STATE.get() != BleState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        BLE_ADVERTISING_BUF.with(|buf| buf[..data.len()].copy_from_slice(data));
+
+        unsafe {
+            let _ = allow_readonly(
+                DRIVER_NUM,
+                allow_num::ADVERTISING,
+                BLE_ADVERTISING_BUF.as_ptr() as *const u8,
+                data.len(),
+            )
+            .and_then(|_| {
+                command(
+                    DRIVER_NUM,
+                    command_num::START_ADVERTISING,
+                    interval_ms as usize,
+                    0,
+                )
+            })
+            .inspect_err(|_| {
+                let _ = allow_readonly(DRIVER_NUM, allow_num::ADVERTISING, ptr::null(), 0);
+            })?;
+        }
+
+        BLE_STATE.set(BleState::Advertising);
+
+        Ok(())
+    }
+
+    pub fn stop_advertising(&self) -> Result<()> {
+        if BLE_STATE.get() != BleState::Advertising {
+            return Err(Error::EINVAL);
+        }
+
+        unsafe {
+            command(DRIVER_NUM, command_num::STOP, 0, 0)?;
+            let _ = allow_readonly(DRIVER_NUM, allow_num::ADVERTISING, ptr::null(), 0);
+        }
+
+        BLE_STATE.set(BleState::Nothing);
+
+        Ok(())
+    }
+
+    // Listen for advertisements without sending scan requests. Scanning stops
+    // when the stream is dropped.
+    pub fn scan(&self) -> Result<Scan> {
+        if BLE_STATE.get() != BleState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        BLE_PENDING.set([None; MAX_PENDING]);
+        BLE_PENDING_HEAD.set(0);
+        BLE_PENDING_LEN.set(0);
+        BLE_DROPPED_REPORTS.set(0);
+
+        unsafe {
+            let _ = allow(
+                DRIVER_NUM,
+                allow_num::SCAN,
+                BLE_SCAN_BUF.as_ptr() as *mut u8,
+                SCAN_PDU_LEN,
+            )
+            .and_then(|_| {
+                subscribe(
+                    DRIVER_NUM,
+                    subscribe_num::SCAN,
+                    scan_callback as *const _,
+                    0,
+                )
+            })
+            .and_then(|_| command(DRIVER_NUM, command_num::PASSIVE_SCAN, 1, 0))
+            .inspect_err(|_| {
+                stop_scan();
+            })?;
+        }
+
+        BLE_STATE.set(BleState::Scanning);
+
+        Ok(Scan)
+    }
+}
+
+fn stop_scan() {
+    unsafe {
+        let _ = command(DRIVER_NUM, command_num::STOP, 0, 0);
+        let _ = subscribe(DRIVER_NUM, subscribe_num::SCAN, ptr::null(), 0);
+        let _ = allow(DRIVER_NUM, allow_num::SCAN, ptr::null_mut(), 0);
+    }
+
+    BLE_STATE.set(BleState::Nothing);
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PduType {
+    AdvInd,
+    AdvDirectInd,
+    AdvNonconnInd,
+    ScanReq,
+    ScanRsp,
+    ConnectReq,
+    AdvScanInd,
+    Unknown(u8),
+}
+
+impl PduType {
+    fn from_header(header: u8) -> PduType {
+        match header & 0x0f {
+            0 => PduType::AdvInd,
+            1 => PduType::AdvDirectInd,
+            2 => PduType::AdvNonconnInd,
+            3 => PduType::ScanReq,
+            4 => PduType::ScanRsp,
+            5 => PduType::ConnectReq,
+            6 => PduType::AdvScanInd,
+            other => PduType::Unknown(other),
+        }
+    }
+}
+
+/// An advertisement received while scanning.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+    pdu_type: PduType,
+    address: [u8; 6],
+    data: Vec<u8, U31>,
+}
+
+impl Report {
+    // `None` if the PDU is too short to hold a header and an address
+    fn from_raw(raw: &RawReport) -> Option<Report> {
+        if raw.len < 8 {
+            return None;
+        }
+
+        let mut address = [0; 6];
+        address.copy_from_slice(&raw.pdu[2..8]);
+
+        let mut data = Vec::new();
+        let _ = data.extend_from_slice(&raw.pdu[8..raw.len]);
+
+        Some(Report {
+            pdu_type: PduType::from_header(raw.pdu[0]),
+            address,
+            data,
+        })
+    }
+
+    pub fn get_pdu_type(&self) -> PduType {
+        self.pdu_type
+    }
+
+    // The advertiser's address, least significant byte first
+    pub fn get_address(&self) -> [u8; 6] {
+        self.address
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn ad_structures(&self) -> AdStructures<'_> {
+        AdStructures::new(&self.data)
+    }
+}
+
+/// Stream of reports returned by `Ble::scan`.
+pub struct Scan;
+
+impl Scan {
+    // Number of reports that were dropped because the stream was not polled
+    // fast enough.
+    pub fn get_dropped(&self) -> usize {
+        BLE_DROPPED_REPORTS.get()
+    }
+}
+
+impl Stream for Scan {
+    type Item = Report;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Some(raw) = pop_pending() {
+            if let Some(report) = Report::from_raw(&raw) {
+                return Poll::Ready(Some(report));
+            }
+        }
+
+        BLE_SCAN_WAKER.register(cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Scan {
+    fn drop(&mut self) {
+        stop_scan();
+    }
+}
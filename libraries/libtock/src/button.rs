@@ -1,11 +1,14 @@
 use core::pin::Pin;
 use core::task::{Context, Poll, Waker};
+use embedded_hal::digital::v2::InputPin;
 use futures_core::stream::Stream;
 
-use crate::result::Result;
+use crate::cell::RacyCell;
+use crate::drivers::driver_num;
+use crate::result::{Error, Result};
 use crate::syscalls::{command, subscribe, CallbackData};
 
-const DRIVER_NUM: usize = 3;
+pub(crate) const DRIVER_NUM: usize = driver_num::BUTTON;
 
 mod subscribe_num {
     pub const CALLBACK: usize = 0;
@@ -15,23 +18,19 @@ mod command_num {
     pub const NUM_BUTTONS: usize = 0;
     pub const ENABLE_INTERRUPT: usize = 1;
     pub const DISABLE_INTERRUPT: usize = 2;
-    pub const CURRENT_STATE: usize = 2;
+    pub const CURRENT_STATE: usize = 3;
 }
 
-static mut BUTTON_CALLBACK_DATA: Option<CallbackData> = None;
+static BUTTON_CALLBACK_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
 
-static mut BUTTON_WAKER: Option<Waker> = None;
+static BUTTON_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
 
 extern "C" fn button_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
     let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
 
-    unsafe {
-        BUTTON_CALLBACK_DATA = Some(cb_data);
+    BUTTON_CALLBACK_DATA.set(Some(cb_data));
 
-        BUTTON_WAKER.as_ref().map(|w| {
-            w.wake_by_ref();
-        });
-    }
+    BUTTON_WAKER.wake();
 }
 
 pub struct Button;
@@ -41,12 +40,6 @@ impl Button {
         Button
     }
 
-    unsafe fn set_button_waker(cx: &mut Context<'_>) {
-        if BUTTON_WAKER.is_none() {
-            BUTTON_WAKER = Some(cx.waker().clone());
-        }
-    }
-
     pub fn initialize(&self) -> Result<()> {
         unsafe {
             subscribe(
@@ -82,28 +75,35 @@ impl Button {
             })
         }
     }
+
+    // A single button as an `embedded-hal` input pin, high while pressed
+    pub fn pin(&self, button_num: usize) -> Result<ButtonPin> {
+        if button_num >= self.get_num_buttons()? {
+            return Err(Error::EINVAL);
+        }
+
+        Ok(ButtonPin { button_num })
+    }
 }
 
 impl Stream for Button {
     type Item = ButtonEventData;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        unsafe {
-            if let Some(cb_data) = BUTTON_CALLBACK_DATA.take() {
-                Button::set_button_waker(cx);
+        if let Some(cb_data) = BUTTON_CALLBACK_DATA.take() {
+            BUTTON_WAKER.register(cx.waker());
 
-                let button_num = cb_data.get_arg0();
-                let button_event_data = if cb_data.get_arg1() == 0 {
-                    ButtonEventData::new(button_num, ButtonState::Released)
-                } else {
-                    ButtonEventData::new(button_num, ButtonState::Pressed)
-                };
-
-                Poll::Ready(Some(button_event_data))
+            let button_num = cb_data.get_arg0();
+            let button_event_data = if cb_data.get_arg1() == 0 {
+                ButtonEventData::new(button_num, ButtonState::Released)
             } else {
-                Button::set_button_waker(cx);
-                Poll::Pending
-            }
+                ButtonEventData::new(button_num, ButtonState::Pressed)
+            };
+
+            Poll::Ready(Some(button_event_data))
+        } else {
+            BUTTON_WAKER.register(cx.waker());
+            Poll::Pending
         }
     }
 }
@@ -133,3 +133,27 @@ pub enum ButtonState {
     Released,
     Pressed,
 }
+
+pub struct ButtonPin {
+    button_num: usize,
+}
+
+impl ButtonPin {
+    pub fn get_num(&self) -> usize {
+        self.button_num
+    }
+}
+
+impl InputPin for ButtonPin {
+    type Error = Error;
+
+    fn is_high(&self) -> Result<bool> {
+        Button::new()
+            .get_button_state(self.button_num)
+            .map(|state| state == ButtonState::Pressed)
+    }
+
+    fn is_low(&self) -> Result<bool> {
+        self.is_high().map(|high| !high)
+    }
+}
@@ -0,0 +1,203 @@
+// Shared state for drivers. Apps are single threaded and the kernel only runs
+// upcalls while the app is inside `yieldk`, so a value is never touched from
+// two places at once as long as nobody yields while holding it. `RacyCell`
+// hands out access through a closure, which keeps borrows short and makes that
+// rule easy to follow.
+//
+// In debug builds the rule is checked: accessing a cell from inside its own
+// closure, or yielding while any cell is borrowed, panics. This catches on the
+// host what would otherwise be a corrupted driver state on the device.
+
+use core::cell::UnsafeCell;
+use core::mem;
+use core::task::Waker;
+
+#[cfg(debug_assertions)]
+use core::cell::Cell;
+
+// Number of cells currently borrowed. Only consulted in debug builds.
+#[cfg(debug_assertions)]
+static mut ACTIVE_BORROWS: usize = 0;
+
+/// State shared between poll context and upcall context. See the module
+/// comment for the access rules.
+pub struct RacyCell<T> {
+    value: UnsafeCell<T>,
+    #[cfg(debug_assertions)]
+    borrowed: Cell<bool>,
+}
+
+// Only sound because apps are single threaded; the debug checks cover
+// interleaving through upcalls.
+unsafe impl<T> Sync for RacyCell<T> {}
+
+impl<T> RacyCell<T> {
+    pub const fn new(value: T) -> RacyCell<T> {
+        RacyCell {
+            value: UnsafeCell::new(value),
+            #[cfg(debug_assertions)]
+            borrowed: Cell::new(false),
+        }
+    }
+
+    // Run `f` with exclusive access to the value. `f` must not yield.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        #[cfg(debug_assertions)]
+        let _borrow = self.borrow();
+
+        f(unsafe { &mut *self.value.get() })
+    }
+
+    pub fn set(&self, value: T) {
+        self.with(|v| *v = value)
+    }
+
+    pub fn replace(&self, value: T) -> T {
+        self.with(|v| mem::replace(v, value))
+    }
+
+    // For buffers shared with the kernel through `allow`. The kernel may write
+    // through the pointer whenever the app yields.
+    pub fn as_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    #[cfg(debug_assertions)]
+    fn borrow(&self) -> Borrow<'_> {
+        if self.borrowed.replace(true) {
+            panic!("RacyCell accessed while already borrowed");
+        }
+
+        unsafe {
+            ACTIVE_BORROWS += 1;
+        }
+
+        Borrow {
+            borrowed: &self.borrowed,
+        }
+    }
+}
+
+// Released on drop, so that a panic inside `with` does not leave the cell
+// borrowed for good
+#[cfg(debug_assertions)]
+struct Borrow<'a> {
+    borrowed: &'a Cell<bool>,
+}
+
+#[cfg(debug_assertions)]
+impl Drop for Borrow<'_> {
+    fn drop(&mut self) {
+        self.borrowed.set(false);
+
+        unsafe {
+            ACTIVE_BORROWS -= 1;
+        }
+    }
+}
+
+impl<T: Copy> RacyCell<T> {
+    pub fn get(&self) -> T {
+        self.with(|v| *v)
+    }
+}
+
+impl<T> RacyCell<Option<T>> {
+    pub fn take(&self) -> Option<T> {
+        self.with(|v| v.take())
+    }
+
+    pub fn is_some(&self) -> bool {
+        self.with(|v| v.is_some())
+    }
+}
+
+// The waker of the task waiting on a driver, set from poll context and woken
+// from the upcall.
+impl RacyCell<Option<Waker>> {
+    // See `register_waker`
+    pub fn register(&self, waker: &Waker) {
+        self.with(|w| register_waker(w, waker))
+    }
+
+    pub fn wake(&self) {
+        // Released before waking, in case the waker looks at driver state
+        if let Some(waker) = self.with(|w| w.clone()) {
+            waker.wake();
+        }
+    }
+}
+
+// Store `waker` in `slot`, unless the one there already wakes the same task. A
+// driver may be polled by `block_on` and then by an executor, and has to wake
+// the latter. Also used for drivers that keep a waker per slot.
+pub(crate) fn register_waker(slot: &mut Option<Waker>, waker: &Waker) {
+    match slot {
+        Some(w) if w.will_wake(waker) => {}
+        _ => *slot = Some(waker.clone()),
+    }
+}
+
+// Whether any cell is borrowed right now. Borrows are only tracked in debug
+// builds, so this is always `false` in release builds. Used by the panic
+// handler, and there is none on the host.
+#[cfg(feature = "trace")]
+#[cfg_attr(
+    not(any(target_arch = "arm", target_arch = "riscv32")),
+    allow(dead_code)
+)]
+pub(crate) fn any_borrowed() -> bool {
+    #[cfg(debug_assertions)]
+    return unsafe { ACTIVE_BORROWS } != 0;
+
+    #[cfg(not(debug_assertions))]
+    false
+}
+
+// Called by `yieldk`. Upcalls run during the yield and may access any cell,
+// so none may be borrowed across it.
+#[cfg(debug_assertions)]
+pub(crate) fn check_yield() {
+    if unsafe { ACTIVE_BORROWS } != 0 {
+        panic!("yield while a RacyCell is borrowed");
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    use crate::syscalls::{self, host};
+
+    #[test]
+    #[should_panic(expected = "RacyCell accessed while already borrowed")]
+    fn overlapping_access_panics() {
+        let _lock = host::lock();
+        let cell = RacyCell::new(0);
+
+        cell.with(|_| cell.set(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "yield while a RacyCell is borrowed")]
+    fn yield_while_borrowed_panics() {
+        let _lock = host::lock();
+        let cell = RacyCell::new(0);
+
+        cell.with(|_| syscalls::yieldk());
+    }
+
+    #[test]
+    fn borrow_is_released_on_panic() {
+        let _lock = host::lock();
+        let cell = RacyCell::new(0);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| cell.with(|_| panic!("inside with"))));
+        assert!(res.is_err());
+
+        cell.set(1);
+        assert_eq!(cell.get(), 1);
+        check_yield();
+    }
+}
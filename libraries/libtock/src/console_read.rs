@@ -1,11 +1,17 @@
 use core::future::Future;
 use core::pin::Pin;
+use core::ptr;
 use core::task::{Context, Poll, Waker};
 
-use crate::result::{Error, Result, UsizeError};
+use embedded_hal::serial;
+
+use crate::cell::RacyCell;
+use crate::drivers::driver_num;
+use crate::futures::block_on;
+use crate::result::{nb_error, Error, Result, UsizeError};
 use crate::syscalls::{allow, command, subscribe, CallbackData};
 
-const DRIVER_NUM: usize = 1;
+const DRIVER_NUM: usize = driver_num::CONSOLE;
 
 mod allow_num {
     pub const READ: usize = 2;
@@ -20,20 +26,16 @@ mod command_num {
     pub const READ_ABORT: usize = 3;
 }
 
-static mut CONSOLE_READ_DATA: Option<CallbackData> = None;
+static CONSOLE_READ_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
 
-static mut CONSOLE_READ_WAKER: Option<Waker> = None;
+static CONSOLE_READ_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
 
 extern "C" fn console_read_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
     let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
 
-    unsafe {
-        CONSOLE_READ_DATA = Some(cb_data);
+    CONSOLE_READ_DATA.set(Some(cb_data));
 
-        CONSOLE_READ_WAKER.as_ref().map(|w| {
-            w.wake_by_ref();
-        });
-    }
+    CONSOLE_READ_WAKER.wake();
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -53,10 +55,10 @@ enum ConsoleReadState {
     Nothing,
 }
 
-static mut CONSOLE_READ_STATE: ConsoleReadState = ConsoleReadState::Nothing;
+static CONSOLE_READ_STATE: RacyCell<ConsoleReadState> = RacyCell::new(ConsoleReadState::Nothing);
 
 // Corresponds to the kernel read buffer
-static mut CONSOLE_READ_BUF: [u8; 64] = [0; 64];
+static CONSOLE_READ_BUF: RacyCell<[u8; 64]> = RacyCell::new([0; 64]);
 
 pub struct ConsoleRead;
 
@@ -67,34 +69,26 @@ impl ConsoleRead {
 
     pub fn read_buffer(buf: &mut [u8]) {
         let len = buf.len();
-        unsafe {
-            buf.copy_from_slice(&CONSOLE_READ_BUF[..len]);
-        }
-    }
-
-    unsafe fn set_console_read_waker(cx: &mut Context<'_>) {
-        if CONSOLE_READ_WAKER.is_none() {
-            CONSOLE_READ_WAKER = Some(cx.waker().clone());
-        }
+        CONSOLE_READ_BUF.with(|read_buf| buf.copy_from_slice(&read_buf[..len]));
     }
 
     pub fn read(&self, len: usize) -> Result<impl Future<Output = Result<BytesRead>>> {
-        unsafe {
-            if CONSOLE_READ_STATE != ConsoleReadState::Nothing {
-                return Err(Error::EBUSY);
-            }
+        if CONSOLE_READ_STATE.get() != ConsoleReadState::Nothing {
+            return Err(Error::EBUSY);
+        }
 
-            if len > CONSOLE_READ_BUF.len() {
-                return Err(Error::EINVAL);
-            }
+        if len > CONSOLE_READ_BUF.with(|buf| buf.len()) {
+            return Err(Error::EINVAL);
+        }
 
-            // clear previous read
-            &CONSOLE_READ_BUF.iter_mut().for_each(|x| *x = 0);
+        // clear previous read
+        CONSOLE_READ_BUF.with(|buf| buf.iter_mut().for_each(|x| *x = 0));
 
+        unsafe {
             let _ = allow(
                 DRIVER_NUM,
                 allow_num::READ,
-                &CONSOLE_READ_BUF as *const u8 as *mut u8,
+                CONSOLE_READ_BUF.as_ptr() as *mut u8,
                 len,
             )
             .and_then(|_| {
@@ -106,37 +100,44 @@ impl ConsoleRead {
                 )
             })
             .and_then(|_| command(DRIVER_NUM, command_num::READ, len, 0))?;
+        }
 
-            CONSOLE_READ_STATE = ConsoleReadState::Ongoing(InflightReads {
-                pending: len,
-                completed: 0,
-            });
+        CONSOLE_READ_STATE.set(ConsoleReadState::Ongoing(InflightReads {
+            pending: len,
+            completed: 0,
+        }));
 
-            Ok(ConsoleReader)
-        }
+        Ok(ConsoleReader)
     }
 
     // `CONSOLE_READ_STATE` does from `Ongoing(...)` to `Aborting(...)`
     pub fn abort(&self) -> Result<()> {
-        unsafe {
-            match CONSOLE_READ_STATE {
-                ConsoleReadState::Ongoing(InflightReads {
+        match CONSOLE_READ_STATE.get() {
+            ConsoleReadState::Ongoing(InflightReads {
+                pending: rp,
+                completed: rc,
+            }) => unsafe { command(DRIVER_NUM, command_num::READ_ABORT, 0, 0) }.map(|_| {
+                CONSOLE_READ_STATE.set(ConsoleReadState::Aborting(InflightReads {
                     pending: rp,
                     completed: rc,
-                }) => command(DRIVER_NUM, command_num::READ_ABORT, 0, 0).map(|_| {
-                    CONSOLE_READ_STATE = ConsoleReadState::Aborting(InflightReads {
-                        pending: rp,
-                        completed: rc,
-                    });
-                    ()
-                }),
-                ConsoleReadState::Aborting(_) => Err(Error::EBUSY),
-                ConsoleReadState::Nothing => Err(Error::EINVAL),
-            }
+                }));
+            }),
+            ConsoleReadState::Aborting(_) => Err(Error::EBUSY),
+            ConsoleReadState::Nothing => Err(Error::EINVAL),
         }
     }
 }
 
+// Take the read buffer back from the kernel, so that `read_buffer` can look at
+// what it wrote
+fn finish_read() {
+    unsafe {
+        let _ = allow(DRIVER_NUM, allow_num::READ, ptr::null_mut(), 0);
+    }
+
+    CONSOLE_READ_STATE.set(ConsoleReadState::Nothing);
+}
+
 // Future returned by ConsoleRead::read
 struct ConsoleReader;
 
@@ -144,63 +145,81 @@ impl Future for ConsoleReader {
     type Output = Result<BytesRead>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        unsafe {
-            if let Some(cb_data) = CONSOLE_READ_DATA.take() {
-                let x: UsizeError = cb_data.get_arg0().into();
-                match x.0 {
-                    Some(e) => {
-                        // Callback error
-                        CONSOLE_READ_STATE = ConsoleReadState::Nothing;
-                        Poll::Ready(Err(e))
-                    }
-                    None => {
-                        // No callback error, roll the state machine
-                        let c = CONSOLE_READ_STATE.clone();
-                        match c {
-                            ConsoleReadState::Ongoing(InflightReads {
-                                pending: rp,
-                                completed: rc,
-                            }) => {
-                                let mut rp = rp;
-                                let mut rc = rc;
-
-                                rc += cb_data.get_arg1();
-                                rp -= cb_data.get_arg1();
-
-                                if rp == 0 {
-                                    // Read completed successfully
-                                    CONSOLE_READ_STATE = ConsoleReadState::Nothing;
-                                    Poll::Ready(Ok(rc))
-                                } else {
-                                    // Read is still ongoing
-                                    CONSOLE_READ_STATE = ConsoleReadState::Ongoing(InflightReads {
-                                        pending: rp,
-                                        completed: rc,
-                                    });
-                                    ConsoleRead::set_console_read_waker(cx);
-                                    Poll::Pending
-                                }
+        if let Some(cb_data) = CONSOLE_READ_DATA.take() {
+            let x: UsizeError = cb_data.get_arg0().into();
+            match x.0 {
+                Some(e) => {
+                    // Callback error
+                    finish_read();
+                    Poll::Ready(Err(e))
+                }
+                None => {
+                    // No callback error, roll the state machine
+                    let c = CONSOLE_READ_STATE.get();
+                    match c {
+                        ConsoleReadState::Ongoing(InflightReads {
+                            pending: rp,
+                            completed: rc,
+                        }) => {
+                            let mut rp = rp;
+                            let mut rc = rc;
+
+                            rc += cb_data.get_arg1();
+                            rp -= cb_data.get_arg1();
+
+                            if rp == 0 {
+                                // Read completed successfully
+                                finish_read();
+                                Poll::Ready(Ok(rc))
+                            } else {
+                                // Read is still ongoing
+                                CONSOLE_READ_STATE.set(ConsoleReadState::Ongoing(InflightReads {
+                                    pending: rp,
+                                    completed: rc,
+                                }));
+                                CONSOLE_READ_WAKER.register(cx.waker());
+                                Poll::Pending
                             }
-                            ConsoleReadState::Aborting(InflightReads { completed: rc, .. }) => {
-                                let mut rc = rc;
+                        }
+                        ConsoleReadState::Aborting(InflightReads { completed: rc, .. }) => {
+                            let mut rc = rc;
 
-                                rc += cb_data.get_arg1();
+                            rc += cb_data.get_arg1();
 
-                                CONSOLE_READ_STATE = ConsoleReadState::Nothing;
-                                Poll::Ready(Ok(rc))
-                            }
-                            ConsoleReadState::Nothing => {
-                                unreachable!();
-                            }
+                            finish_read();
+                            Poll::Ready(Ok(rc))
+                        }
+                        ConsoleReadState::Nothing => {
+                            unreachable!();
                         }
                     }
                 }
-            } else {
-                ConsoleRead::set_console_read_waker(cx);
-                Poll::Pending
             }
+        } else {
+            CONSOLE_READ_WAKER.register(cx.waker());
+            Poll::Pending
         }
     }
 }
 
 pub type BytesRead = usize;
+
+// `embedded-hal` read of a single byte. It spins on `yieldk` until the byte
+// arrives.
+impl serial::Read<u8> for ConsoleRead {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        let reader = ConsoleRead::read(self, 1).map_err(nb_error)?;
+
+        // Nothing was read if the read got aborted
+        if block_on(reader)? == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let mut byte = [0];
+        ConsoleRead::read_buffer(&mut byte);
+
+        Ok(byte[0])
+    }
+}
@@ -1,12 +1,18 @@
 use core::fmt;
 use core::future::Future;
 use core::pin::Pin;
+use core::ptr;
 use core::task::{Context, Poll, Waker};
 
-use crate::result::{Error, Result, UsizeError};
-use crate::syscalls::{allow, command, subscribe, CallbackData};
+use embedded_hal::{blocking, serial};
 
-const DRIVER_NUM: usize = 1;
+use crate::cell::RacyCell;
+use crate::drivers::driver_num;
+use crate::futures::block_on;
+use crate::result::{nb_error, Error, Result};
+use crate::syscalls::{allow_readonly, command, subscribe, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = driver_num::CONSOLE;
 
 mod allow_num {
     pub const WRITE: usize = 1;
@@ -20,20 +26,22 @@ mod command_num {
     pub const WRITE: usize = 1;
 }
 
-static mut CONSOLE_WRITE_DATA: Option<CallbackData> = None;
+static CONSOLE_WRITE_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
 
-static mut CONSOLE_WRITE_WAKER: Option<Waker> = None;
+static CONSOLE_WRITE_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
 
 extern "C" fn console_write_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    // Completion of a write whose future was dropped
+    if CONSOLE_WRITE_STATE.get() == ConsoleWriteState::Abandoned {
+        CONSOLE_WRITE_STATE.set(ConsoleWriteState::Nothing);
+        return;
+    }
+
     let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
 
-    unsafe {
-        CONSOLE_WRITE_DATA = Some(cb_data);
+    CONSOLE_WRITE_DATA.set(Some(cb_data));
 
-        CONSOLE_WRITE_WAKER.as_ref().map(|w| {
-            w.wake_by_ref();
-        });
-    }
+    CONSOLE_WRITE_WAKER.wake();
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -43,17 +51,20 @@ struct InflightWrites {
 }
 
 // Indicates if there is an ongoing write. Once the write is complete,
-// `CONSOLE_WRITE_STATE` is set to `Nothing` and the future can be resolved.
+// `CONSOLE_WRITE_STATE` is set to `Nothing` and the future can be resolved. A
+// write whose future was dropped is `Abandoned` until the capsule completes it,
+// so that its completion is not taken for that of the next write.
 #[derive(Copy, Clone, PartialEq)]
 enum ConsoleWriteState {
     Ongoing(InflightWrites),
+    Abandoned,
     Nothing,
 }
 
-static mut CONSOLE_WRITE_STATE: ConsoleWriteState = ConsoleWriteState::Nothing;
+static CONSOLE_WRITE_STATE: RacyCell<ConsoleWriteState> = RacyCell::new(ConsoleWriteState::Nothing);
 
 // Corresponds to the kernel write buffer
-static mut CONSOLE_WRITE_BUF: [u8; 64] = [0; 64];
+static CONSOLE_WRITE_BUF: RacyCell<[u8; 64]> = RacyCell::new([0; 64]);
 
 pub struct ConsoleWrite;
 
@@ -62,30 +73,26 @@ impl ConsoleWrite {
         ConsoleWrite
     }
 
-    unsafe fn set_console_write_waker(cx: &mut Context<'_>) {
-        if CONSOLE_WRITE_WAKER.is_none() {
-            CONSOLE_WRITE_WAKER = Some(cx.waker().clone());
+    pub fn write(&self, s: &[u8]) -> Result<impl Future<Output = Result<BytesWritten>>> {
+        if CONSOLE_WRITE_STATE.get() != ConsoleWriteState::Nothing {
+            return Err(Error::EBUSY);
         }
-    }
 
-    pub fn write(&self, s: &[u8]) -> Result<impl Future<Output = Result<BytesWritten>>> {
-        unsafe {
-            if CONSOLE_WRITE_STATE != ConsoleWriteState::Nothing {
-                return Err(Error::EBUSY);
-            }
+        if s.len() > CONSOLE_WRITE_BUF.with(|buf| buf.len()) {
+            return Err(Error::EINVAL);
+        }
 
-            if s.len() > CONSOLE_WRITE_BUF.len() {
-                return Err(Error::EINVAL);
-            }
+        self.clear_console_write_buf();
 
-            self.clear_console_write_buf();
+        CONSOLE_WRITE_BUF.with(|buf| buf[..s.len()].copy_from_slice(s));
 
-            &CONSOLE_WRITE_BUF[..s.len()].copy_from_slice(s);
+        CONSOLE_WRITE_DATA.set(None);
 
-            let _ = allow(
+        unsafe {
+            let _ = allow_readonly(
                 DRIVER_NUM,
                 allow_num::WRITE,
-                &CONSOLE_WRITE_BUF as *const u8 as *mut u8,
+                CONSOLE_WRITE_BUF.as_ptr() as *const u8,
                 s.len(),
             )
             .and_then(|_| {
@@ -97,27 +104,45 @@ impl ConsoleWrite {
                 )
             })
             .and_then(|_| command(DRIVER_NUM, command_num::WRITE, s.len(), 0))
-            .map_err(|e| {
+            .inspect_err(|_| {
+                unallow_write_buf();
                 self.clear_console_write_buf();
-                e
             })?;
+        }
 
-            CONSOLE_WRITE_STATE = ConsoleWriteState::Ongoing(InflightWrites {
-                pending: s.len(),
-                completed: 0,
-            });
+        CONSOLE_WRITE_STATE.set(ConsoleWriteState::Ongoing(InflightWrites {
+            pending: s.len(),
+            completed: 0,
+        }));
 
-            Ok(ConsoleWriter)
-        }
+        Ok(ConsoleWriter)
     }
 
     fn clear_console_write_buf(&self) {
-        unsafe {
-            &CONSOLE_WRITE_BUF.iter_mut().for_each(|x| *x = 0);
-        }
+        CONSOLE_WRITE_BUF.with(|buf| buf.iter_mut().for_each(|x| *x = 0));
+    }
+}
+
+// Take the write buffer back from the kernel
+fn unallow_write_buf() {
+    unsafe {
+        let _ = allow_readonly(DRIVER_NUM, allow_num::WRITE, ptr::null(), 0);
     }
 }
 
+// Tock 1.x signals a failed write with a negative `arg0`. Tock 2.0 only ever
+// passes the number of bytes written in `arg0`.
+#[cfg(not(feature = "tock2"))]
+fn write_callback_error(cb_data: &CallbackData) -> Option<Error> {
+    let x: crate::result::UsizeError = cb_data.get_arg0().into();
+    x.0
+}
+
+#[cfg(feature = "tock2")]
+fn write_callback_error(_cb_data: &CallbackData) -> Option<Error> {
+    None
+}
+
 // Future returned by ConsoleWrite::write
 struct ConsoleWriter;
 
@@ -125,54 +150,71 @@ impl Future for ConsoleWriter {
     type Output = Result<BytesWritten>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        unsafe {
-            if let Some(cb_data) = CONSOLE_WRITE_DATA.take() {
-                let x: UsizeError = cb_data.get_arg0().into();
-                match x.0 {
-                    Some(e) => {
-                        // Callback error
-                        CONSOLE_WRITE_STATE = ConsoleWriteState::Nothing;
-                        Poll::Ready(Err(e))
-                    }
-                    None => {
-                        // No callback error, roll the state machine
-                        let c = CONSOLE_WRITE_STATE.clone();
-                        match c {
-                            ConsoleWriteState::Ongoing(InflightWrites {
-                                pending: wp,
-                                completed: wc,
-                            }) => {
-                                // No callback error
-                                let mut wp = wp;
-                                let mut wc = wc;
-
-                                wc += cb_data.get_arg0();
-                                wp -= cb_data.get_arg0();
-
-                                if wp == 0 {
-                                    // Write completed successfully
-                                    CONSOLE_WRITE_STATE = ConsoleWriteState::Nothing;
-                                    Poll::Ready(Ok(wc))
-                                } else {
-                                    // Write is still congoing
-                                    CONSOLE_WRITE_STATE =
-                                        ConsoleWriteState::Ongoing(InflightWrites {
-                                            pending: wp,
-                                            completed: wc,
-                                        });
-                                    ConsoleWrite::set_console_write_waker(cx);
-                                    Poll::Pending
-                                }
-                            }
-                            ConsoleWriteState::Nothing => {
-                                unreachable!();
+        if let Some(cb_data) = CONSOLE_WRITE_DATA.take() {
+            match write_callback_error(&cb_data) {
+                Some(e) => {
+                    // Callback error
+                    unallow_write_buf();
+                    CONSOLE_WRITE_STATE.set(ConsoleWriteState::Nothing);
+                    Poll::Ready(Err(e))
+                }
+                None => {
+                    // No callback error, roll the state machine
+                    let c = CONSOLE_WRITE_STATE.get();
+                    match c {
+                        ConsoleWriteState::Ongoing(InflightWrites {
+                            pending: wp,
+                            completed: wc,
+                        }) => {
+                            // No callback error
+                            let mut wp = wp;
+                            let mut wc = wc;
+
+                            wc += cb_data.get_arg0();
+                            wp -= cb_data.get_arg0();
+
+                            if wp == 0 {
+                                // Write completed successfully
+                                unallow_write_buf();
+                                CONSOLE_WRITE_STATE.set(ConsoleWriteState::Nothing);
+                                Poll::Ready(Ok(wc))
+                            } else {
+                                // Write is still congoing
+                                CONSOLE_WRITE_STATE.set(ConsoleWriteState::Ongoing(
+                                    InflightWrites {
+                                        pending: wp,
+                                        completed: wc,
+                                    },
+                                ));
+                                CONSOLE_WRITE_WAKER.register(cx.waker());
+                                Poll::Pending
                             }
                         }
+                        // Polled again after it completed
+                        ConsoleWriteState::Abandoned | ConsoleWriteState::Nothing => {
+                            Poll::Ready(Err(Error::FAIL))
+                        }
                     }
                 }
+            }
+        } else {
+            CONSOLE_WRITE_WAKER.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+// Dropped before it was resolved. The buffer is taken back, and the next write
+// can start once the capsule has completed this one.
+impl Drop for ConsoleWriter {
+    fn drop(&mut self) {
+        if let ConsoleWriteState::Ongoing(_) = CONSOLE_WRITE_STATE.get() {
+            unallow_write_buf();
+
+            if CONSOLE_WRITE_DATA.take().is_some() {
+                CONSOLE_WRITE_STATE.set(ConsoleWriteState::Nothing);
             } else {
-                ConsoleWrite::set_console_write_waker(cx);
-                Poll::Pending
+                CONSOLE_WRITE_STATE.set(ConsoleWriteState::Abandoned);
             }
         }
     }
@@ -180,6 +222,56 @@ impl Future for ConsoleWriter {
 
 pub type BytesWritten = usize;
 
+// Blocking writes for `embedded-hal` and `core::fmt`. These spin on `yieldk`
+// until the kernel has written everything.
+impl ConsoleWrite {
+    fn write_all(&self, bytes: &[u8]) -> Result<()> {
+        for chunk in bytes.chunks(CONSOLE_WRITE_BUF.with(|buf| buf.len())) {
+            block_on(self.write(chunk)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl serial::Write<u8> for ConsoleWrite {
+    type Error = Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+        let writer = ConsoleWrite::write(self, &[word]).map_err(nb_error)?;
+
+        block_on(writer).map(|_| ()).map_err(nb::Error::Other)
+    }
+
+    // Writes are complete by the time they return, unless an async write is
+    // still running.
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        if CONSOLE_WRITE_STATE.get() != ConsoleWriteState::Nothing {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        Ok(())
+    }
+}
+
+impl blocking::serial::Write<u8> for ConsoleWrite {
+    type Error = Error;
+
+    fn bwrite_all(&mut self, buffer: &[u8]) -> Result<()> {
+        self.write_all(buffer)
+    }
+
+    fn bflush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Write for ConsoleWrite {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
 pub struct ConsoleWriteStr<'a> {
     buf: &'a mut [u8],
     offset: usize,
@@ -187,10 +279,7 @@ pub struct ConsoleWriteStr<'a> {
 
 impl<'a> ConsoleWriteStr<'a> {
     pub fn new(buf: &'a mut [u8]) -> ConsoleWriteStr<'a> {
-        ConsoleWriteStr {
-            buf: buf,
-            offset: 0,
-        }
+        ConsoleWriteStr { buf, offset: 0 }
     }
 
     pub fn get_offset(&self) -> usize {
@@ -221,3 +310,68 @@ impl<'a> fmt::Write for ConsoleWriteStr<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{self, host};
+
+    // Writes everything it is asked to in one go
+    struct FakeConsole;
+
+    impl host::Driver for FakeConsole {
+        fn command(&mut self, _minor: usize, len: usize, _arg2: usize) -> isize {
+            host::schedule(DRIVER_NUM, subscribe_num::WRITE, len, 0, 0);
+            0
+        }
+    }
+
+    // The blocking write leaves its waker behind, the async write must replace it
+    #[test]
+    fn blocking_then_async() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeConsole)));
+
+        let mut console = ConsoleWrite::new();
+        assert!(fmt::Write::write_str(&mut console, "blocking").is_ok());
+
+        let writer = console.write(b"async").unwrap();
+        assert_eq!(host::run(writer), Ok(5));
+    }
+
+    // The kernel only sees the buffer while the write is running
+    #[test]
+    fn write_buffer_is_taken_back_on_completion() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeConsole)));
+
+        let console = ConsoleWrite::new();
+        let writer = console.write(b"hello").unwrap();
+        assert_eq!(
+            unsafe { host::readonly_buffer(DRIVER_NUM, allow_num::WRITE) },
+            Some(&b"hello"[..])
+        );
+
+        assert_eq!(host::run(writer), Ok(5));
+        assert!(unsafe { host::readonly_buffer(DRIVER_NUM, allow_num::WRITE) }.is_none());
+    }
+
+    // The console stays busy until the dropped write completes, and that
+    // completion is not taken for the next write's
+    #[test]
+    fn late_completion_of_dropped_write_is_discarded() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeConsole)));
+
+        let console = ConsoleWrite::new();
+        drop(console.write(b"dropped").unwrap());
+        assert!(unsafe { host::readonly_buffer(DRIVER_NUM, allow_num::WRITE) }.is_none());
+        assert_eq!(console.write(b"next").err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+
+        let writer = console.write(b"next").unwrap();
+        assert_eq!(host::run(writer), Ok(4));
+        assert_eq!(host::scheduled(), 0);
+    }
+}
@@ -0,0 +1,337 @@
+use crate::result::{Error, Result};
+
+#[cfg(not(feature = "crypto-soft"))]
+pub use self::hw::Aes128;
+#[cfg(feature = "crypto-soft")]
+pub use self::soft::Aes128;
+
+pub(crate) const DRIVER_NUM: usize = 0x40006;
+
+pub const BLOCK_LEN: usize = 16;
+
+pub const KEY_LEN: usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+    Ecb = 0,
+    Cbc = 1,
+    Ctr = 2,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Direction {
+    Encrypt,
+    Decrypt,
+}
+
+// `Aes128::new(key, mode, direction, iv)` sets up the cipher, ignoring `iv`
+// for ECB. `crypt(&mut self, src, dest)` can then be called any number of
+// times; CBC and CTR chain across calls, so a long message can be processed a
+// piece at a time. Only one `Aes128` can exist at a time, `new` fails with
+// `EBUSY` otherwise.
+
+fn check_lengths(src: &[u8], dest: &[u8]) -> Result<()> {
+    if src.len() != dest.len() || !src.len().is_multiple_of(BLOCK_LEN) {
+        Err(Error::EINVAL)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "crypto-soft"))]
+mod hw {
+    use core::future::Future;
+    use core::marker::PhantomData;
+    use core::pin::Pin;
+    use core::ptr;
+    use core::task::{Context, Poll};
+
+    use super::super::engine::{self, Engine};
+    use super::super::CHUNK_LEN;
+    use super::{check_lengths, Direction, Mode, BLOCK_LEN, DRIVER_NUM, KEY_LEN};
+    use crate::cell::RacyCell;
+    use crate::result::Result;
+    use crate::syscalls::{allow, allow_readonly, command};
+
+    mod allow_num {
+        pub const KEY: usize = 0;
+        pub const IV: usize = 1;
+        pub const SOURCE: usize = 2;
+        pub const DEST: usize = 3;
+    }
+
+    mod subscribe_num {
+        pub const CALLBACK: usize = 0;
+    }
+
+    mod command_num {
+        pub const SET_ALGORITHM: usize = 1;
+        pub const SETUP: usize = 2;
+        pub const CRYPT: usize = 3;
+        pub const FINISH: usize = 4;
+    }
+
+    // Copied here so that they stay allowed for as long as the `Aes128` lives
+    static AES_KEY: RacyCell<[u8; KEY_LEN]> = RacyCell::new([0; KEY_LEN]);
+    static AES_IV: RacyCell<[u8; BLOCK_LEN]> = RacyCell::new([0; BLOCK_LEN]);
+
+    pub struct Aes128 {
+        _private: (),
+    }
+
+    impl Aes128 {
+        pub fn new(
+            key: &[u8; KEY_LEN],
+            mode: Mode,
+            direction: Direction,
+            iv: &[u8; BLOCK_LEN],
+        ) -> Result<Aes128> {
+            engine::claim(Engine::Aes)?;
+
+            // Claimed from here on, so that an error releases it
+            let aes = Aes128 { _private: () };
+
+            AES_KEY.with(|aes_key| aes_key.copy_from_slice(key));
+            AES_IV.with(|aes_iv| aes_iv.copy_from_slice(iv));
+
+            unsafe {
+                let key_ptr = AES_KEY.as_ptr() as *const u8;
+                let iv_ptr = AES_IV.as_ptr() as *const u8;
+
+                let _ = allow_readonly(DRIVER_NUM, allow_num::KEY, key_ptr, KEY_LEN)
+                    .and_then(|_| allow_readonly(DRIVER_NUM, allow_num::IV, iv_ptr, BLOCK_LEN))
+                    .and_then(|_| {
+                        command(
+                            DRIVER_NUM,
+                            command_num::SET_ALGORITHM,
+                            mode as usize,
+                            (direction == Direction::Encrypt) as usize,
+                        )
+                    })
+                    .and_then(|_| command(DRIVER_NUM, command_num::SETUP, 0, 0))?;
+            }
+
+            Ok(aes)
+        }
+
+        pub fn crypt<'a>(
+            &'a mut self,
+            src: &'a [u8],
+            dest: &'a mut [u8],
+        ) -> Result<impl Future<Output = Result<()>> + 'a> {
+            check_lengths(src, dest)?;
+
+            Ok(AesCrypt {
+                src,
+                dest: dest.as_mut_ptr(),
+                offset: 0,
+                chunk_len: 0,
+                _dest: PhantomData,
+            })
+        }
+    }
+
+    impl Drop for Aes128 {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = command(DRIVER_NUM, command_num::FINISH, 0, 0);
+                let _ = allow_readonly(DRIVER_NUM, allow_num::KEY, ptr::null(), 0);
+                let _ = allow_readonly(DRIVER_NUM, allow_num::IV, ptr::null(), 0);
+            }
+
+            AES_KEY.with(|key| key.iter_mut().for_each(|x| *x = 0));
+
+            engine::release(Engine::Aes);
+        }
+    }
+
+    // Future returned by Aes128::crypt. It borrows the caller's buffers for as
+    // long as the kernel may access them.
+    struct AesCrypt<'a> {
+        src: &'a [u8],
+        dest: *mut u8,
+        offset: usize,
+        // Length of the chunk in flight, 0 if there is none
+        chunk_len: usize,
+        _dest: PhantomData<&'a mut [u8]>,
+    }
+
+    impl<'a> AesCrypt<'a> {
+        unsafe fn start_chunk(&mut self) -> Result<()> {
+            let len = core::cmp::min(CHUNK_LEN, self.src.len() - self.offset);
+
+            allow_readonly(
+                DRIVER_NUM,
+                allow_num::SOURCE,
+                self.src[self.offset..].as_ptr(),
+                len,
+            )
+            .and_then(|_| allow(DRIVER_NUM, allow_num::DEST, self.dest.add(self.offset), len))
+            .and_then(|_| {
+                engine::start(
+                    Engine::Aes,
+                    subscribe_num::CALLBACK,
+                    command_num::CRYPT,
+                    0,
+                    0,
+                )
+            })?;
+
+            self.chunk_len = len;
+
+            Ok(())
+        }
+    }
+
+    impl<'a> Future for AesCrypt<'a> {
+        type Output = Result<()>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let this = &mut *self;
+
+            loop {
+                if this.chunk_len != 0 {
+                    match engine::poll(Engine::Aes, cx) {
+                        Poll::Ready(Ok(_)) => {
+                            this.offset += this.chunk_len;
+                            this.chunk_len = 0;
+                        }
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+
+                if this.offset == this.src.len() {
+                    return Poll::Ready(Ok(()));
+                }
+
+                if let Err(e) = unsafe { this.start_chunk() } {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+    }
+
+    impl<'a> Drop for AesCrypt<'a> {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = allow_readonly(DRIVER_NUM, allow_num::SOURCE, ptr::null(), 0);
+                let _ = allow(DRIVER_NUM, allow_num::DEST, ptr::null_mut(), 0);
+            }
+        }
+    }
+}
+
+// generic-array 0.14.9 deprecates itself in favour of 1.x, which the `cipher`
+// traits of `aes-soft` 0.6 still take
+#[cfg(feature = "crypto-soft")]
+#[allow(deprecated)]
+mod soft {
+    use aes_soft::cipher::generic_array::GenericArray;
+    use aes_soft::cipher::{BlockCipher, NewBlockCipher};
+    use core::future::Future;
+
+    use super::super::engine::{self, Engine};
+    use super::super::ready::Ready;
+    use super::{check_lengths, Direction, Mode, BLOCK_LEN, KEY_LEN};
+    use crate::result::Result;
+
+    pub struct Aes128 {
+        cipher: aes_soft::Aes128,
+        mode: Mode,
+        direction: Direction,
+        // The previous ciphertext block for CBC, the counter for CTR
+        iv: [u8; BLOCK_LEN],
+    }
+
+    impl Aes128 {
+        pub fn new(
+            key: &[u8; KEY_LEN],
+            mode: Mode,
+            direction: Direction,
+            iv: &[u8; BLOCK_LEN],
+        ) -> Result<Aes128> {
+            engine::claim(Engine::Aes)?;
+
+            Ok(Aes128 {
+                cipher: aes_soft::Aes128::new(GenericArray::from_slice(key)),
+                mode,
+                direction,
+                iv: *iv,
+            })
+        }
+
+        pub fn crypt(
+            &mut self,
+            src: &[u8],
+            dest: &mut [u8],
+        ) -> Result<impl Future<Output = Result<()>>> {
+            check_lengths(src, dest)?;
+
+            for (s, d) in src.chunks(BLOCK_LEN).zip(dest.chunks_mut(BLOCK_LEN)) {
+                self.crypt_block(s, d);
+            }
+
+            Ok(Ready::new(Ok(())))
+        }
+
+        fn crypt_block(&mut self, src: &[u8], dest: &mut [u8]) {
+            match (self.mode, self.direction) {
+                (Mode::Ecb, Direction::Encrypt) => {
+                    dest.copy_from_slice(src);
+                    self.cipher
+                        .encrypt_block(GenericArray::from_mut_slice(dest));
+                }
+                (Mode::Ecb, Direction::Decrypt) => {
+                    dest.copy_from_slice(src);
+                    self.cipher
+                        .decrypt_block(GenericArray::from_mut_slice(dest));
+                }
+                (Mode::Cbc, Direction::Encrypt) => {
+                    xor(dest, src, &self.iv);
+                    self.cipher
+                        .encrypt_block(GenericArray::from_mut_slice(dest));
+                    self.iv.copy_from_slice(dest);
+                }
+                (Mode::Cbc, Direction::Decrypt) => {
+                    let mut block = [0; BLOCK_LEN];
+                    block.copy_from_slice(src);
+                    self.cipher
+                        .decrypt_block(GenericArray::from_mut_slice(&mut block));
+                    xor(dest, &block, &self.iv);
+                    self.iv.copy_from_slice(src);
+                }
+                // Decryption is the same as encryption
+                (Mode::Ctr, _) => {
+                    let mut keystream = self.iv;
+                    self.cipher
+                        .encrypt_block(GenericArray::from_mut_slice(&mut keystream));
+                    xor(dest, src, &keystream);
+                    increment(&mut self.iv);
+                }
+            }
+        }
+    }
+
+    impl Drop for Aes128 {
+        fn drop(&mut self) {
+            engine::release(Engine::Aes);
+        }
+    }
+
+    fn xor(dest: &mut [u8], a: &[u8], b: &[u8]) {
+        for ((d, a), b) in dest.iter_mut().zip(a).zip(b) {
+            *d = a ^ b;
+        }
+    }
+
+    // Big-endian increment of the whole counter block
+    fn increment(counter: &mut [u8; BLOCK_LEN]) {
+        for byte in counter.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use core::future::Future;
+
+use crate::result::Result;
+
+#[cfg(not(feature = "crypto-soft"))]
+use self::hw as backend;
+#[cfg(feature = "crypto-soft")]
+use self::soft as backend;
+
+pub(crate) const DRIVER_NUM: usize = 0x40002;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Algorithm {
+    Crc32 = 0,
+    Crc32c = 1,
+}
+
+impl Algorithm {
+    // Both are reflected, start from all ones and invert the result
+    fn get_polynomial(&self) -> u32 {
+        match self {
+            Algorithm::Crc32 => 0xedb8_8320,
+            Algorithm::Crc32c => 0x82f6_3b78,
+        }
+    }
+}
+
+// Bitwise CRC update without the initial and final inversion, so that it can
+// be chained over several slices.
+pub(crate) fn update_raw(algorithm: Algorithm, mut crc: u32, data: &[u8]) -> u32 {
+    let poly = algorithm.get_polynomial();
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Streaming CRC. Feed it with `update` and read the CRC of everything so far
+/// with `get_crc`.
+pub struct Crc {
+    algorithm: Algorithm,
+    crc: u32,
+}
+
+impl Crc {
+    pub fn new(algorithm: Algorithm) -> Crc {
+        // The CRC of nothing
+        Crc { algorithm, crc: 0 }
+    }
+
+    pub fn update<'a>(
+        &'a mut self,
+        data: &'a [u8],
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        backend::update(self, data)
+    }
+
+    pub fn get_crc(&self) -> u32 {
+        self.crc
+    }
+}
+
+#[cfg(not(feature = "crypto-soft"))]
+mod hw {
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::ptr;
+    use core::task::{Context, Poll};
+
+    use super::super::engine::{self, Engine};
+    use super::super::CHUNK_LEN;
+    use super::{Crc, DRIVER_NUM};
+    use crate::result::Result;
+    use crate::syscalls::allow_readonly;
+
+    mod allow_num {
+        pub const BUFFER: usize = 0;
+    }
+
+    mod subscribe_num {
+        pub const CALLBACK: usize = 0;
+    }
+
+    mod command_num {
+        pub const COMPUTE: usize = 2;
+    }
+
+    pub(super) fn update<'a>(crc: &'a mut Crc, data: &'a [u8]) -> Result<CrcUpdate<'a>> {
+        engine::claim(Engine::Crc)?;
+
+        Ok(CrcUpdate {
+            crc,
+            data,
+            offset: 0,
+            chunk_len: 0,
+        })
+    }
+
+    // The capsule computes a complete CRC over the allowed buffer and cannot
+    // pick up where the previous chunk left off. Instead, the CRC of each chunk
+    // is folded into the running one in userspace.
+    fn combine(poly: u32, crc1: u32, crc2: u32, mut len2: usize) -> u32 {
+        if len2 == 0 {
+            return crc1;
+        }
+
+        // Operator for appending one, two and four zero bits, as in zlib's
+        // `crc32_combine`
+        let mut even = [0u32; 32];
+        let mut odd = [0u32; 32];
+
+        odd[0] = poly;
+        for (n, row) in odd.iter_mut().enumerate().skip(1) {
+            *row = 1 << (n - 1);
+        }
+
+        gf2_matrix_square(&mut even, &odd);
+        gf2_matrix_square(&mut odd, &even);
+
+        let mut crc1 = crc1;
+
+        loop {
+            gf2_matrix_square(&mut even, &odd);
+            if len2 & 1 != 0 {
+                crc1 = gf2_matrix_times(&even, crc1);
+            }
+            len2 >>= 1;
+            if len2 == 0 {
+                break;
+            }
+
+            gf2_matrix_square(&mut odd, &even);
+            if len2 & 1 != 0 {
+                crc1 = gf2_matrix_times(&odd, crc1);
+            }
+            len2 >>= 1;
+            if len2 == 0 {
+                break;
+            }
+        }
+
+        crc1 ^ crc2
+    }
+
+    fn gf2_matrix_times(mat: &[u32; 32], mut vec: u32) -> u32 {
+        let mut sum = 0;
+        let mut i = 0;
+
+        while vec != 0 {
+            if vec & 1 != 0 {
+                sum ^= mat[i];
+            }
+            vec >>= 1;
+            i += 1;
+        }
+
+        sum
+    }
+
+    fn gf2_matrix_square(square: &mut [u32; 32], mat: &[u32; 32]) {
+        for n in 0..32 {
+            square[n] = gf2_matrix_times(mat, mat[n]);
+        }
+    }
+
+    // Future returned by Crc::update
+    pub(super) struct CrcUpdate<'a> {
+        crc: &'a mut Crc,
+        data: &'a [u8],
+        offset: usize,
+        // Length of the chunk in flight, 0 if there is none
+        chunk_len: usize,
+    }
+
+    impl<'a> CrcUpdate<'a> {
+        unsafe fn start_chunk(&mut self) -> Result<()> {
+            let end = core::cmp::min(self.offset + CHUNK_LEN, self.data.len());
+            let chunk = &self.data[self.offset..end];
+
+            allow_readonly(DRIVER_NUM, allow_num::BUFFER, chunk.as_ptr(), chunk.len()).and_then(
+                |_| {
+                    engine::start(
+                        Engine::Crc,
+                        subscribe_num::CALLBACK,
+                        command_num::COMPUTE,
+                        self.crc.algorithm as usize,
+                        0,
+                    )
+                },
+            )?;
+
+            self.chunk_len = chunk.len();
+
+            Ok(())
+        }
+    }
+
+    impl<'a> Future for CrcUpdate<'a> {
+        type Output = Result<()>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let this = &mut *self;
+
+            loop {
+                if this.chunk_len != 0 {
+                    match engine::poll(Engine::Crc, cx) {
+                        Poll::Ready(Ok(chunk_crc)) => {
+                            this.crc.crc = combine(
+                                this.crc.algorithm.get_polynomial(),
+                                this.crc.crc,
+                                chunk_crc as u32,
+                                this.chunk_len,
+                            );
+                            this.offset += this.chunk_len;
+                            this.chunk_len = 0;
+                        }
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+
+                if this.offset == this.data.len() {
+                    return Poll::Ready(Ok(()));
+                }
+
+                if let Err(e) = unsafe { this.start_chunk() } {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+    }
+
+    impl<'a> Drop for CrcUpdate<'a> {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = allow_readonly(DRIVER_NUM, allow_num::BUFFER, ptr::null(), 0);
+            }
+
+            engine::release(Engine::Crc);
+        }
+    }
+}
+
+#[cfg(feature = "crypto-soft")]
+mod soft {
+    use super::super::engine::{self, Engine};
+    use super::super::ready::Ready;
+    use super::{update_raw, Crc};
+    use crate::result::Result;
+
+    // Done before returning, so the claim is only held for the computation
+    pub(super) fn update(crc: &mut Crc, data: &[u8]) -> Result<Ready<Result<()>>> {
+        engine::claim(Engine::Crc)?;
+
+        crc.crc = !update_raw(crc.algorithm, !crc.crc, data);
+
+        engine::release(Engine::Crc);
+
+        Ok(Ready::new(Ok(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::host;
+
+    const CHECK: &[u8] = b"123456789";
+
+    #[cfg(not(feature = "crypto-soft"))]
+    mod hw {
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::task::{Context, Waker};
+
+        use super::*;
+        use crate::result::Error;
+
+        // Answers every computation with the CRC-32 of `CHECK`
+        struct FakeCrc;
+
+        impl host::Driver for FakeCrc {
+            fn command(&mut self, _minor: usize, _algorithm: usize, _arg2: usize) -> isize {
+                host::schedule(DRIVER_NUM, 0, 0, 0xcbf4_3926, 0);
+                0
+            }
+        }
+
+        // A poll with another waker must not keep the engine on the first one
+        #[test]
+        fn repoll_with_new_waker() {
+            let _lock = host::lock();
+            host::install(DRIVER_NUM, Box::leak(Box::new(FakeCrc)));
+
+            let mut crc = Crc::new(Algorithm::Crc32);
+            let mut update = crc.update(CHECK).unwrap();
+
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(unsafe { Pin::new_unchecked(&mut update) }
+                .poll(&mut cx)
+                .is_pending());
+
+            let mut other = Crc::new(Algorithm::Crc32);
+            assert_eq!(other.update(CHECK).err(), Some(Error::EBUSY));
+
+            assert_eq!(host::run(update), Ok(()));
+            assert_eq!(crc.get_crc(), 0xcbf4_3926);
+        }
+    }
+
+    #[cfg(feature = "crypto-soft")]
+    #[test]
+    fn soft_check_value() {
+        let mut crc = Crc::new(Algorithm::Crc32);
+        assert_eq!(host::run(crc.update(CHECK).unwrap()), Ok(()));
+
+        assert_eq!(crc.get_crc(), 0xcbf4_3926);
+        assert!(Crc::new(Algorithm::Crc32).update(CHECK).is_ok());
+    }
+}
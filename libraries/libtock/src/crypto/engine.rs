@@ -0,0 +1,142 @@
+use crate::cell::RacyCell;
+use crate::result::{Error, Result};
+
+#[cfg(not(feature = "crypto-soft"))]
+pub(super) use self::hw::{poll, start};
+
+// The crypto capsules all work the same way: buffers are allowed, a command
+// starts the operation and a callback carries the status in `arg0` and, for
+// CRC, the result in `arg1`. Each capsule serves one operation at a time, so a
+// second user is rejected with `EBUSY` until the first one releases it. The
+// software implementations keep to the same rule.
+#[derive(Copy, Clone, PartialEq)]
+pub(super) enum Engine {
+    Crc = 0,
+    Sha = 1,
+    Hmac = 2,
+    Aes = 3,
+}
+
+const NUM_ENGINES: usize = 4;
+
+static ENGINE_CLAIMED: RacyCell<[bool; NUM_ENGINES]> = RacyCell::new([false; NUM_ENGINES]);
+
+pub(super) fn claim(engine: Engine) -> Result<()> {
+    ENGINE_CLAIMED.with(|claimed| {
+        if claimed[engine as usize] {
+            return Err(Error::EBUSY);
+        }
+
+        claimed[engine as usize] = true;
+
+        Ok(())
+    })
+}
+
+pub(super) fn release(engine: Engine) {
+    ENGINE_CLAIMED.with(|claimed| claimed[engine as usize] = false);
+}
+
+#[cfg(not(feature = "crypto-soft"))]
+mod hw {
+    use core::task::{Context, Poll, Waker};
+
+    use super::super::{aes, crc, sha};
+    use super::{Engine, NUM_ENGINES};
+    use crate::cell::{register_waker, RacyCell};
+    use crate::result::{Result, UsizeError};
+    use crate::syscalls::{command, subscribe, CallbackData};
+
+    impl Engine {
+        pub(in crate::crypto) fn get_driver_num(&self) -> usize {
+            match self {
+                Engine::Crc => crc::DRIVER_NUM,
+                Engine::Sha => sha::SHA_DRIVER_NUM,
+                Engine::Hmac => sha::HMAC_DRIVER_NUM,
+                Engine::Aes => aes::DRIVER_NUM,
+            }
+        }
+
+        fn get_callback(&self) -> extern "C" fn(usize, usize, usize, usize) {
+            match self {
+                Engine::Crc => crc_callback,
+                Engine::Sha => sha_callback,
+                Engine::Hmac => hmac_callback,
+                Engine::Aes => aes_callback,
+            }
+        }
+    }
+
+    // Indexed by `Engine`
+    static ENGINE_DATA: RacyCell<[Option<CallbackData>; NUM_ENGINES]> =
+        RacyCell::new([None, None, None, None]);
+
+    static ENGINE_WAKERS: RacyCell<[Option<Waker>; NUM_ENGINES]> =
+        RacyCell::new([None, None, None, None]);
+
+    fn engine_callback(engine: Engine, cb_data: CallbackData) {
+        ENGINE_DATA.with(|data| data[engine as usize] = Some(cb_data));
+
+        if let Some(waker) = ENGINE_WAKERS.with(|wakers| wakers[engine as usize].clone()) {
+            waker.wake();
+        }
+    }
+
+    extern "C" fn crc_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+        engine_callback(Engine::Crc, CallbackData::new(arg0, arg1, arg2, userdata));
+    }
+
+    extern "C" fn sha_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+        engine_callback(Engine::Sha, CallbackData::new(arg0, arg1, arg2, userdata));
+    }
+
+    extern "C" fn hmac_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+        engine_callback(Engine::Hmac, CallbackData::new(arg0, arg1, arg2, userdata));
+    }
+
+    extern "C" fn aes_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+        engine_callback(Engine::Aes, CallbackData::new(arg0, arg1, arg2, userdata));
+    }
+
+    // Subscribe to `subscribe_num` and issue `cmd`. The buffers must already be
+    // allowed.
+    pub(in crate::crypto) unsafe fn start(
+        engine: Engine,
+        subscribe_num: usize,
+        cmd: usize,
+        arg1: usize,
+        arg2: usize,
+    ) -> Result<()> {
+        ENGINE_DATA.with(|data| data[engine as usize] = None);
+
+        let _ = subscribe(
+            engine.get_driver_num(),
+            subscribe_num,
+            engine.get_callback() as *const _,
+            0,
+        )
+        .and_then(|_| command(engine.get_driver_num(), cmd, arg1, arg2))?;
+
+        Ok(())
+    }
+
+    // Resolves to `arg1` of the callback once the operation started by `start` is
+    // done.
+    pub(in crate::crypto) fn poll(engine: Engine, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        match ENGINE_DATA.with(|data| data[engine as usize].take()) {
+            Some(cb_data) => {
+                let status: UsizeError = cb_data.get_arg0().into();
+
+                Poll::Ready(match status.0 {
+                    Some(e) => Err(e),
+                    None => Ok(cb_data.get_arg1()),
+                })
+            }
+            None => {
+                ENGINE_WAKERS
+                    .with(|wakers| register_waker(&mut wakers[engine as usize], cx.waker()));
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,22 @@
+// Bindings for the kernel's crypto accelerators: AES, SHA-256 and HMAC, and
+// CRC. Inputs longer than `CHUNK_LEN` are fed to the capsule a chunk at a time
+// straight out of the caller's buffers, which stay borrowed by the returned
+// futures until the kernel is done with them.
+//
+// With the `crypto-soft` feature, the same API is backed by software
+// implementations instead, for boards that lack the capsules. The futures are
+// then ready on their first poll.
+
+pub mod aes;
+pub mod crc;
+pub mod sha;
+
+mod engine;
+
+#[cfg(feature = "crypto-soft")]
+mod ready;
+
+// Largest slice allowed to a capsule in one go. A multiple of the AES block
+// size.
+#[cfg(not(feature = "crypto-soft"))]
+const CHUNK_LEN: usize = 256;
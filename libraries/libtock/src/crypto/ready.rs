@@ -0,0 +1,22 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+// Future returned by the software implementations, which complete right away
+pub(super) struct Ready<T>(Option<T>);
+
+impl<T> Ready<T> {
+    pub(super) fn new(t: T) -> Ready<T> {
+        Ready(Some(t))
+    }
+}
+
+impl<T> Unpin for Ready<T> {}
+
+impl<T> Future for Ready<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Ready(self.0.take().expect("Ready polled after completion"))
+    }
+}
@@ -0,0 +1,412 @@
+#[cfg(not(feature = "crypto-soft"))]
+pub use self::hw::{HmacSha256, Sha256};
+#[cfg(feature = "crypto-soft")]
+pub use self::soft::{HmacSha256, Sha256};
+
+pub(crate) const SHA_DRIVER_NUM: usize = 0x40005;
+pub(crate) const HMAC_DRIVER_NUM: usize = 0x40003;
+
+pub const DIGEST_LEN: usize = 32;
+
+pub type Digest = [u8; DIGEST_LEN];
+
+// `Sha256` and `HmacSha256` both offer:
+//
+// - `update(&mut self, data)`, which can be called any number of times, and
+//   returns a future that completes once `data` has been consumed.
+// - `finalize(self)`, which returns a future of the digest.
+//
+// Only one of each can exist at a time, `new` fails with `EBUSY` otherwise.
+
+#[cfg(not(feature = "crypto-soft"))]
+mod hw {
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::ptr;
+    use core::task::{Context, Poll};
+
+    use super::super::engine::{self, Engine};
+    use super::super::CHUNK_LEN;
+    use super::{Digest, DIGEST_LEN, HMAC_DRIVER_NUM};
+    use crate::cell::RacyCell;
+    use crate::result::{Error, Result};
+    use crate::syscalls::{allow, allow_readonly, command};
+
+    // The SHA and HMAC capsules share the same interface, apart from the key
+    mod allow_num {
+        pub const KEY: usize = 0;
+        pub const DATA: usize = 1;
+        pub const DEST: usize = 2;
+    }
+
+    mod subscribe_num {
+        pub const DONE: usize = 0;
+        pub const UPDATE_DONE: usize = 1;
+    }
+
+    mod command_num {
+        pub const SET_ALGORITHM: usize = 1;
+        pub const UPDATE: usize = 3;
+        pub const FINISH: usize = 4;
+    }
+
+    mod algorithm {
+        pub const SHA256: usize = 0;
+    }
+
+    // Longest key the HMAC capsule takes, one SHA-256 block
+    const MAX_KEY_LEN: usize = 64;
+
+    // The key has to stay allowed for the whole operation, so it is copied
+    // here rather than borrowed from the caller.
+    static HMAC_KEY: RacyCell<[u8; MAX_KEY_LEN]> = RacyCell::new([0; MAX_KEY_LEN]);
+
+    // Indexed by `Engine`, only `Sha` and `Hmac` are used
+    static DIGEST_BUF: RacyCell<[Digest; 3]> = RacyCell::new([[0; DIGEST_LEN]; 3]);
+
+    unsafe fn set_algorithm(engine: Engine) -> Result<()> {
+        command(
+            engine.get_driver_num(),
+            command_num::SET_ALGORITHM,
+            algorithm::SHA256,
+            0,
+        )
+        .map(|_| ())
+    }
+
+    pub struct Sha256 {
+        _private: (),
+    }
+
+    impl Sha256 {
+        pub fn new() -> Result<Sha256> {
+            engine::claim(Engine::Sha)?;
+
+            // Claimed from here on, so that an error releases it
+            let sha = Sha256 { _private: () };
+
+            unsafe {
+                set_algorithm(Engine::Sha)?;
+            }
+
+            Ok(sha)
+        }
+
+        pub fn update<'a>(
+            &'a mut self,
+            data: &'a [u8],
+        ) -> Result<impl Future<Output = Result<()>> + 'a> {
+            Ok(DigestUpdate::new(Engine::Sha, data))
+        }
+
+        pub fn finalize(self) -> Result<impl Future<Output = Result<Digest>>> {
+            DigestFinish::new(Engine::Sha, self)
+        }
+    }
+
+    impl Drop for Sha256 {
+        fn drop(&mut self) {
+            engine::release(Engine::Sha);
+        }
+    }
+
+    pub struct HmacSha256 {
+        _private: (),
+    }
+
+    impl HmacSha256 {
+        // Keys longer than 64 bytes have to be hashed beforehand
+        pub fn new(key: &[u8]) -> Result<HmacSha256> {
+            if key.len() > MAX_KEY_LEN {
+                return Err(Error::ESIZE);
+            }
+
+            engine::claim(Engine::Hmac)?;
+
+            let hmac = HmacSha256 { _private: () };
+
+            HMAC_KEY.with(|hmac_key| hmac_key[..key.len()].copy_from_slice(key));
+
+            unsafe {
+                allow_readonly(
+                    HMAC_DRIVER_NUM,
+                    allow_num::KEY,
+                    HMAC_KEY.as_ptr() as *const u8,
+                    key.len(),
+                )?;
+
+                set_algorithm(Engine::Hmac)?;
+            }
+
+            Ok(hmac)
+        }
+
+        pub fn update<'a>(
+            &'a mut self,
+            data: &'a [u8],
+        ) -> Result<impl Future<Output = Result<()>> + 'a> {
+            Ok(DigestUpdate::new(Engine::Hmac, data))
+        }
+
+        pub fn finalize(self) -> Result<impl Future<Output = Result<Digest>>> {
+            DigestFinish::new(Engine::Hmac, self)
+        }
+    }
+
+    impl Drop for HmacSha256 {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = allow_readonly(HMAC_DRIVER_NUM, allow_num::KEY, ptr::null(), 0);
+            }
+
+            HMAC_KEY.with(|key| key.iter_mut().for_each(|x| *x = 0));
+
+            engine::release(Engine::Hmac);
+        }
+    }
+
+    // Future returned by Sha256::update and HmacSha256::update. The mutable
+    // borrow of the owner keeps a second update or the finalize from starting
+    // while this one is running.
+    struct DigestUpdate<'a> {
+        engine: Engine,
+        data: &'a [u8],
+        offset: usize,
+        // Length of the chunk in flight, 0 if there is none
+        chunk_len: usize,
+    }
+
+    impl<'a> DigestUpdate<'a> {
+        fn new(engine: Engine, data: &'a [u8]) -> DigestUpdate<'a> {
+            DigestUpdate {
+                engine,
+                data,
+                offset: 0,
+                chunk_len: 0,
+            }
+        }
+
+        unsafe fn start_chunk(&mut self) -> Result<()> {
+            let end = core::cmp::min(self.offset + CHUNK_LEN, self.data.len());
+            let chunk = &self.data[self.offset..end];
+
+            allow_readonly(
+                self.engine.get_driver_num(),
+                allow_num::DATA,
+                chunk.as_ptr(),
+                chunk.len(),
+            )
+            .and_then(|_| {
+                engine::start(
+                    self.engine,
+                    subscribe_num::UPDATE_DONE,
+                    command_num::UPDATE,
+                    0,
+                    0,
+                )
+            })?;
+
+            self.chunk_len = chunk.len();
+
+            Ok(())
+        }
+    }
+
+    impl<'a> Future for DigestUpdate<'a> {
+        type Output = Result<()>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let this = &mut *self;
+
+            loop {
+                if this.chunk_len != 0 {
+                    match engine::poll(this.engine, cx) {
+                        Poll::Ready(Ok(_)) => {
+                            this.offset += this.chunk_len;
+                            this.chunk_len = 0;
+                        }
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+
+                if this.offset == this.data.len() {
+                    return Poll::Ready(Ok(()));
+                }
+
+                if let Err(e) = unsafe { this.start_chunk() } {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+    }
+
+    impl<'a> Drop for DigestUpdate<'a> {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = allow_readonly(
+                    self.engine.get_driver_num(),
+                    allow_num::DATA,
+                    ptr::null(),
+                    0,
+                );
+            }
+        }
+    }
+
+    // Future returned by Sha256::finalize and HmacSha256::finalize. It owns the
+    // `Sha256` or `HmacSha256`, so the capsule is released when it is dropped.
+    struct DigestFinish<T> {
+        engine: Engine,
+        _owner: T,
+    }
+
+    impl<T> DigestFinish<T> {
+        fn new(engine: Engine, owner: T) -> Result<DigestFinish<T>> {
+            let finish = DigestFinish {
+                engine,
+                _owner: owner,
+            };
+
+            unsafe {
+                let digest_ptr = (DIGEST_BUF.as_ptr() as *mut Digest).add(engine as usize);
+
+                allow(
+                    engine.get_driver_num(),
+                    allow_num::DEST,
+                    digest_ptr as *mut u8,
+                    DIGEST_LEN,
+                )
+                .and_then(|_| {
+                    engine::start(engine, subscribe_num::DONE, command_num::FINISH, 0, 0)
+                })?;
+            }
+
+            Ok(finish)
+        }
+    }
+
+    impl<T> Future for DigestFinish<T> {
+        type Output = Result<Digest>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            engine::poll(self.engine, cx).map(|r| {
+                // Take the digest buffer back before reading from it
+                unallow_digest(self.engine);
+
+                r.map(|_| DIGEST_BUF.with(|digests| digests[self.engine as usize]))
+            })
+        }
+    }
+
+    impl<T> Drop for DigestFinish<T> {
+        fn drop(&mut self) {
+            unallow_digest(self.engine);
+        }
+    }
+
+    fn unallow_digest(engine: Engine) {
+        unsafe {
+            let _ = allow(engine.get_driver_num(), allow_num::DEST, ptr::null_mut(), 0);
+        }
+    }
+}
+
+#[cfg(feature = "crypto-soft")]
+mod soft {
+    use core::future::Future;
+    use hmac::Mac;
+    use sha2::Digest as _;
+
+    use super::super::engine::{self, Engine};
+    use super::super::ready::Ready;
+    use super::Digest;
+    use crate::result::{Error, Result};
+
+    pub struct Sha256 {
+        inner: sha2::Sha256,
+    }
+
+    impl Sha256 {
+        pub fn new() -> Result<Sha256> {
+            engine::claim(Engine::Sha)?;
+
+            Ok(Sha256 {
+                inner: sha2::Sha256::new(),
+            })
+        }
+
+        pub fn update(&mut self, data: &[u8]) -> Result<impl Future<Output = Result<()>>> {
+            self.inner.input(data);
+
+            Ok(Ready::new(Ok(())))
+        }
+
+        pub fn finalize(self) -> Result<impl Future<Output = Result<Digest>>> {
+            let mut digest = [0; super::DIGEST_LEN];
+            digest.copy_from_slice(&self.inner.clone().result());
+
+            Ok(Ready::new(Ok(digest)))
+        }
+    }
+
+    impl Drop for Sha256 {
+        fn drop(&mut self) {
+            engine::release(Engine::Sha);
+        }
+    }
+
+    pub struct HmacSha256 {
+        inner: hmac::Hmac<sha2::Sha256>,
+    }
+
+    impl HmacSha256 {
+        pub fn new(key: &[u8]) -> Result<HmacSha256> {
+            let inner = hmac::Hmac::new_varkey(key).map_err(|_| Error::EINVAL)?;
+
+            engine::claim(Engine::Hmac)?;
+
+            Ok(HmacSha256 { inner })
+        }
+
+        pub fn update(&mut self, data: &[u8]) -> Result<impl Future<Output = Result<()>>> {
+            self.inner.input(data);
+
+            Ok(Ready::new(Ok(())))
+        }
+
+        pub fn finalize(self) -> Result<impl Future<Output = Result<Digest>>> {
+            let mut digest = [0; super::DIGEST_LEN];
+            digest.copy_from_slice(&self.inner.clone().result().code());
+
+            Ok(Ready::new(Ok(digest)))
+        }
+    }
+    impl Drop for HmacSha256 {
+        fn drop(&mut self) {
+            engine::release(Engine::Hmac);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "crypto-soft"))]
+mod tests {
+    use super::*;
+    use crate::result::Error;
+    use crate::syscalls::host;
+
+    #[test]
+    fn soft_one_at_a_time() {
+        let sha = Sha256::new().unwrap();
+        assert_eq!(Sha256::new().err(), Some(Error::EBUSY));
+
+        assert!(host::run(sha.finalize().unwrap()).is_ok());
+        assert!(Sha256::new().is_ok());
+
+        let hmac = HmacSha256::new(b"key").unwrap();
+        assert_eq!(HmacSha256::new(b"key").err(), Some(Error::EBUSY));
+
+        drop(hmac);
+        assert!(HmacSha256::new(b"key").is_ok());
+    }
+}
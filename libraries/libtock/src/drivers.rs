@@ -0,0 +1,253 @@
+use core::fmt;
+
+use crate::adc::{self, Adc};
+use crate::alarm;
+use crate::ble;
+use crate::crypto::{aes, crc, sha};
+use crate::gpio::{self, Gpio};
+use crate::i2c;
+use crate::ipc;
+use crate::net;
+use crate::ninedof;
+use crate::result::Error;
+use crate::rng;
+use crate::screen;
+use crate::sensors;
+use crate::spi;
+use crate::storage;
+use crate::syscalls::command;
+
+// Every capsule answers command 0. Some return a driver specific count (number
+// of LEDs, number of buttons), the rest just return success.
+mod command_num {
+    pub const DRIVER_EXISTS: usize = 0;
+}
+
+// Drivers that can be compiled out with cargo features. They are probed all the
+// same, so their numbers live here, where they are always compiled, and their
+// modules take them from here.
+pub(crate) mod driver_num {
+    pub const CONSOLE: usize = 1;
+    pub const LED: usize = 2;
+    pub const BUTTON: usize = 3;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Driver {
+    Console,
+    Led,
+    Button,
+    Gpio,
+    I2c,
+    Spi,
+    Adc,
+    Alarm,
+    Temperature,
+    Humidity,
+    AmbientLight,
+    Ninedof,
+    Rng,
+    Storage,
+    Ipc,
+    Crc,
+    Sha,
+    Hmac,
+    Aes,
+    Screen,
+    Udp,
+    Ble,
+}
+
+const NUM_DRIVERS: usize = 22;
+
+const DRIVERS: [Driver; NUM_DRIVERS] = [
+    Driver::Console,
+    Driver::Led,
+    Driver::Button,
+    Driver::Gpio,
+    Driver::I2c,
+    Driver::Spi,
+    Driver::Adc,
+    Driver::Alarm,
+    Driver::Temperature,
+    Driver::Humidity,
+    Driver::AmbientLight,
+    Driver::Ninedof,
+    Driver::Rng,
+    Driver::Storage,
+    Driver::Ipc,
+    Driver::Crc,
+    Driver::Sha,
+    Driver::Hmac,
+    Driver::Aes,
+    Driver::Screen,
+    Driver::Udp,
+    Driver::Ble,
+];
+
+impl Driver {
+    pub fn get_driver_num(&self) -> usize {
+        match self {
+            Driver::Console => driver_num::CONSOLE,
+            Driver::Led => driver_num::LED,
+            Driver::Button => driver_num::BUTTON,
+            Driver::Gpio => gpio::DRIVER_NUM,
+            Driver::I2c => i2c::DRIVER_NUM,
+            Driver::Spi => spi::DRIVER_NUM,
+            Driver::Adc => adc::DRIVER_NUM,
+            Driver::Alarm => alarm::DRIVER_NUM,
+            Driver::Temperature => sensors::TEMPERATURE_DRIVER_NUM,
+            Driver::Humidity => sensors::HUMIDITY_DRIVER_NUM,
+            Driver::AmbientLight => sensors::AMBIENT_LIGHT_DRIVER_NUM,
+            Driver::Ninedof => ninedof::DRIVER_NUM,
+            Driver::Rng => rng::DRIVER_NUM,
+            Driver::Storage => storage::DRIVER_NUM,
+            Driver::Ipc => ipc::DRIVER_NUM,
+            Driver::Crc => crc::DRIVER_NUM,
+            Driver::Sha => sha::SHA_DRIVER_NUM,
+            Driver::Hmac => sha::HMAC_DRIVER_NUM,
+            Driver::Aes => aes::DRIVER_NUM,
+            Driver::Screen => screen::DRIVER_NUM,
+            Driver::Udp => net::DRIVER_NUM,
+            Driver::Ble => ble::DRIVER_NUM,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Driver::Console => "console",
+            Driver::Led => "led",
+            Driver::Button => "button",
+            Driver::Gpio => "gpio",
+            Driver::I2c => "i2c",
+            Driver::Spi => "spi",
+            Driver::Adc => "adc",
+            Driver::Alarm => "alarm",
+            Driver::Temperature => "temperature",
+            Driver::Humidity => "humidity",
+            Driver::AmbientLight => "ambient light",
+            Driver::Ninedof => "ninedof",
+            Driver::Rng => "rng",
+            Driver::Storage => "storage",
+            Driver::Ipc => "ipc",
+            Driver::Crc => "crc",
+            Driver::Sha => "sha",
+            Driver::Hmac => "hmac",
+            Driver::Aes => "aes",
+            Driver::Screen => "screen",
+            Driver::Udp => "udp",
+            Driver::Ble => "ble",
+        }
+    }
+
+    fn probe(&self) -> DriverStatus {
+        let res = match self {
+            // The existence command of these two returns the count
+            Driver::Led | Driver::Button => unsafe {
+                command(self.get_driver_num(), command_num::DRIVER_EXISTS, 0, 0)
+                    .map(DriverStatus::Count)
+            },
+            Driver::Gpio => Gpio::new().get_num_pins().map(DriverStatus::Count),
+            Driver::Adc => Adc::new().get_num_channels().map(DriverStatus::Count),
+            _ => unsafe {
+                command(self.get_driver_num(), command_num::DRIVER_EXISTS, 0, 0)
+                    .map(|_| DriverStatus::Present)
+            },
+        };
+
+        match res {
+            Ok(status) => status,
+            // The kernel answers `ENODEVICE` when there is no capsule for the
+            // driver number. Any other error comes from the capsule itself.
+            Err(Error::ENODEVICE) => DriverStatus::Absent,
+            Err(_) => DriverStatus::Present,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DriverStatus {
+    Absent,
+    Present,
+    Count(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DriverInfo {
+    driver: Driver,
+    status: DriverStatus,
+}
+
+impl DriverInfo {
+    pub fn get_driver(&self) -> Driver {
+        self.driver
+    }
+
+    pub fn get_status(&self) -> DriverStatus {
+        self.status
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.status != DriverStatus::Absent
+    }
+
+    pub fn get_count(&self) -> Option<usize> {
+        match self.status {
+            DriverStatus::Count(c) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+/// Returned by [`probe`]. Lists every driver libtock knows about, in a fixed
+/// order, along with whether the board provides it.
+pub struct ProbeReport {
+    drivers: [DriverInfo; NUM_DRIVERS],
+}
+
+impl ProbeReport {
+    pub fn iter(&self) -> impl Iterator<Item = &DriverInfo> {
+        self.drivers.iter()
+    }
+
+    pub fn get(&self, driver: Driver) -> DriverInfo {
+        // Every `Driver` has an entry, so this cannot fail
+        *self.drivers.iter().find(|d| d.driver == driver).unwrap()
+    }
+
+    pub fn is_present(&self, driver: Driver) -> bool {
+        self.get(driver).is_present()
+    }
+}
+
+// One line per driver, for printing a board summary at boot.
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for d in self.iter() {
+            match d.status {
+                DriverStatus::Absent => writeln!(f, "{}: absent", d.driver.get_name())?,
+                DriverStatus::Present => writeln!(f, "{}: present", d.driver.get_name())?,
+                DriverStatus::Count(c) => writeln!(f, "{}: {}", d.driver.get_name(), c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Issue the driver-existence command for each driver libtock knows about.
+pub fn probe() -> ProbeReport {
+    let mut drivers = [DriverInfo {
+        driver: Driver::Console,
+        status: DriverStatus::Absent,
+    }; NUM_DRIVERS];
+
+    for (info, driver) in drivers.iter_mut().zip(DRIVERS.iter()) {
+        *info = DriverInfo {
+            driver: *driver,
+            status: driver.probe(),
+        };
+    }
+
+    ProbeReport { drivers }
+}
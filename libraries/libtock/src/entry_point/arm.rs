@@ -0,0 +1,57 @@
+use core::arch::naked_asm;
+
+/// Tock programs' entry point. Called by the kernel at program start. Sets up
+/// the stack then calls rust_start() for the remainder of setup.
+///
+/// The kernel passes app_start, mem_start, memory_len and app_heap_break in
+/// r0-r3.
+#[doc(hidden)]
+#[no_mangle]
+#[unsafe(naked)]
+#[link_section = ".start"]
+pub unsafe extern "C" fn _start(
+    _app_start: usize,
+    _mem_start: usize,
+    _memory_len: usize,
+    _app_heap_break: usize,
+) -> ! {
+    naked_asm!(
+        "
+        // Because ROPI-RWPI support in LLVM/rustc is incomplete, Rust
+        // applications must be statically linked. An offset between the
+        // location the program is linked at and its actual location in flash
+        // would cause references in .data and .rodata to point to the wrong
+        // data. To mitigate this, this section checks that .text (and .start)
+        // are loaded at the correct location. If the application was linked and
+        // loaded correctly, the location of the first instruction (read using
+        // the Program Counter) will match the intended location of .start. We
+        // don't have an easy way to signal an error, so for now we just yield
+        // if the location is wrong.
+        sub r4, pc, #4    // r4 = pc
+        ldr r5, =.start   // r5 = address of .start
+        cmp r4, r5
+        beq .Lstack_init  // Jump to stack initialization if pc was correct
+        .Lyield_loop:
+        movs r0, #1       // yield-wait in Tock 2.0, ignored by Tock 1.x
+        svc 0             // yield() syscall
+        b .Lyield_loop
+
+        .Lstack_init:
+        // Compute the stacktop (stack_start). The stacktop is computed as
+        // stack_size + mem_start plus padding to align the stack to a multiple
+        // of 8 bytes. The 8 byte alignment is to follow ARM AAPCS:
+        // http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.faqs/ka4127.html
+        ldr r4, [r0, #36]  // r4 = app_start->stack_size
+        add r4, r4, r1     // r4 = app_start->stack_size + mem_start
+        add r4, #7         // r4 = app_start->stack_size + mem_start + 7
+        bic r4, r4, #7     // r4 = (app_start->stack_size + mem_start + 7) & ~0x7
+        mov sp, r4         // sp = r4
+
+        // Setup parameters needed by rust_start
+        // r0 (app_start), r1 (stacktop)
+        mov r1, sp
+
+        // Call rust_start
+        bl rust_start"
+    );
+}
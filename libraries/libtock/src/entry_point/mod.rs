@@ -0,0 +1,187 @@
+use core::intrinsics;
+use core::ptr;
+
+#[cfg(target_arch = "arm")]
+mod arm;
+#[cfg(target_arch = "arm")]
+pub use self::arm::_start;
+
+#[cfg(target_arch = "riscv32")]
+mod riscv32;
+#[cfg(target_arch = "riscv32")]
+pub use self::riscv32::_start;
+
+use crate::syscalls;
+
+use crate::FUTURE_ALLOCS;
+
+// _start and rust_start are the first two procedures executed when a Tock
+// application starts. _start is invoked directly by the Tock kernel; it
+// performs stack setup then calls rust_start. rust_start tells the kernel about
+// the stack and heap, performs data relocation and sets up the heap before
+// calling the rustc-generated main.
+// rust_start and _start are tightly coupled.
+//
+// The memory layout is controlled by the linker script.
+//
+// When the kernel gives control to us, we get r0-r3 (a0-a3 on RISC-V) values
+// that is as follows.
+//
+//     +--------------+ <- (r2) mem.len()
+//     | Grant        |
+//     +--------------+
+//     | Unused       |
+//  S  +--------------+ <- (r3) app_heap_break
+//  R  | Heap         |         (hardcoded to mem_start + 3072 in
+//  A  +--------------|          Processs::create which could be lesser than
+//  M  | .bss         |          mem_start + stack + .data + .bss)
+//     +--------------|
+//     | .data        |
+//     +--------------+
+//     | Stack        |
+//     +--------------+ <- (r1) mem_start
+//
+//     +--------------+
+//     | .text        |
+//  F  +--------------+
+//  L  | .crt0_header |
+//  A  +--------------+ <- (r0) app_start
+//  S  | Protected    |
+//  H  | Region       |
+//     +--------------+
+//
+// We want to organize the memory as follows.
+//
+//     +--------------+ <- app_heap_break
+//     | Heap         |
+//     +--------------| <- heap_start
+//     | .bss         |
+//     +--------------|
+//     | .data        |
+//     +--------------+ <- stack_start (stacktop)
+//     | Stack        |
+//     | (grows down) |
+//     +--------------+ <- mem_start
+//
+// app_heap_break and mem_start are given to us by the kernel. The stack size is
+// determined using pointer app_start, and is used with mem_start to compute
+// stack_start (stacktop). The placement of .data and .bss are given to us by
+// the linker script; the heap is located between the end of .bss and
+// app_heap_break. This requires that .bss is the last (highest-address) section
+// placed by the linker script.
+
+/// The header encoded at the beginning of .text by the linker script. It is
+/// accessed by rust_start() using its app_start parameter.
+#[repr(C)]
+struct LayoutHeader {
+    got_sym_start: usize,
+    got_start: usize,
+    got_size: usize,
+    data_sym_start: usize,
+    data_start: usize,
+    data_size: usize,
+    bss_start: usize,
+    bss_size: usize,
+    reldata_start: usize,
+    stack_size: usize,
+}
+
+/// Rust setup, called by _start. Uses the extern "C" calling convention so that
+/// the assembly in _start knows how to call it (the Rust ABI is not defined).
+/// Sets up the data segment (including relocations) and the heap, then calls
+/// into the rustc-generated main(). This cannot use mutable global variables or
+/// global references to globals until it is done setting up the data segment.
+///
+/// # Safety
+///
+/// Only _start may call this, once, with the addresses the kernel passed it.
+#[no_mangle]
+pub unsafe extern "C" fn rust_start(app_start: usize, stacktop: usize) -> ! {
+    extern "C" {
+        // This function is created internally by `rustc`. See
+        // `src/lang_items.rs` for more details.
+        fn main(argc: isize, argv: *const *const u8) -> isize;
+    }
+
+    let layout_header = &*(app_start as *const LayoutHeader);
+
+    // Debug support, tell the kernel the stack location
+    let _ = syscalls::memop(10, stacktop);
+
+    // Debug support, tell the kernel the heap_start location
+    let app_heap_break = layout_header.bss_start + layout_header.bss_size;
+    let _ = syscalls::memop(11, app_heap_break);
+
+    // There is a possibility that stack + .data + .bss is greater than 3072.
+    // Therefore setup the initial app_heap_break to heap_start (that is zero
+    // initial heap) and determine below where the actual app_heap_break should
+    // go.
+    //
+    // Also, because app_heap_break is where the unprivileged MPU region ends,
+    // in case mem_start + stack + .data + .bss is greater than initial
+    // app_heap_break (mem_start + 3072), we will get a memory fault when
+    // initializing .data and .bss. Setting app_heap_break to heap_start avoids
+    // that.
+    //
+    // These memops are made from here rather than from `_start`, so that the
+    // assembly does not depend on the syscall ABI.
+    let _ = syscalls::memop(0, app_heap_break);
+
+    // NOTE: If there is a hard-fault before this point, then
+    //       process_detail_fmt in kernel/src/process.rs panics which will
+    //       result in us losing the PC of the instruction generating the
+    //       hard-fault. Therefore any code before this point is critical code
+
+    // Copy .data into its final location in RAM (determined by the linker
+    // script -- should be immediately above the stack).
+
+    let data_flash_start_addr = app_start + layout_header.data_sym_start;
+
+    intrinsics::copy_nonoverlapping(
+        data_flash_start_addr as *const u8,
+        stacktop as *mut u8,
+        layout_header.data_size,
+    );
+
+    // Zero .bss (specified by the linker script).
+    let bss_end = layout_header.bss_start + layout_header.bss_size; // 1 past the end of .bss
+    for i in layout_header.bss_start..bss_end {
+        core::ptr::write(i as *mut u8, 0);
+    }
+
+    // TODO: Wait for rustc to have working ROPI-RWPI relocation support, then
+    // implement dynamic relocations here. At the moment, rustc does not have
+    // working ROPI-RWPI support, and it is not clear what that support would
+    // look like at the LLVM level. Once we know what the relocation strategy
+    // looks like we can write the dynamic linker.
+
+    // Initialize the heap.
+    //
+    // The heap only holds the future arenas, so it is sized to the ones that
+    // are compiled in. `elf2tab` reserves room for it with `--app-heap`, which
+    // has to be at least `MAX_HEAP_SIZE`.
+    const MAX_HEAP_SIZE: usize = 4096;
+    const FUTURE_ALLOC_SIZE: usize = 512;
+
+    let heap_size = FUTURE_ALLOCS.len() * FUTURE_ALLOC_SIZE;
+    debug_assert!(heap_size <= MAX_HEAP_SIZE);
+
+    // we could have also used bss_end for app_heap_start;
+    let app_heap_start = app_heap_break;
+    let app_heap_end = app_heap_break + heap_size;
+
+    // tell the kernel the new app heap break
+    syscalls::memop(0, app_heap_end).unwrap();
+
+    // Partition heap
+    for (i, (_, future_alloc)) in FUTURE_ALLOCS.iter().enumerate() {
+        let future_alloc_start = app_heap_start + i * FUTURE_ALLOC_SIZE;
+
+        future_alloc.with(|heap| heap.init(future_alloc_start, FUTURE_ALLOC_SIZE));
+    }
+
+    let completion_code = main(0, ptr::null());
+
+    // Nothing left to run. Return to the kernel instead of spinning.
+    syscalls::exit(completion_code as usize)
+}
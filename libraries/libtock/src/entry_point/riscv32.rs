@@ -0,0 +1,58 @@
+use core::arch::naked_asm;
+
+// a0 for the yield in `_start`: the yield class in the Tock 1.x ABI, and a
+// yield-wait in Tock 2.0. A yield-no-wait (0) would have the kernel write its
+// flag through whatever a1 holds.
+#[cfg(not(feature = "tock2"))]
+const YIELD_A0: usize = 0;
+#[cfg(feature = "tock2")]
+const YIELD_A0: usize = 1;
+
+/// Tock programs' entry point. Called by the kernel at program start. Sets up
+/// the stack then calls rust_start() for the remainder of setup.
+///
+/// This mirrors the ARM `_start`, with the kernel passing app_start, mem_start,
+/// memory_len and app_heap_break in a0-a3.
+#[doc(hidden)]
+#[no_mangle]
+#[unsafe(naked)]
+#[link_section = ".start"]
+pub unsafe extern "C" fn _start(
+    _app_start: usize,
+    _mem_start: usize,
+    _memory_len: usize,
+    _app_heap_break: usize,
+) -> ! {
+    naked_asm!("
+        // See the ARM `_start` for why we check that .start is loaded at the
+        // location it was linked at. If it is not, we just yield forever.
+        auipc s0, 0             // s0 = pc
+        lui   s1, %hi(.start)
+        addi  s1, s1, %lo(.start) // s1 = address of .start
+        beq   s0, s1, .Lstack_init
+        .Lyield_loop:
+        li    a0, {yield_a0}    // yield() syscall. The Tock 1.x ABI takes the
+        li    a4, 0             // syscall class in a0, Tock 2.0 takes it in a4
+        ecall                   // and the kind of yield in a0, see YIELD_A0.
+        j     .Lyield_loop
+
+        .Lstack_init:
+        // Compute the stacktop (stack_start). The stacktop is computed as
+        // stack_size + mem_start plus padding to align the stack to a multiple
+        // of 8 bytes. This has to agree with the ALIGN(8) after the .stack
+        // section in layout.ld, because rust_start copies .data to stacktop.
+        lw    t0, 36(a0)        // t0 = app_start->stack_size
+        add   t0, t0, a1        // t0 = app_start->stack_size + mem_start
+        addi  t0, t0, 7         // t0 = app_start->stack_size + mem_start + 7
+        andi  t0, t0, -8        // t0 = (app_start->stack_size + mem_start + 7) & ~0x7
+        mv    sp, t0            // sp = t0
+
+        // Setup parameters needed by rust_start
+        // a0 (app_start), a1 (stacktop)
+        mv    a1, sp
+
+        // Call rust_start
+        jal   rust_start",
+        yield_a0 = const YIELD_A0,
+    );
+}
@@ -0,0 +1,418 @@
+use core::alloc::Layout;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::mem;
+use core::pin::Pin;
+use core::ptr::{self, NonNull};
+use core::task::{Context, Poll, Waker};
+
+use allocator_api::alloc::handle_alloc_error;
+use allocator_api::{Alloc, AllocErr};
+use futures_core::future::{LocalFutureObj, UnsafeFutureObj};
+
+#[cfg(any(feature = "button", feature = "console"))]
+use super::ArenaStats;
+use crate::cell::RacyCell;
+
+#[cfg(feature = "button")]
+use crate::BUTTON_FUTURE_ALLOC;
+#[cfg(feature = "console")]
+use crate::CONSOLE_READ_FUTURE_ALLOC;
+#[cfg(feature = "console")]
+use crate::CONSOLE_WRITE_FUTURE_ALLOC;
+
+/// An allocator that can tell which pointers it handed out. `Fallback` needs
+/// this to give memory back to the right arena.
+///
+/// # Safety
+///
+/// `Fallback` frees into whichever allocator claims the pointer, so `owns` must
+/// only claim pointers that this allocator handed out.
+pub unsafe trait Arena: Alloc {
+    fn owns(&self, ptr: NonNull<u8>) -> bool;
+}
+
+#[cfg(feature = "button")]
+pub struct ButtonFutureAlloc;
+
+#[cfg(feature = "button")]
+impl ButtonFutureAlloc {
+    pub fn stats(&self) -> ArenaStats {
+        BUTTON_FUTURE_ALLOC.with(|heap| heap.stats())
+    }
+}
+
+#[cfg(feature = "button")]
+unsafe impl Alloc for ButtonFutureAlloc {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        BUTTON_FUTURE_ALLOC.with(|heap| heap.alloc(layout))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        BUTTON_FUTURE_ALLOC.with(|heap| heap.dealloc(ptr, layout))
+    }
+}
+
+#[cfg(feature = "button")]
+unsafe impl Arena for ButtonFutureAlloc {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        BUTTON_FUTURE_ALLOC.with(|heap| heap.owns(ptr))
+    }
+}
+
+#[cfg(feature = "console")]
+pub struct ConsoleWriteFutureAlloc;
+
+#[cfg(feature = "console")]
+impl ConsoleWriteFutureAlloc {
+    pub fn stats(&self) -> ArenaStats {
+        CONSOLE_WRITE_FUTURE_ALLOC.with(|heap| heap.stats())
+    }
+}
+
+#[cfg(feature = "console")]
+unsafe impl Alloc for ConsoleWriteFutureAlloc {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        CONSOLE_WRITE_FUTURE_ALLOC.with(|heap| heap.alloc(layout))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        CONSOLE_WRITE_FUTURE_ALLOC.with(|heap| heap.dealloc(ptr, layout))
+    }
+}
+
+#[cfg(feature = "console")]
+unsafe impl Arena for ConsoleWriteFutureAlloc {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        CONSOLE_WRITE_FUTURE_ALLOC.with(|heap| heap.owns(ptr))
+    }
+}
+
+#[cfg(feature = "console")]
+pub struct ConsoleReadFutureAlloc;
+
+#[cfg(feature = "console")]
+impl ConsoleReadFutureAlloc {
+    pub fn stats(&self) -> ArenaStats {
+        CONSOLE_READ_FUTURE_ALLOC.with(|heap| heap.stats())
+    }
+}
+
+#[cfg(feature = "console")]
+unsafe impl Alloc for ConsoleReadFutureAlloc {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        CONSOLE_READ_FUTURE_ALLOC.with(|heap| heap.alloc(layout))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        CONSOLE_READ_FUTURE_ALLOC.with(|heap| heap.dealloc(ptr, layout))
+    }
+}
+
+#[cfg(feature = "console")]
+unsafe impl Arena for ConsoleReadFutureAlloc {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        CONSOLE_READ_FUTURE_ALLOC.with(|heap| heap.owns(ptr))
+    }
+}
+
+/// Allocates from `A`, and from `B` once `A` is exhausted. For instance
+/// `Fallback::new(ConsoleWriteFutureAlloc, ConsoleReadFutureAlloc)` lets a
+/// burst of writes spill over into the read arena.
+pub struct Fallback<A, B> {
+    primary: A,
+    fallback: B,
+}
+
+impl<A: Arena, B: Alloc> Fallback<A, B> {
+    pub fn new(primary: A, fallback: B) -> Fallback<A, B> {
+        Fallback { primary, fallback }
+    }
+}
+
+unsafe impl<A: Arena, B: Alloc> Alloc for Fallback<A, B> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        self.primary
+            .alloc(layout)
+            .or_else(|_| self.fallback.alloc(layout))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if self.primary.owns(ptr) {
+            self.primary.dealloc(ptr, layout)
+        } else {
+            self.fallback.dealloc(ptr, layout)
+        }
+    }
+}
+
+unsafe impl<A: Arena, B: Arena> Arena for Fallback<A, B> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.primary.owns(ptr) || self.fallback.owns(ptr)
+    }
+}
+
+// How many `WaitNew` can wait at the same time. Any more are polled again
+// straight away until a slot frees up.
+const MAX_WAIT_NEW: usize = 4;
+
+// Woken when a `FutureBox` gives its memory back, one per waiting `WaitNew`
+static FUTURE_ALLOC_WAKERS: RacyCell<[Option<Waker>; MAX_WAIT_NEW]> =
+    RacyCell::new([None, None, None, None]);
+
+// What a `FutureBox` points to. The allocator is kept next to the future, so
+// that the memory can be given back once the box has been turned into a raw
+// `dyn Future` pointer.
+struct Raw<F, A> {
+    future: F,
+    alloc: A,
+}
+
+impl<F: Future, A> Future for Raw<F, A> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The future is never moved out of `Raw`
+        unsafe { self.map_unchecked_mut(|raw| &mut raw.future) }.poll(cx)
+    }
+}
+
+/// A future allocated with `A`, to be turned into a `LocalFutureObj`. Any
+/// `Alloc` works, such as the driver arenas in this module.
+///
+/// What happens when `A` is out of memory is up to the caller: `try_new`
+/// fails with `AllocErr`, `Fallback` tries a second allocator, and `wait_new`
+/// waits until another `FutureBox` is freed. `new` aborts.
+pub struct FutureBox<F, A>
+where
+    A: Alloc,
+{
+    raw: NonNull<Raw<F, A>>,
+    _marker: PhantomData<Raw<F, A>>,
+}
+
+impl<F, A> FutureBox<F, A>
+where
+    A: Alloc,
+{
+    pub fn new(f: F, a: A) -> FutureBox<F, A> {
+        FutureBox::try_new(f, a).unwrap_or_else(|_| handle_alloc_error(Layout::new::<Raw<F, A>>()))
+    }
+
+    pub fn try_new(f: F, a: A) -> Result<FutureBox<F, A>, AllocErr> {
+        FutureBox::try_alloc(f, a).map_err(|_| AllocErr)
+    }
+
+    // `try_new` straight into a `LocalFutureObj`
+    pub fn try_local<'a>(f: F, a: A) -> Result<LocalFutureObj<'a, F::Output>, AllocErr>
+    where
+        F: Future + 'a,
+        A: 'a,
+    {
+        FutureBox::try_new(f, a).map(LocalFutureObj::new)
+    }
+
+    // Resolves once `a` has room for the future
+    pub fn wait_new(f: F, a: A) -> WaitNew<F, A> {
+        WaitNew {
+            parts: Some((f, a)),
+        }
+    }
+
+    // Hands `f` and `a` back when out of memory, so that `WaitNew` can retry
+    fn try_alloc(f: F, a: A) -> Result<FutureBox<F, A>, (F, A)> {
+        let mut a = a;
+        let layout = Layout::new::<Raw<F, A>>();
+
+        let raw = if layout.size() == 0 {
+            NonNull::dangling()
+        } else {
+            match unsafe { a.alloc(layout) } {
+                Ok(raw) => raw.cast(),
+                Err(_) => return Err((f, a)),
+            }
+        };
+
+        unsafe {
+            ptr::write(
+                raw.as_ptr(),
+                Raw {
+                    future: f,
+                    alloc: a,
+                },
+            );
+        }
+
+        Ok(FutureBox {
+            raw,
+            _marker: PhantomData,
+        })
+    }
+
+    // Drop the future in place, as it may be pinned, then free its memory with
+    // the allocator stored next to it.
+    unsafe fn free(raw: NonNull<Raw<F, A>>) {
+        let raw = raw.as_ptr();
+
+        ptr::drop_in_place(&mut (*raw).future);
+
+        let mut alloc = ptr::read(&(*raw).alloc);
+        let layout = Layout::new::<Raw<F, A>>();
+
+        if layout.size() != 0 {
+            alloc.dealloc(NonNull::new_unchecked(raw).cast(), layout);
+        }
+
+        // Every waiter retries, as any of them may fit in the memory given back
+        for i in 0..MAX_WAIT_NEW {
+            if let Some(waker) = FUTURE_ALLOC_WAKERS.with(|wakers| wakers[i].take()) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// Only reached if the box is dropped before being turned into a future object
+impl<F, A> Drop for FutureBox<F, A>
+where
+    A: Alloc,
+{
+    fn drop(&mut self) {
+        unsafe { Self::free(self.raw) }
+    }
+}
+
+unsafe impl<'a, T, F, A> UnsafeFutureObj<'a, T> for FutureBox<F, A>
+where
+    F: Future<Output = T> + 'a,
+    A: Alloc + 'a,
+{
+    fn into_raw(self) -> *mut (dyn Future<Output = T> + 'a) {
+        let raw = self.raw.as_ptr();
+        mem::forget(self);
+
+        raw
+    }
+
+    unsafe fn drop(ptr: *mut (dyn Future<Output = T> + 'a)) {
+        Self::free(NonNull::new_unchecked(ptr as *mut Raw<F, A>));
+    }
+}
+
+/// Future returned by `FutureBox::wait_new`. Allocation is retried every time
+/// a `FutureBox` is freed. Up to four can wait at once, and any more keep
+/// retrying each time they are polled.
+pub struct WaitNew<F, A> {
+    parts: Option<(F, A)>,
+}
+
+impl<F, A> Future for WaitNew<F, A>
+where
+    A: Alloc,
+{
+    type Output = FutureBox<F, A>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The future has not been polled yet, so it may still be moved
+        let this = unsafe { self.get_unchecked_mut() };
+        let (f, a) = this.parts.take().expect("WaitNew polled after completion");
+
+        match FutureBox::try_alloc(f, a) {
+            Ok(b) => Poll::Ready(b),
+            Err(parts) => {
+                this.parts = Some(parts);
+                register_wait_new(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Keep `waker` until the next `FutureBox` is freed, next to those of the other
+// waiting `WaitNew`
+fn register_wait_new(waker: &Waker) {
+    let registered = FUTURE_ALLOC_WAKERS.with(|wakers| {
+        if wakers.iter().flatten().any(|w| w.will_wake(waker)) {
+            return true;
+        }
+
+        match wakers.iter_mut().find(|w| w.is_none()) {
+            Some(slot) => {
+                *slot = Some(waker.clone());
+                true
+            }
+            None => false,
+        }
+    });
+
+    if !registered {
+        waker.wake_by_ref();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::ready;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    use crate::syscalls::host;
+
+    // Room for a single future at a time
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    static mut SLOT: [u64; 4] = [0; 4];
+
+    struct OneSlot;
+
+    unsafe impl Alloc for OneSlot {
+        unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+            if layout.size() > mem::size_of_val(&*ptr::addr_of!(SLOT))
+                || layout.align() > mem::align_of::<u64>()
+                || TAKEN.swap(true, Ordering::SeqCst)
+            {
+                return Err(AllocErr);
+            }
+
+            Ok(NonNull::new_unchecked(ptr::addr_of_mut!(SLOT).cast()))
+        }
+
+        unsafe fn dealloc(&mut self, _ptr: NonNull<u8>, _layout: Layout) {
+            TAKEN.store(false, Ordering::SeqCst);
+        }
+    }
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn every_waiter_is_woken() {
+        let _lock = host::lock();
+
+        let held = FutureBox::new(ready(0u8), OneSlot);
+        assert!(FutureBox::try_new(ready(1u8), OneSlot).is_err());
+
+        let flags: Vec<Arc<Flag>> = (0..2)
+            .map(|_| Arc::new(Flag(AtomicBool::new(false))))
+            .collect();
+        let mut waiting: Vec<_> = (0..2)
+            .map(|i| Box::pin(FutureBox::wait_new(ready(i as u8), OneSlot)))
+            .collect();
+
+        for (future, flag) in waiting.iter_mut().zip(&flags) {
+            let waker = Waker::from(flag.clone());
+            let mut cx = Context::from_waker(&waker);
+            assert!(future.as_mut().poll(&mut cx).is_pending());
+        }
+
+        drop(held);
+
+        assert!(flags.iter().all(|flag| flag.0.load(Ordering::SeqCst)));
+    }
+}
@@ -0,0 +1,158 @@
+use core::alloc::Layout;
+use core::cmp;
+use core::fmt;
+use core::ptr::NonNull;
+
+use allocator_api::{Alloc, AllocErr};
+use linked_list_allocator::Heap;
+
+#[cfg(feature = "console")]
+use core::fmt::Write;
+
+#[cfg(feature = "console")]
+use crate::console_write::ConsoleWrite;
+#[cfg(feature = "console")]
+use crate::FUTURE_ALLOCS;
+
+/// Usage of a `TrackedHeap` since it was initialized. Sizes are the ones asked
+/// for; the heap rounds each allocation up a little, so `in_use` can reach
+/// `size` slightly later than the arena actually fills up.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ArenaStats {
+    size: usize,
+    in_use: usize,
+    peak: usize,
+    allocs: usize,
+    failed: usize,
+    largest: usize,
+}
+
+impl ArenaStats {
+    const fn empty() -> ArenaStats {
+        ArenaStats {
+            size: 0,
+            in_use: 0,
+            peak: 0,
+            allocs: 0,
+            failed: 0,
+            largest: 0,
+        }
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get_in_use(&self) -> usize {
+        self.in_use
+    }
+
+    // Highest `in_use` seen. An arena can be shrunk down to a bit above this.
+    pub fn get_peak(&self) -> usize {
+        self.peak
+    }
+
+    pub fn get_allocs(&self) -> usize {
+        self.allocs
+    }
+
+    pub fn get_failed(&self) -> usize {
+        self.failed
+    }
+
+    pub fn get_largest(&self) -> usize {
+        self.largest
+    }
+}
+
+impl fmt::Display for ArenaStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} bytes, peak {}, {} allocs, {} failed, largest {}",
+            self.in_use, self.size, self.peak, self.allocs, self.failed, self.largest
+        )
+    }
+}
+
+/// A `linked_list_allocator::Heap` that keeps `ArenaStats`. The driver future
+/// arenas are built on it, and so can an app's own arenas.
+pub struct TrackedHeap {
+    heap: Heap,
+    stats: ArenaStats,
+}
+
+impl TrackedHeap {
+    pub const fn empty() -> TrackedHeap {
+        TrackedHeap {
+            heap: Heap::empty(),
+            stats: ArenaStats::empty(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `bottom..bottom + size` must be unused memory that outlives the heap.
+    pub unsafe fn init(&mut self, bottom: usize, size: usize) {
+        self.heap.init(bottom, size);
+
+        self.stats = ArenaStats {
+            size,
+            ..ArenaStats::empty()
+        };
+    }
+
+    /// # Safety
+    ///
+    /// As for `Alloc::alloc`.
+    pub unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let res = self.heap.alloc(layout);
+        let stats = &mut self.stats;
+
+        match res {
+            Ok(_) => {
+                stats.allocs += 1;
+                stats.in_use += layout.size();
+                stats.peak = cmp::max(stats.peak, stats.in_use);
+                stats.largest = cmp::max(stats.largest, layout.size());
+            }
+            Err(_) => stats.failed += 1,
+        }
+
+        res
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have been handed out by `alloc` on this heap, for `layout`.
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.heap.dealloc(ptr, layout);
+
+        self.stats.in_use -= layout.size();
+    }
+
+    pub fn owns(&self, ptr: NonNull<u8>) -> bool {
+        let addr = ptr.as_ptr() as usize;
+
+        self.heap.bottom() <= addr && addr < self.heap.top()
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        self.stats
+    }
+}
+
+// Print the stats of every future arena over the console, one line each.
+// Blocks until it is written.
+#[cfg(feature = "console")]
+pub fn arena_report() -> fmt::Result {
+    let mut console = ConsoleWrite::new();
+
+    for (name, arena) in FUTURE_ALLOCS.iter() {
+        let stats = arena.with(|arena| arena.stats());
+
+        writeln!(console, "{}: {}", name, stats)?;
+    }
+
+    Ok(())
+}
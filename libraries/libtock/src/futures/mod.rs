@@ -0,0 +1,51 @@
+use core::future::Future;
+use core::ptr;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use crate::syscalls;
+
+// `FutureBox` and the arenas it allocates from
+#[cfg(feature = "futures-alloc")]
+mod alloc;
+
+// The heap the arenas are built on, with usage stats
+mod arena;
+
+#[cfg(feature = "futures-alloc")]
+pub use self::alloc::{Arena, Fallback, FutureBox, WaitNew};
+
+#[cfg(all(feature = "futures-alloc", feature = "button"))]
+pub use self::alloc::ButtonFutureAlloc;
+
+#[cfg(all(feature = "futures-alloc", feature = "console"))]
+pub use self::alloc::{ConsoleReadFutureAlloc, ConsoleWriteFutureAlloc};
+
+pub use self::arena::{ArenaStats, TrackedHeap};
+
+#[cfg(feature = "console")]
+pub use self::arena::arena_report;
+
+static NOOP_RAW_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    |_| RawWaker::new(ptr::null(), &NOOP_RAW_WAKER_VTABLE),
+    |_| (/* Noop */),
+    |_| (/* Noop */),
+    |_| (/* Noop */),
+);
+
+// Run a future to completion outside of the executor, for blocking APIs such as
+// the `embedded-hal` traits. Callbacks only ever run while we are in `yieldk`,
+// so polling once after each yield is enough and the waker can be a no-op.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    pin_utils::pin_mut!(future);
+
+    let waker = unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &NOOP_RAW_WAKER_VTABLE)) };
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(val) = future.as_mut().poll(&mut context) {
+            return val;
+        }
+
+        syscalls::yieldk();
+    }
+}
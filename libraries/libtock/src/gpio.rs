@@ -0,0 +1,442 @@
+use core::future::Future;
+use core::marker::PhantomData;
+use core::mem;
+use core::task::{Context, Poll, Waker};
+use futures_core::stream::Stream;
+
+use crate::cell::{register_waker, RacyCell};
+use crate::result::{Error, Result};
+use crate::syscalls::{command, subscribe, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = 4;
+
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const NUM_PINS: usize = 0;
+    pub const ENABLE_OUTPUT: usize = 1;
+    pub const SET: usize = 2;
+    pub const CLEAR: usize = 3;
+    pub const TOGGLE: usize = 4;
+    pub const ENABLE_INPUT: usize = 5;
+    pub const READ: usize = 6;
+    pub const ENABLE_INTERRUPT: usize = 7;
+    pub const DISABLE_INTERRUPT: usize = 8;
+    pub const DISABLE: usize = 9;
+}
+
+// Events and wakers are kept per pin, so that interrupts from several pins
+// are delivered to the right future or stream. Pins above `MAX_PINS` cannot be
+// used from libtock.
+const MAX_PINS: usize = 16;
+
+// Each pin has at most one handle, so that its mode cannot be changed behind
+// the back of another. The claim is released when the handle is dropped.
+static GPIO_PIN_CLAIMED: RacyCell<[bool; MAX_PINS]> = RacyCell::new([false; MAX_PINS]);
+
+static GPIO_PIN_EVENTS: RacyCell<[Option<PinLevel>; MAX_PINS]> = RacyCell::new([None; MAX_PINS]);
+
+static GPIO_PIN_WAKERS: RacyCell<[Option<Waker>; MAX_PINS]> = RacyCell::new([
+    None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+]);
+
+extern "C" fn gpio_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    let pin_num = cb_data.get_arg0();
+    if pin_num >= MAX_PINS {
+        return;
+    }
+
+    let level = if cb_data.get_arg1() == 0 {
+        PinLevel::Low
+    } else {
+        PinLevel::High
+    };
+
+    GPIO_PIN_EVENTS.with(|events| events[pin_num] = Some(level));
+
+    if let Some(waker) = GPIO_PIN_WAKERS.with(|wakers| wakers[pin_num].clone()) {
+        waker.wake();
+    }
+}
+
+pub struct Gpio;
+
+impl Gpio {
+    pub fn new() -> Gpio {
+        Gpio
+    }
+
+    pub fn get_num_pins(&self) -> Result<usize> {
+        unsafe { command(DRIVER_NUM, command_num::NUM_PINS, 0, 0) }
+    }
+
+    pub fn pin(&self, pin_num: usize) -> Result<Pin<Disabled>> {
+        if pin_num >= MAX_PINS || pin_num >= self.get_num_pins()? {
+            return Err(Error::EINVAL);
+        }
+
+        GPIO_PIN_CLAIMED.with(|claimed| {
+            if claimed[pin_num] {
+                return Err(Error::EBUSY);
+            }
+
+            claimed[pin_num] = true;
+
+            Ok(())
+        })?;
+
+        Ok(Pin::new(pin_num))
+    }
+}
+
+// Pin modes
+pub struct Disabled;
+pub struct Output;
+pub struct Input;
+
+/// A single GPIO pin, typed by its mode.
+pub struct Pin<MODE> {
+    num: usize,
+    _mode: PhantomData<MODE>,
+}
+
+impl<MODE> Pin<MODE> {
+    fn new(num: usize) -> Pin<MODE> {
+        Pin {
+            num,
+            _mode: PhantomData,
+        }
+    }
+
+    pub fn get_num(&self) -> usize {
+        self.num
+    }
+
+    // The claim on the pin moves to the returned handle
+    fn into_mode<NEW>(self) -> Pin<NEW> {
+        let num = self.num;
+        mem::forget(self);
+
+        Pin::new(num)
+    }
+
+    pub fn into_output(self) -> Result<Pin<Output>> {
+        unsafe { command(DRIVER_NUM, command_num::ENABLE_OUTPUT, self.num, 0) }
+            .map(|_| self.into_mode())
+    }
+
+    pub fn into_input(self, pull: Pull) -> Result<Pin<Input>> {
+        unsafe {
+            command(
+                DRIVER_NUM,
+                command_num::ENABLE_INPUT,
+                self.num,
+                pull as usize,
+            )
+        }
+        .map(|_| self.into_mode())
+    }
+
+    pub fn disable(self) -> Result<Pin<Disabled>> {
+        unsafe { command(DRIVER_NUM, command_num::DISABLE, self.num, 0) }.map(|_| self.into_mode())
+    }
+}
+
+impl<MODE> Drop for Pin<MODE> {
+    fn drop(&mut self) {
+        GPIO_PIN_CLAIMED.with(|claimed| claimed[self.num] = false);
+    }
+}
+
+impl Pin<Output> {
+    pub fn set(&self) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::SET, self.num, 0).map(|_| ()) }
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::CLEAR, self.num, 0).map(|_| ()) }
+    }
+
+    pub fn toggle(&self) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::TOGGLE, self.num, 0).map(|_| ()) }
+    }
+}
+
+impl Pin<Input> {
+    pub fn read(&self) -> Result<PinLevel> {
+        unsafe {
+            command(DRIVER_NUM, command_num::READ, self.num, 0).map(|r| {
+                if r == 0 {
+                    PinLevel::Low
+                } else {
+                    PinLevel::High
+                }
+            })
+        }
+    }
+
+    // Any event left over from a previous wait is dropped, so that only edges
+    // from this point on are reported.
+    fn enable_interrupt(&self, edge: Edge) -> Result<()> {
+        GPIO_PIN_EVENTS.with(|events| events[self.num] = None);
+
+        unsafe {
+            subscribe(
+                DRIVER_NUM,
+                subscribe_num::CALLBACK,
+                gpio_callback as *const _,
+                0,
+            )
+            .and_then(|_| {
+                command(
+                    DRIVER_NUM,
+                    command_num::ENABLE_INTERRUPT,
+                    self.num,
+                    edge as usize,
+                )
+            })
+            .map(|_| ())
+        }
+    }
+
+    pub fn wait_for_edge(&self, edge: Edge) -> Result<impl Future<Output = EdgeEvent> + '_> {
+        self.enable_interrupt(edge)?;
+
+        Ok(EdgeWaiter { pin: self, edge })
+    }
+
+    pub fn edges(&self, edge: Edge) -> Result<EdgeStream<'_>> {
+        self.enable_interrupt(edge)?;
+
+        Ok(EdgeStream { pin: self, edge })
+    }
+
+    fn set_pin_waker(&self, cx: &mut Context<'_>) {
+        GPIO_PIN_WAKERS.with(|wakers| register_waker(&mut wakers[self.num], cx.waker()));
+    }
+
+    fn poll_edge(&self, edge: Edge, cx: &mut Context<'_>) -> Poll<EdgeEvent> {
+        match GPIO_PIN_EVENTS.with(|events| events[self.num].take()) {
+            // The kernel only reports the configured edge, but check anyway
+            // in case another waiter on the same pin reconfigured it.
+            Some(level) if edge.matches(level) => Poll::Ready(EdgeEvent::new(self.num, level)),
+            _ => {
+                self.set_pin_waker(cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    fn disable_interrupt(&self) {
+        unsafe {
+            let _ = command(DRIVER_NUM, command_num::DISABLE_INTERRUPT, self.num, 0);
+        }
+    }
+}
+
+// Future returned by Pin::<Input>::wait_for_edge
+struct EdgeWaiter<'a> {
+    pin: &'a Pin<Input>,
+    edge: Edge,
+}
+
+impl<'a> Future for EdgeWaiter<'a> {
+    type Output = EdgeEvent;
+
+    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.pin.poll_edge(self.edge, cx)
+    }
+}
+
+impl<'a> Drop for EdgeWaiter<'a> {
+    fn drop(&mut self) {
+        self.pin.disable_interrupt();
+    }
+}
+
+/// Stream of edge events on a single pin, returned by
+/// `Pin::<Input>::edges`. The interrupt is disabled when it is dropped.
+pub struct EdgeStream<'a> {
+    pin: &'a Pin<Input>,
+    edge: Edge,
+}
+
+impl<'a> Stream for EdgeStream<'a> {
+    type Item = EdgeEvent;
+
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.pin.poll_edge(self.edge, cx).map(Some)
+    }
+}
+
+impl<'a> Drop for EdgeStream<'a> {
+    fn drop(&mut self) {
+        self.pin.disable_interrupt();
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Pull {
+    None = 0,
+    Up = 1,
+    Down = 2,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Edge {
+    Either = 0,
+    Rising = 1,
+    Falling = 2,
+}
+
+impl Edge {
+    fn matches(&self, level: PinLevel) -> bool {
+        match self {
+            Edge::Either => true,
+            Edge::Rising => level == PinLevel::High,
+            Edge::Falling => level == PinLevel::Low,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum PinLevel {
+    Low,
+    High,
+}
+
+#[derive(Copy, Clone)]
+pub struct EdgeEvent {
+    num: usize,
+    level: PinLevel,
+}
+
+impl EdgeEvent {
+    pub fn new(num: usize, level: PinLevel) -> EdgeEvent {
+        EdgeEvent { num, level }
+    }
+
+    pub fn get_num(&self) -> usize {
+        self.num
+    }
+
+    pub fn get_level(&self) -> PinLevel {
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{self, host};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    // Has four pins, and accepts every command
+    struct FakeGpio;
+
+    impl host::Driver for FakeGpio {
+        fn command(&mut self, minor: usize, _arg1: usize, _arg2: usize) -> isize {
+            match minor {
+                command_num::NUM_PINS => 4,
+                _ => 0,
+            }
+        }
+    }
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn input_pin(gpio: &Gpio, num: usize) -> Pin<Input> {
+        gpio.pin(num).unwrap().into_input(Pull::None).unwrap()
+    }
+
+    #[test]
+    fn interrupt_wakes_only_its_own_pin() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeGpio)));
+
+        let gpio = Gpio::new();
+        let pins = [input_pin(&gpio, 1), input_pin(&gpio, 2)];
+        let flags: Vec<Arc<Flag>> = (0..2)
+            .map(|_| Arc::new(Flag(AtomicBool::new(false))))
+            .collect();
+        let wakers: Vec<Waker> = flags.iter().map(|f| Waker::from(f.clone())).collect();
+        let mut waiters: Vec<_> = pins
+            .iter()
+            .map(|pin| Box::pin(pin.wait_for_edge(Edge::Either).unwrap()))
+            .collect();
+
+        for (waiter, waker) in waiters.iter_mut().zip(&wakers) {
+            let mut cx = Context::from_waker(waker);
+            assert!(waiter.as_mut().poll(&mut cx).is_pending());
+        }
+
+        // Pin 2 goes high
+        host::schedule(DRIVER_NUM, subscribe_num::CALLBACK, 2, 1, 0);
+        syscalls::yieldk();
+
+        assert!(!flags[0].0.load(Ordering::SeqCst));
+        assert!(flags[1].0.load(Ordering::SeqCst));
+        assert!(waiters[0]
+            .as_mut()
+            .poll(&mut Context::from_waker(&wakers[0]))
+            .is_pending());
+        match waiters[1]
+            .as_mut()
+            .poll(&mut Context::from_waker(&wakers[1]))
+        {
+            Poll::Ready(event) => {
+                assert_eq!(event.get_num(), 2);
+                assert!(event.get_level() == PinLevel::High);
+            }
+            Poll::Pending => panic!("pin 2 was not woken by its interrupt"),
+        }
+
+        // Pin 1 goes low
+        host::schedule(DRIVER_NUM, subscribe_num::CALLBACK, 1, 0, 0);
+        syscalls::yieldk();
+
+        assert!(flags[0].0.load(Ordering::SeqCst));
+        match waiters[0]
+            .as_mut()
+            .poll(&mut Context::from_waker(&wakers[0]))
+        {
+            Poll::Ready(event) => {
+                assert_eq!(event.get_num(), 1);
+                assert!(event.get_level() == PinLevel::Low);
+            }
+            Poll::Pending => panic!("pin 1 was not woken by its interrupt"),
+        }
+    }
+
+    #[test]
+    fn pin_is_claimed_until_its_handle_is_dropped() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeGpio)));
+
+        let gpio = Gpio::new();
+        let pin = gpio.pin(1).unwrap();
+        assert_eq!(gpio.pin(1).err(), Some(Error::EBUSY));
+        assert!(gpio.pin(2).is_ok());
+
+        let pin = pin.into_output().unwrap();
+        assert_eq!(gpio.pin(1).err(), Some(Error::EBUSY));
+
+        drop(pin);
+        assert!(gpio.pin(1).is_ok());
+    }
+}
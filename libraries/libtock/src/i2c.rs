@@ -0,0 +1,349 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+
+use embedded_hal::blocking::i2c;
+
+use crate::cell::RacyCell;
+use crate::futures::block_on;
+use crate::result::{Error, Result};
+use crate::syscalls::{allow, command, subscribe, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = 0x20003;
+
+mod allow_num {
+    pub const BUFFER: usize = 1;
+}
+
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const WRITE: usize = 1;
+    pub const READ: usize = 2;
+    pub const WRITE_READ: usize = 3;
+}
+
+// Status passed by the capsule in `arg0` of the completion callback. Mirrors
+// `kernel::hil::i2c::Error`.
+mod status {
+    pub const ADDRESS_NAK: usize = 0;
+    pub const DATA_NAK: usize = 1;
+    pub const ARBITRATION_LOST: usize = 2;
+    pub const OVERRUN: usize = 3;
+    pub const COMMAND_COMPLETE: usize = 4;
+}
+
+static I2C_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
+
+static I2C_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+extern "C" fn i2c_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    // Completion of a transaction whose future was dropped
+    if I2C_STATE.get() == I2cState::Abandoned {
+        I2C_STATE.set(I2cState::Nothing);
+        return;
+    }
+
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    I2C_DATA.set(Some(cb_data));
+
+    I2C_WAKER.wake();
+}
+
+// Indicates if there is an ongoing transaction, and how many bytes are to be
+// read back once it completes. Once the transaction is complete, `I2C_STATE` is
+// set to `Nothing` and the future can be resolved. A transaction whose future
+// was dropped is `Abandoned` until the capsule completes it, so that its
+// completion is not taken for that of the next transaction.
+#[derive(Copy, Clone, PartialEq)]
+enum I2cState {
+    Ongoing { read_len: usize },
+    Abandoned,
+    Nothing,
+}
+
+static I2C_STATE: RacyCell<I2cState> = RacyCell::new(I2cState::Nothing);
+
+// Corresponds to the kernel transaction buffer. Writes are copied in before the
+// transaction starts, and reads are copied out once it completes. For
+// `write_read`, the kernel reads back into the same buffer.
+static I2C_BUF: RacyCell<[u8; 64]> = RacyCell::new([0; 64]);
+
+pub struct I2c;
+
+impl I2c {
+    pub fn new() -> I2c {
+        I2c
+    }
+
+    pub fn write(&self, address: u8, tx: &[u8]) -> Result<impl Future<Output = Result<()>>> {
+        self.start(address, command_num::WRITE, tx, 0, tx.len())?;
+
+        Ok(I2cTransaction { rx: &mut [] })
+    }
+
+    pub fn read<'a>(
+        &self,
+        address: u8,
+        rx: &'a mut [u8],
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        self.start(address, command_num::READ, &[], rx.len(), rx.len())?;
+
+        Ok(I2cTransaction { rx })
+    }
+
+    pub fn write_read<'a>(
+        &self,
+        address: u8,
+        tx: &[u8],
+        rx: &'a mut [u8],
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        // The capsule packs both lengths into a single argument
+        if tx.len() > 0xFF || rx.len() > 0xFF {
+            return Err(Error::ESIZE);
+        }
+
+        let lengths = (rx.len() << 8) | tx.len();
+
+        self.start(address, command_num::WRITE_READ, tx, rx.len(), lengths)?;
+
+        Ok(I2cTransaction { rx })
+    }
+
+    fn start(
+        &self,
+        address: u8,
+        cmd: usize,
+        tx: &[u8],
+        read_len: usize,
+        command_len: usize,
+    ) -> Result<()> {
+        if I2C_STATE.get() != I2cState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        if address > 0x7F {
+            return Err(Error::EINVAL);
+        }
+
+        I2C_BUF.with(|buf| {
+            if tx.len() > buf.len() || read_len > buf.len() {
+                return Err(Error::ESIZE);
+            }
+
+            buf.iter_mut().for_each(|x| *x = 0);
+            buf[..tx.len()].copy_from_slice(tx);
+
+            Ok(())
+        })?;
+
+        let buf_len = if tx.len() > read_len {
+            tx.len()
+        } else {
+            read_len
+        };
+
+        unsafe {
+            let _ = allow(
+                DRIVER_NUM,
+                allow_num::BUFFER,
+                I2C_BUF.as_ptr() as *mut u8,
+                buf_len,
+            )?;
+
+            let _ = subscribe(
+                DRIVER_NUM,
+                subscribe_num::CALLBACK,
+                i2c_callback as *const _,
+                0,
+            )
+            .and_then(|_| command(DRIVER_NUM, cmd, address as usize, command_len))
+            .inspect_err(|_| unallow_buffer())?;
+        }
+
+        I2C_STATE.set(I2cState::Ongoing { read_len });
+
+        Ok(())
+    }
+}
+
+fn status_to_result(s: usize) -> Result<()> {
+    match s {
+        status::COMMAND_COMPLETE => Ok(()),
+        status::ADDRESS_NAK | status::DATA_NAK => Err(Error::ENOACK),
+        status::ARBITRATION_LOST => Err(Error::EBUSY),
+        status::OVERRUN => Err(Error::ESIZE),
+        _ => Err(Error::FAIL),
+    }
+}
+
+// Future returned by I2c::write, I2c::read and I2c::write_read
+struct I2cTransaction<'a> {
+    rx: &'a mut [u8],
+}
+
+impl<'a> Future for I2cTransaction<'a> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(cb_data) = I2C_DATA.take() {
+            let read_len = match I2C_STATE.get() {
+                I2cState::Ongoing { read_len } => read_len,
+                // Polled again after it completed
+                I2cState::Abandoned | I2cState::Nothing => return Poll::Ready(Err(Error::FAIL)),
+            };
+
+            unallow_buffer();
+            I2C_STATE.set(I2cState::Nothing);
+
+            Poll::Ready(status_to_result(cb_data.get_arg0()).map(|_| {
+                I2C_BUF.with(|buf| self.rx[..read_len].copy_from_slice(&buf[..read_len]));
+            }))
+        } else {
+            I2C_WAKER.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+// Dropped before it was resolved. The buffer is taken back, and the next
+// transaction can start once the capsule has completed this one.
+impl<'a> Drop for I2cTransaction<'a> {
+    fn drop(&mut self) {
+        if let I2cState::Ongoing { .. } = I2C_STATE.get() {
+            unallow_buffer();
+
+            if I2C_DATA.take().is_some() {
+                I2C_STATE.set(I2cState::Nothing);
+            } else {
+                I2C_STATE.set(I2cState::Abandoned);
+            }
+        }
+    }
+}
+
+// Take the buffer back from the kernel, before reading what it wrote there
+fn unallow_buffer() {
+    unsafe {
+        let _ = allow(DRIVER_NUM, allow_num::BUFFER, ptr::null_mut(), 0);
+    }
+}
+
+// `embedded-hal` blocking traits. These spin on `yieldk` until the transaction
+// completes, so that off-the-shelf sensor drivers work unmodified.
+impl i2c::Write for I2c {
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<()> {
+        block_on(I2c::write(self, address, bytes)?)
+    }
+}
+
+impl i2c::Read for I2c {
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<()> {
+        block_on(I2c::read(self, address, buffer)?)
+    }
+}
+
+impl i2c::WriteRead for I2c {
+    type Error = Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<()> {
+        block_on(I2c::write_read(self, address, bytes, buffer)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{self, host};
+
+    // Completes every transaction on the next yield, reading back 0xAA. The
+    // first `naks` transactions are not acknowledged.
+    struct FakeI2c {
+        naks: usize,
+    }
+
+    impl host::Driver for FakeI2c {
+        fn command(&mut self, minor: usize, _address: usize, _len: usize) -> isize {
+            if minor == command_num::READ {
+                let buf = unsafe { host::buffer(DRIVER_NUM, allow_num::BUFFER) }.unwrap();
+                buf.iter_mut().for_each(|x| *x = 0xAA);
+            }
+
+            let status = if self.naks > 0 {
+                self.naks -= 1;
+                status::ADDRESS_NAK
+            } else {
+                status::COMMAND_COMPLETE
+            };
+
+            host::schedule(DRIVER_NUM, subscribe_num::CALLBACK, status, 0, 0);
+            0
+        }
+    }
+
+    // `block_on` registers its own waker. The executor's has to replace it, or
+    // the async read is never woken.
+    #[test]
+    fn blocking_then_async() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeI2c { naks: 0 })));
+
+        let mut i2c = I2c::new();
+        assert_eq!(i2c::Write::write(&mut i2c, 0x40, &[1, 2]), Ok(()));
+
+        let mut rx = [0; 4];
+        let read = I2c::read(&i2c, 0x40, &mut rx).unwrap();
+        assert_eq!(host::run(read), Ok(()));
+        assert_eq!(rx, [0xAA; 4]);
+        assert!(unsafe { host::buffer(DRIVER_NUM, allow_num::BUFFER) }.is_none());
+    }
+
+    // The dropped write is not acknowledged. Its completion must not be taken
+    // for that of the read after it.
+    #[test]
+    fn late_completion_of_dropped_transaction_is_discarded() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeI2c { naks: 1 })));
+
+        let i2c = I2c::new();
+        drop(I2c::write(&i2c, 0x40, &[1]).unwrap());
+        assert!(unsafe { host::buffer(DRIVER_NUM, allow_num::BUFFER) }.is_none());
+
+        let mut rx = [0; 2];
+        assert_eq!(I2c::read(&i2c, 0x40, &mut rx).err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+
+        let read = I2c::read(&i2c, 0x40, &mut rx).unwrap();
+        assert_eq!(host::run(read), Ok(()));
+        assert_eq!(rx, [0xAA; 2]);
+        assert_eq!(host::scheduled(), 0);
+    }
+
+    // The capsule refuses the command, so the buffer must not stay allowed
+    #[test]
+    fn refused_transaction_takes_buffer_back() {
+        struct Refusing;
+
+        impl host::Driver for Refusing {
+            fn command(&mut self, _minor: usize, _address: usize, _len: usize) -> isize {
+                -2
+            }
+        }
+
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(Refusing)));
+
+        let i2c = I2c::new();
+        assert!(I2c::write(&i2c, 0x40, &[1]).is_err());
+        assert!(unsafe { host::buffer(DRIVER_NUM, allow_num::BUFFER) }.is_none());
+    }
+}
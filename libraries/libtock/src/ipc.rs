@@ -0,0 +1,342 @@
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::ptr;
+use core::slice;
+use core::task::{Context, Poll, Waker};
+use futures_core::stream::Stream;
+
+use crate::cell::RacyCell;
+use crate::result::{Error, Result};
+use crate::syscalls::{allow, command, subscribe};
+
+pub(crate) const DRIVER_NUM: usize = 0x10000;
+
+// Apart from discovery and the service callback, the IPC capsule uses the
+// target app's id as the allow, subscribe and command number.
+mod allow_num {
+    pub const DISCOVER: usize = 0;
+}
+
+mod subscribe_num {
+    pub const SERVICE: usize = 0;
+}
+
+mod notify_num {
+    pub const SERVICE: usize = 0;
+    pub const CLIENT: usize = 1;
+}
+
+// Copied out of the callback arguments: the id of the app on the other end and
+// the buffer the client shared with the service.
+#[derive(Copy, Clone)]
+struct IpcMessage {
+    from: usize,
+    len: usize,
+    ptr: usize,
+}
+
+// Requests that arrived before the service stream was polled. When it is
+// full, new requests are dropped and counted.
+const MAX_PENDING: usize = 8;
+
+static IPC_PENDING: RacyCell<[Option<IpcMessage>; MAX_PENDING]> =
+    RacyCell::new([None; MAX_PENDING]);
+
+static IPC_PENDING_HEAD: RacyCell<usize> = RacyCell::new(0);
+
+static IPC_PENDING_LEN: RacyCell<usize> = RacyCell::new(0);
+
+static IPC_DROPPED_REQUESTS: RacyCell<usize> = RacyCell::new(0);
+
+static IPC_SERVICE_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+static IPC_SERVICE_REGISTERED: RacyCell<bool> = RacyCell::new(false);
+
+extern "C" fn service_callback(arg0: usize, arg1: usize, arg2: usize, _userdata: usize) {
+    let pending_len = IPC_PENDING_LEN.get();
+    if pending_len == MAX_PENDING {
+        IPC_DROPPED_REQUESTS.with(|dropped| *dropped += 1);
+        return;
+    }
+
+    let index = (IPC_PENDING_HEAD.get() + pending_len) % MAX_PENDING;
+    IPC_PENDING.with(|pending| {
+        pending[index] = Some(IpcMessage {
+            from: arg0,
+            len: arg1,
+            ptr: arg2,
+        })
+    });
+    IPC_PENDING_LEN.set(pending_len + 1);
+
+    IPC_SERVICE_WAKER.wake();
+}
+
+fn pop_pending() -> Option<IpcMessage> {
+    if IPC_PENDING_LEN.get() == 0 {
+        return None;
+    }
+
+    let head = IPC_PENDING_HEAD.get();
+    let message = IPC_PENDING.with(|pending| pending[head].take());
+    IPC_PENDING_HEAD.set((head + 1) % MAX_PENDING);
+    IPC_PENDING_LEN.with(|len| *len -= 1);
+
+    message
+}
+
+// Id of the service that has answered the client's outstanding request
+static IPC_CLIENT_DATA: RacyCell<Option<usize>> = RacyCell::new(None);
+
+static IPC_CLIENT_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+extern "C" fn client_callback(arg0: usize, _arg1: usize, _arg2: usize, _userdata: usize) {
+    IPC_CLIENT_DATA.set(Some(arg0));
+
+    IPC_CLIENT_WAKER.wake();
+}
+
+// Indicates if the client is waiting on a service. A second request while one
+// is in flight is rejected with `EBUSY`.
+#[derive(Copy, Clone, PartialEq)]
+enum ClientState {
+    Ongoing { service: usize },
+    Nothing,
+}
+
+static IPC_CLIENT_STATE: RacyCell<ClientState> = RacyCell::new(ClientState::Nothing);
+
+// The kernel compares the package name in place, so it has to be somewhere
+// that outlives the `allow`.
+static IPC_NAME_BUF: RacyCell<[u8; 64]> = RacyCell::new([0; 64]);
+
+pub struct Ipc;
+
+impl Ipc {
+    pub fn new() -> Ipc {
+        Ipc
+    }
+
+    // Register this app as an IPC service. Clients find it by the package name
+    // in its TBF header. Requests stop being delivered when the stream is
+    // dropped.
+    pub fn serve(&self) -> Result<ServiceRequests> {
+        if IPC_SERVICE_REGISTERED.get() {
+            return Err(Error::EALREADY);
+        }
+
+        IPC_PENDING.set([None; MAX_PENDING]);
+        IPC_PENDING_HEAD.set(0);
+        IPC_PENDING_LEN.set(0);
+        IPC_DROPPED_REQUESTS.set(0);
+
+        unsafe {
+            subscribe(
+                DRIVER_NUM,
+                subscribe_num::SERVICE,
+                service_callback as *const _,
+                0,
+            )?;
+        }
+
+        IPC_SERVICE_REGISTERED.set(true);
+
+        Ok(ServiceRequests)
+    }
+
+    // Look up the service registered under `package_name`
+    pub fn discover(&self, package_name: &str) -> Result<Service> {
+        let name = package_name.as_bytes();
+
+        IPC_NAME_BUF.with(|buf| {
+            if name.is_empty() || name.len() > buf.len() {
+                return Err(Error::EINVAL);
+            }
+
+            buf[..name.len()].copy_from_slice(name);
+
+            Ok(())
+        })?;
+
+        // The buffer is taken back right after, so that the next `discover`
+        // can write to it
+        unsafe {
+            let res = allow(
+                DRIVER_NUM,
+                allow_num::DISCOVER,
+                IPC_NAME_BUF.as_ptr() as *mut u8,
+                name.len(),
+            );
+            let _ = allow(DRIVER_NUM, allow_num::DISCOVER, ptr::null_mut(), 0);
+
+            res.map(|id| Service { id })
+        }
+    }
+}
+
+/// Stream of requests returned by `Ipc::serve`.
+pub struct ServiceRequests;
+
+impl ServiceRequests {
+    // Number of requests that were dropped because the stream was not polled
+    // fast enough.
+    pub fn get_dropped(&self) -> usize {
+        IPC_DROPPED_REQUESTS.get()
+    }
+}
+
+impl Stream for ServiceRequests {
+    type Item = Request;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match pop_pending() {
+            Some(message) => Poll::Ready(Some(Request { message })),
+            None => {
+                IPC_SERVICE_WAKER.register(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for ServiceRequests {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = subscribe(DRIVER_NUM, subscribe_num::SERVICE, ptr::null(), 0);
+        }
+
+        IPC_SERVICE_REGISTERED.set(false);
+    }
+}
+
+/// A request received by a service.
+pub struct Request {
+    message: IpcMessage,
+}
+
+impl Request {
+    pub fn get_client(&self) -> usize {
+        self.message.from
+    }
+
+    /// The buffer the client shared with this service. It is empty if the
+    /// client did not share one.
+    pub fn buffer(&mut self) -> &mut [u8] {
+        if self.message.ptr == 0 {
+            return &mut [];
+        }
+
+        unsafe { slice::from_raw_parts_mut(self.message.ptr as *mut u8, self.message.len) }
+    }
+
+    // Notify the client that the request has been handled
+    pub fn respond(self) -> Result<()> {
+        unsafe { command(DRIVER_NUM, self.message.from, notify_num::CLIENT, 0).map(|_| ()) }
+    }
+}
+
+/// A service found by `Ipc::discover`.
+pub struct Service {
+    id: usize,
+}
+
+impl Service {
+    pub fn get_id(&self) -> usize {
+        self.id
+    }
+
+    // Notify the service without waiting for an answer
+    pub fn notify(&self) -> Result<()> {
+        unsafe { command(DRIVER_NUM, self.id, notify_num::SERVICE, 0).map(|_| ()) }
+    }
+
+    // Notify the service and wait for it to respond
+    pub fn request(&self) -> Result<impl Future<Output = ()>> {
+        if IPC_CLIENT_STATE.get() != ClientState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        IPC_CLIENT_DATA.set(None);
+
+        unsafe {
+            let _ = subscribe(DRIVER_NUM, self.id, client_callback as *const _, 0)
+                .and_then(|_| command(DRIVER_NUM, self.id, notify_num::SERVICE, 0))?;
+        }
+
+        IPC_CLIENT_STATE.set(ClientState::Ongoing { service: self.id });
+
+        Ok(ServiceResponse)
+    }
+
+    // Share `buf` with the service for as long as the returned guard lives. The
+    // service sees it as `Request::buffer`.
+    pub fn share<'a>(&'a self, buf: &'a mut [u8]) -> Result<SharedBuffer<'a>> {
+        unsafe {
+            allow(DRIVER_NUM, self.id, buf.as_mut_ptr(), buf.len())?;
+        }
+
+        Ok(SharedBuffer {
+            service: self,
+            ptr: buf.as_mut_ptr(),
+            len: buf.len(),
+            _buffer: PhantomData,
+        })
+    }
+}
+
+// Future returned by Service::request
+struct ServiceResponse;
+
+impl Future for ServiceResponse {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match (IPC_CLIENT_DATA.get(), IPC_CLIENT_STATE.get()) {
+            (Some(from), ClientState::Ongoing { service }) if from == service => {
+                IPC_CLIENT_DATA.set(None);
+                IPC_CLIENT_STATE.set(ClientState::Nothing);
+                Poll::Ready(())
+            }
+            _ => {
+                IPC_CLIENT_WAKER.register(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for ServiceResponse {
+    fn drop(&mut self) {
+        IPC_CLIENT_STATE.set(ClientState::Nothing);
+    }
+}
+
+/// Buffer shared with a service by `Service::share`. It is taken back from
+/// the kernel when dropped.
+pub struct SharedBuffer<'a> {
+    service: &'a Service,
+    ptr: *mut u8,
+    len: usize,
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> SharedBuffer<'a> {
+    // The service may write to the buffer while it handles a request, so only
+    // look at it once the request has been answered.
+    pub fn buffer(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    pub fn request(&self) -> Result<impl Future<Output = ()>> {
+        self.service.request()
+    }
+}
+
+impl<'a> Drop for SharedBuffer<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = allow(DRIVER_NUM, self.service.id, ptr::null_mut(), 0);
+        }
+    }
+}
@@ -1,16 +1,33 @@
 use core::alloc::Layout;
+use core::fmt::Debug;
 use core::intrinsics;
 use core::panic::PanicInfo;
+#[cfg(feature = "trace")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Set by the first panic, so that a panic while dumping the trace does not
+// dump it again
+#[cfg(feature = "trace")]
+static PANICKING: AtomicBool = AtomicBool::new(false);
 
 // Panic handler. Adapted from `panic-abort` crate
-#[no_mangle]
 #[panic_handler]
-pub unsafe extern "C" fn panic_fmt(_info: &PanicInfo) -> ! {
-    intrinsics::abort();
+fn panic_fmt(_info: &PanicInfo) -> ! {
+    // Show what led up to the panic. This fails quietly if the console was
+    // busy. The dump goes through driver cells and yields, so it is skipped
+    // when the panic came from inside a cell: it would only panic again.
+    #[cfg(feature = "trace")]
+    {
+        if !crate::cell::any_borrowed() && !PANICKING.swap(true, Ordering::Relaxed) {
+            let _ = crate::syscalls::trace::dump();
+        }
+    }
+
+    intrinsics::abort()
 }
 
 #[lang = "start"]
-extern "C" fn start<T>(main: fn() -> T, _argc: isize, _argv: *const *const u8) -> i32
+fn start<T>(main: fn() -> T, _argc: isize, _argv: *const *const u8, _sigpipe: u8) -> isize
 where
     T: Termination,
 {
@@ -18,16 +35,28 @@ where
 }
 
 pub trait Termination {
-    fn report(self) -> i32;
+    fn report(self) -> isize;
 }
 
 impl Termination for () {
-    fn report(self) -> i32 {
+    fn report(self) -> isize {
         0
     }
 }
 
+// Returned by `#[tock::main] async fn main() -> Result<(), E>`. There is no
+// stderr to print the error on, so we only report failure.
+impl<E: Debug> Termination for Result<(), E> {
+    fn report(self) -> isize {
+        match self {
+            Ok(()) => 0,
+            Err(_) => 1,
+        }
+    }
+}
+
 #[alloc_error_handler]
+#[allow(clippy::empty_loop)]
 fn alloc_error(_: Layout) -> ! {
     loop {}
 }
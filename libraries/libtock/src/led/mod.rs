@@ -0,0 +1,81 @@
+use embedded_hal::digital::v2::{OutputPin, ToggleableOutputPin};
+
+use crate::drivers::driver_num;
+use crate::result::{Error, Result};
+use crate::syscalls::command;
+
+mod pattern;
+
+pub use self::pattern::{Blink, Heartbeat, Morse, Pattern, State, Step};
+
+pub(crate) const DRIVER_NUM: usize = driver_num::LED;
+
+mod command_num {
+    pub const NUM_LEDS: usize = 0;
+    pub const ON: usize = 1;
+    pub const OFF: usize = 2;
+    pub const TOGGLE: usize = 3;
+}
+
+pub struct Led;
+
+impl Led {
+    pub fn new() -> Led {
+        Led
+    }
+
+    pub fn get_num_leds(&self) -> Result<usize> {
+        unsafe { command(DRIVER_NUM, command_num::NUM_LEDS, 0, 0) }
+    }
+
+    pub fn on(&self, led_num: usize) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::ON, led_num, 0).map(|_| ()) }
+    }
+
+    pub fn off(&self, led_num: usize) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::OFF, led_num, 0).map(|_| ()) }
+    }
+
+    pub fn toggle(&self, led_num: usize) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::TOGGLE, led_num, 0).map(|_| ()) }
+    }
+
+    // A single LED as an `embedded-hal` output pin
+    pub fn pin(&self, led_num: usize) -> Result<LedPin> {
+        if led_num >= self.get_num_leds()? {
+            return Err(Error::EINVAL);
+        }
+
+        Ok(LedPin { led_num })
+    }
+}
+
+pub struct LedPin {
+    led_num: usize,
+}
+
+impl LedPin {
+    pub fn get_num(&self) -> usize {
+        self.led_num
+    }
+}
+
+impl OutputPin for LedPin {
+    type Error = Error;
+
+    fn set_low(&mut self) -> Result<()> {
+        Led::new().off(self.led_num)
+    }
+
+    fn set_high(&mut self) -> Result<()> {
+        Led::new().on(self.led_num)
+    }
+}
+
+impl ToggleableOutputPin for LedPin {
+    type Error = Error;
+
+    fn toggle(&mut self) -> Result<()> {
+        Led::new().toggle(self.led_num)
+    }
+}
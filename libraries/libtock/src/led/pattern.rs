@@ -0,0 +1,266 @@
+use core::future::Future;
+use core::iter::{Cloned, Cycle};
+use core::pin::Pin;
+use core::slice;
+use core::str::Chars;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use super::Led;
+use crate::alarm::{Alarm, Sleep};
+use crate::cell::RacyCell;
+use crate::result::{Error, Result};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum State {
+    On,
+    Off,
+}
+
+/// Hold the LED in `State` for `Duration`.
+pub type Step = (State, Duration);
+
+// One pattern per LED at a time. Bit `n` is set while LED `n` runs one.
+static LED_PATTERNS: RacyCell<u32> = RacyCell::new(0);
+
+const MAX_PATTERN_LEDS: usize = 32;
+
+impl Led {
+    // Run `steps` on `led_num`, one after the other. Any iterator of steps
+    // works, e.g. `steps.iter().cloned().cycle()` to repeat a fixed sequence.
+    pub fn pattern<I>(&self, led_num: usize, steps: I) -> Result<Pattern<I::IntoIter>>
+    where
+        I: IntoIterator<Item = Step>,
+        I::IntoIter: Unpin,
+    {
+        if led_num >= self.get_num_leds()? || led_num >= MAX_PATTERN_LEDS {
+            return Err(Error::EINVAL);
+        }
+
+        LED_PATTERNS.with(|patterns| {
+            if *patterns & (1 << led_num) != 0 {
+                return Err(Error::EBUSY);
+            }
+
+            *patterns |= 1 << led_num;
+
+            Ok(())
+        })?;
+
+        Ok(Pattern {
+            led_num,
+            steps: steps.into_iter(),
+            sleep: None,
+        })
+    }
+
+    // Blink `n` times, `period` being the length of one on/off cycle
+    pub fn blink(&self, led_num: usize, n: usize, period: Duration) -> Result<Pattern<Blink>> {
+        self.pattern(
+            led_num,
+            Blink {
+                remaining: n * 2,
+                half_period: period / 2,
+            },
+        )
+    }
+
+    // Double beat once a second, until dropped
+    pub fn heartbeat(&self, led_num: usize) -> Result<Pattern<Heartbeat>> {
+        self.pattern(led_num, HEARTBEAT.iter().cloned().cycle())
+    }
+
+    // Spell out `message` in Morse code. `unit` is the length of a dot.
+    // Characters other than letters, digits and spaces are skipped.
+    pub fn morse<'a>(
+        &self,
+        led_num: usize,
+        message: &'a str,
+        unit: Duration,
+    ) -> Result<Pattern<Morse<'a>>> {
+        self.pattern(
+            led_num,
+            Morse {
+                chars: message.chars(),
+                code: "",
+                gap: None,
+                unit,
+            },
+        )
+    }
+}
+
+/// Future returned by the LED pattern functions. It resolves once the steps
+/// run out, which for `heartbeat` is never. Dropping it stops the pattern and
+/// turns the LED off.
+pub struct Pattern<I> {
+    led_num: usize,
+    steps: I,
+    sleep: Option<Sleep>,
+}
+
+impl<I: Iterator<Item = Step> + Unpin> Future for Pattern<I> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let led = Led::new();
+
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                match Pin::new(sleep).poll(cx) {
+                    Poll::Ready(()) => this.sleep = None,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            let (state, duration) = match this.steps.next() {
+                Some(step) => step,
+                None => return Poll::Ready(Ok(())),
+            };
+
+            // Errors from the LED itself are not worth stopping for
+            let _ = match state {
+                State::On => led.on(this.led_num),
+                State::Off => led.off(this.led_num),
+            };
+
+            match Alarm::new().sleep(duration) {
+                Ok(sleep) => this.sleep = Some(sleep),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl<I> Drop for Pattern<I> {
+    fn drop(&mut self) {
+        let _ = Led::new().off(self.led_num);
+
+        LED_PATTERNS.with(|patterns| *patterns &= !(1 << self.led_num));
+    }
+}
+
+/// Steps of `Led::blink`.
+pub struct Blink {
+    // Number of on and off steps left
+    remaining: usize,
+    half_period: Duration,
+}
+
+impl Iterator for Blink {
+    type Item = Step;
+
+    fn next(&mut self) -> Option<Step> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let state = if self.remaining.is_multiple_of(2) {
+            State::On
+        } else {
+            State::Off
+        };
+        self.remaining -= 1;
+
+        Some((state, self.half_period))
+    }
+}
+
+const HEARTBEAT: [Step; 4] = [
+    (State::On, Duration::from_millis(100)),
+    (State::Off, Duration::from_millis(150)),
+    (State::On, Duration::from_millis(100)),
+    (State::Off, Duration::from_millis(650)),
+];
+
+/// Steps of `Led::heartbeat`.
+pub type Heartbeat = Cycle<Cloned<slice::Iter<'static, Step>>>;
+
+/// Steps of `Led::morse`.
+pub struct Morse<'a> {
+    chars: Chars<'a>,
+    // Dots and dashes of the current character that are still to be sent
+    code: &'static str,
+    // Gap to send after the dot or dash that was just sent
+    gap: Option<Duration>,
+    unit: Duration,
+}
+
+impl<'a> Iterator for Morse<'a> {
+    type Item = Step;
+
+    // A dot is one unit on and a dash three. Within a character they are one
+    // unit apart, characters are three units apart and words seven.
+    fn next(&mut self) -> Option<Step> {
+        if let Some(gap) = self.gap.take() {
+            return Some((State::Off, gap));
+        }
+
+        while self.code.is_empty() {
+            match self.chars.next()? {
+                // Three units have already been sent after the last character
+                ' ' => return Some((State::Off, self.unit * 4)),
+                c => self.code = morse_code(c).unwrap_or(""),
+            }
+        }
+
+        let on = match self.code.as_bytes()[0] {
+            b'-' => self.unit * 3,
+            _ => self.unit,
+        };
+        self.code = &self.code[1..];
+
+        self.gap = Some(if self.code.is_empty() {
+            self.unit * 3
+        } else {
+            self.unit
+        });
+
+        Some((State::On, on))
+    }
+}
+
+fn morse_code(c: char) -> Option<&'static str> {
+    let code = match c.to_ascii_uppercase() {
+        'A' => ".-",
+        'B' => "-...",
+        'C' => "-.-.",
+        'D' => "-..",
+        'E' => ".",
+        'F' => "..-.",
+        'G' => "--.",
+        'H' => "....",
+        'I' => "..",
+        'J' => ".---",
+        'K' => "-.-",
+        'L' => ".-..",
+        'M' => "--",
+        'N' => "-.",
+        'O' => "---",
+        'P' => ".--.",
+        'Q' => "--.-",
+        'R' => ".-.",
+        'S' => "...",
+        'T' => "-",
+        'U' => "..-",
+        'V' => "...-",
+        'W' => ".--",
+        'X' => "-..-",
+        'Y' => "-.--",
+        'Z' => "--..",
+        '0' => "-----",
+        '1' => ".----",
+        '2' => "..---",
+        '3' => "...--",
+        '4' => "....-",
+        '5' => ".....",
+        '6' => "-....",
+        '7' => "--...",
+        '8' => "---..",
+        '9' => "----.",
+        _ => return None,
+    };
+
+    Some(code)
+}
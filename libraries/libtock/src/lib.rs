@@ -1,50 +1,93 @@
-#![no_std]
-#![feature(
-    asm,
-    alloc_error_handler,
-    allocator_api,
-    core_intrinsics,
-    in_band_lifetimes,
-    lang_items,
-    naked_functions
+#![cfg_attr(not(test), no_std)]
+// The runtime (entry point, lang items and syscall assembly) only exists on the
+// Tock targets. On the host the drivers run against `syscalls::host`, so that
+// they can be tested with `cargo test`.
+#![cfg_attr(
+    any(target_arch = "arm", target_arch = "riscv32"),
+    feature(alloc_error_handler, core_intrinsics, lang_items),
+    allow(internal_features)
 )]
+// Drivers are reached through handles made with `new()`. A `Default` would not
+// say anything more.
+#![allow(clippy::new_without_default)]
 
-use heapless::{self, consts, LinearMap};
-use linked_list_allocator::{Heap, LockedHeap};
+#[cfg(any(target_arch = "arm", target_arch = "riscv32"))]
+use linked_list_allocator::LockedHeap;
 
+use crate::cell::RacyCell;
+use crate::futures::TrackedHeap;
+
+pub mod adc;
+pub mod alarm;
+pub mod ble;
+#[cfg(feature = "button")]
 pub mod button;
+pub mod cell;
+#[cfg(feature = "console")]
 pub mod console_read;
+#[cfg(feature = "console")]
 pub mod console_write;
+pub mod crypto;
+pub mod drivers;
+#[cfg(any(target_arch = "arm", target_arch = "riscv32"))]
 pub mod entry_point;
 pub mod futures;
+pub mod gpio;
+pub mod i2c;
+pub mod ipc;
+#[cfg(any(target_arch = "arm", target_arch = "riscv32"))]
 pub mod lang_items;
+#[cfg(feature = "led")]
 pub mod led;
+pub mod net;
+pub mod ninedof;
 pub mod result;
+pub mod rng;
+pub mod screen;
+pub mod sensors;
+pub mod spi;
+pub mod storage;
 pub mod syscalls;
+#[cfg(target_arch = "arm")]
 pub mod unwind_symbols;
 
 pub use result::Result;
+pub use tock_macros::main;
 
 // Even though we do not use a global allocator, when `linked_list_allocator`
 // does `extern crate alloc`
+#[cfg(any(target_arch = "arm", target_arch = "riscv32"))]
 #[global_allocator]
 static GLOBAL_ALLOC: LockedHeap = LockedHeap::empty();
 
+#[cfg(all(feature = "futures-alloc", feature = "button"))]
 #[no_mangle]
 #[used]
-static mut BUTTON_FUTURE_ALLOC: Heap = Heap::empty();
+static BUTTON_FUTURE_ALLOC: RacyCell<TrackedHeap> = RacyCell::new(TrackedHeap::empty());
 
+#[cfg(all(feature = "futures-alloc", feature = "console"))]
 #[no_mangle]
 #[used]
-static mut CONSOLE_WRITE_FUTURE_ALLOC: Heap = Heap::empty();
+static CONSOLE_WRITE_FUTURE_ALLOC: RacyCell<TrackedHeap> = RacyCell::new(TrackedHeap::empty());
 
+#[cfg(all(feature = "futures-alloc", feature = "console"))]
 #[no_mangle]
 #[used]
-static mut CONSOLE_READ_FUTURE_ALLOC: Heap = Heap::empty();
+static CONSOLE_READ_FUTURE_ALLOC: RacyCell<TrackedHeap> = RacyCell::new(TrackedHeap::empty());
 
-// Because `FnvIndexMap` does not have a `const fn` constructor, we have to use
-// a `LinearMap`. `usize` here is a pointer to allocated memory.
-#[no_mangle]
-#[used]
-static mut ALLOCED_FUTURE_PTR: LinearMap<usize, futures::AllocedFor, consts::U16> =
-    LinearMap(heapless::i::LinearMap::new());
+// The future arenas that are compiled in, in the order `rust_start` lays them
+// out on the heap, along with the name `arena_report` prints them under.
+// Without `futures-alloc` the list is empty and the app has no heap. On the
+// host it is only read by `arena_report`.
+#[cfg_attr(
+    not(any(feature = "console", target_arch = "arm", target_arch = "riscv32")),
+    allow(dead_code)
+)]
+static FUTURE_ALLOCS: &[(&str, &RacyCell<TrackedHeap>)] = &[
+    #[cfg(all(feature = "futures-alloc", feature = "button"))]
+    ("button", &BUTTON_FUTURE_ALLOC),
+    #[cfg(all(feature = "futures-alloc", feature = "console"))]
+    ("console write", &CONSOLE_WRITE_FUTURE_ALLOC),
+    #[cfg(all(feature = "futures-alloc", feature = "console"))]
+    ("console read", &CONSOLE_READ_FUTURE_ALLOC),
+];
@@ -0,0 +1,569 @@
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+
+use crate::cell::{register_waker, RacyCell};
+use crate::result::{Error, Result, UsizeError};
+use crate::syscalls::{allow, allow_readonly, command, subscribe, with_reallowed, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = 0x30002;
+
+mod allow_num {
+    pub const RX: usize = 0;
+    pub const TX: usize = 1;
+    pub const CFG: usize = 2;
+    pub const RX_CFG: usize = 3;
+}
+
+mod subscribe_num {
+    pub const RX: usize = 0;
+    pub const TX: usize = 1;
+}
+
+mod command_num {
+    pub const GET_INTERFACES: usize = 1;
+    pub const SEND: usize = 2;
+    pub const BIND: usize = 3;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Ipv6Addr(pub [u8; 16]);
+
+impl Ipv6Addr {
+    pub const UNSPECIFIED: Ipv6Addr = Ipv6Addr([0; 16]);
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct SocketAddr {
+    pub addr: Ipv6Addr,
+    pub port: u16,
+}
+
+// Size of a `SocketAddr` in the capsule's config buffers: the address, then
+// the port in native byte order.
+const SOCKET_ADDR_LEN: usize = 18;
+
+impl SocketAddr {
+    pub fn new(addr: Ipv6Addr, port: u16) -> SocketAddr {
+        SocketAddr { addr, port }
+    }
+
+    fn to_bytes(self, buf: &mut [u8]) {
+        buf[..16].copy_from_slice(&self.addr.0);
+        buf[16..SOCKET_ADDR_LEN].copy_from_slice(&self.port.to_ne_bytes());
+    }
+
+    fn from_bytes(buf: &[u8]) -> SocketAddr {
+        let mut addr = [0; 16];
+        addr.copy_from_slice(&buf[..16]);
+
+        SocketAddr {
+            addr: Ipv6Addr(addr),
+            port: u16::from_ne_bytes([buf[16], buf[17]]),
+        }
+    }
+}
+
+// The capsule delivers every datagram for this app into one receive buffer,
+// with the source and destination written into the receive config buffer. We
+// demultiplex them in userspace: each bound `UdpSocket` owns one of these
+// slots, matched by destination port, and holds at most one datagram that has
+// not been received yet. Datagrams for a full slot are dropped and counted.
+const MAX_SOCKETS: usize = 4;
+
+pub const MAX_PAYLOAD_LEN: usize = 128;
+
+#[derive(Copy, Clone)]
+struct Datagram {
+    src: SocketAddr,
+    len: usize,
+    payload: [u8; MAX_PAYLOAD_LEN],
+}
+
+#[derive(Copy, Clone)]
+struct SocketSlot {
+    local: SocketAddr,
+    datagram: Option<Datagram>,
+}
+
+static UDP_SOCKETS: RacyCell<[Option<SocketSlot>; MAX_SOCKETS]> =
+    RacyCell::new([None; MAX_SOCKETS]);
+
+static UDP_RX_WAKERS: RacyCell<[Option<Waker>; MAX_SOCKETS]> =
+    RacyCell::new([None, None, None, None]);
+
+static UDP_DROPPED_DATAGRAMS: RacyCell<usize> = RacyCell::new(0);
+
+// Allowed to the kernel for as long as a socket is bound
+static UDP_RX_BUF: RacyCell<[u8; MAX_PAYLOAD_LEN]> = RacyCell::new([0; MAX_PAYLOAD_LEN]);
+
+static UDP_RX_CFG: RacyCell<[u8; 2 * SOCKET_ADDR_LEN]> = RacyCell::new([0; 2 * SOCKET_ADDR_LEN]);
+
+static UDP_RX_READY: RacyCell<bool> = RacyCell::new(false);
+
+extern "C" fn rx_callback(arg0: usize, _arg1: usize, _arg2: usize, _userdata: usize) {
+    let len = core::cmp::min(arg0, MAX_PAYLOAD_LEN);
+    let mut payload = [0; MAX_PAYLOAD_LEN];
+
+    let (src, dst) = unsafe {
+        with_reallowed(
+            DRIVER_NUM,
+            allow_num::RX,
+            UDP_RX_BUF.as_ptr() as *mut u8,
+            MAX_PAYLOAD_LEN,
+            || {
+                with_reallowed(
+                    DRIVER_NUM,
+                    allow_num::RX_CFG,
+                    UDP_RX_CFG.as_ptr() as *mut u8,
+                    2 * SOCKET_ADDR_LEN,
+                    || {
+                        UDP_RX_BUF.with(|buf| payload[..len].copy_from_slice(&buf[..len]));
+                        UDP_RX_CFG.with(|cfg| {
+                            (
+                                SocketAddr::from_bytes(&cfg[..SOCKET_ADDR_LEN]),
+                                SocketAddr::from_bytes(&cfg[SOCKET_ADDR_LEN..]),
+                            )
+                        })
+                    },
+                )
+            },
+        )
+    };
+
+    let datagram = Datagram { src, len, payload };
+
+    let index = UDP_SOCKETS.with(|sockets| {
+        let index = sockets.iter().position(|slot| match slot {
+            Some(s) => s.local.port == dst.port,
+            None => false,
+        })?;
+
+        let slot = sockets[index].as_mut().unwrap();
+
+        if slot.datagram.is_some() {
+            UDP_DROPPED_DATAGRAMS.with(|dropped| *dropped += 1);
+            return None;
+        }
+
+        slot.datagram = Some(datagram);
+
+        Some(index)
+    });
+
+    if let Some(index) = index {
+        if let Some(waker) = UDP_RX_WAKERS.with(|wakers| wakers[index].clone()) {
+            waker.wake();
+        }
+    }
+}
+
+static UDP_TX_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
+
+static UDP_TX_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+extern "C" fn tx_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    UDP_TX_DATA.set(Some(cb_data));
+
+    UDP_TX_WAKER.wake();
+}
+
+// Indicates if a datagram is being sent. The capsule sends one at a time, so
+// a second `send_to` while one is in flight is rejected with `EBUSY`.
+#[derive(Copy, Clone, PartialEq)]
+enum TxState {
+    Ongoing,
+    Nothing,
+}
+
+static UDP_TX_STATE: RacyCell<TxState> = RacyCell::new(TxState::Nothing);
+
+// Source and destination of the datagram being sent
+static UDP_TX_CFG: RacyCell<[u8; 2 * SOCKET_ADDR_LEN]> = RacyCell::new([0; 2 * SOCKET_ADDR_LEN]);
+
+const MAX_INTERFACES: usize = 4;
+
+static UDP_INTERFACES_BUF: RacyCell<[u8; 16 * MAX_INTERFACES]> =
+    RacyCell::new([0; 16 * MAX_INTERFACES]);
+
+fn set_rx_waker(index: usize, cx: &mut Context<'_>) {
+    UDP_RX_WAKERS.with(|wakers| register_waker(&mut wakers[index], cx.waker()));
+}
+
+// Fill `ifaces` with the addresses of the board's network interfaces and
+// return how many there are. At most `MAX_INTERFACES` are reported.
+pub fn interfaces(ifaces: &mut [Ipv6Addr]) -> Result<usize> {
+    // The config buffer is still in use by the send
+    if UDP_TX_STATE.get() != TxState::Nothing {
+        return Err(Error::EBUSY);
+    }
+
+    let max = core::cmp::min(ifaces.len(), MAX_INTERFACES);
+
+    let count = unsafe {
+        let res = allow(
+            DRIVER_NUM,
+            allow_num::CFG,
+            UDP_INTERFACES_BUF.as_ptr() as *mut u8,
+            16 * max,
+        )
+        .and_then(|_| command(DRIVER_NUM, command_num::GET_INTERFACES, max, 0));
+
+        let _ = allow(DRIVER_NUM, allow_num::CFG, ptr::null_mut(), 0);
+
+        res?
+    };
+
+    UDP_INTERFACES_BUF.with(|buf| {
+        for (i, iface) in ifaces
+            .iter_mut()
+            .take(core::cmp::min(count, max))
+            .enumerate()
+        {
+            iface.0.copy_from_slice(&buf[16 * i..16 * (i + 1)]);
+        }
+    });
+
+    Ok(count)
+}
+
+/// A UDP socket bound to a local address and port. Datagrams are sent from
+/// that address and only datagrams for that port are received. The port is
+/// released when the socket is dropped, and can then be bound again.
+pub struct UdpSocket {
+    index: usize,
+}
+
+impl UdpSocket {
+    pub fn bind(local: SocketAddr) -> Result<UdpSocket> {
+        let index = UDP_SOCKETS.with(|sockets| {
+            if sockets.iter().any(|slot| match slot {
+                Some(s) => s.local.port == local.port,
+                None => false,
+            }) {
+                return Err(Error::EALREADY);
+            }
+
+            sockets
+                .iter()
+                .position(|slot| slot.is_none())
+                .ok_or(Error::ENOMEM)
+        })?;
+
+        if !UDP_RX_READY.get() {
+            unsafe { start_rx()? };
+        }
+
+        UDP_SOCKETS.with(|sockets| {
+            sockets[index] = Some(SocketSlot {
+                local,
+                datagram: None,
+            })
+        });
+
+        Ok(UdpSocket { index })
+    }
+
+    pub fn get_local_addr(&self) -> SocketAddr {
+        UDP_SOCKETS.with(|sockets| sockets[self.index].as_ref().unwrap().local)
+    }
+
+    // Number of datagrams that were dropped because one was already waiting to
+    // be received, across all sockets
+    pub fn get_dropped(&self) -> usize {
+        UDP_DROPPED_DATAGRAMS.get()
+    }
+
+    pub fn send_to<'a>(
+        &self,
+        buf: &'a [u8],
+        dst: SocketAddr,
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        if buf.is_empty() || buf.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::EINVAL);
+        }
+
+        if UDP_TX_STATE.get() != TxState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        let local = self.get_local_addr();
+        UDP_TX_CFG.with(|cfg| {
+            local.to_bytes(&mut cfg[..SOCKET_ADDR_LEN]);
+            dst.to_bytes(&mut cfg[SOCKET_ADDR_LEN..]);
+        });
+
+        UDP_TX_DATA.set(None);
+
+        unsafe {
+            let _ = allow(
+                DRIVER_NUM,
+                allow_num::CFG,
+                UDP_TX_CFG.as_ptr() as *mut u8,
+                2 * SOCKET_ADDR_LEN,
+            )
+            .and_then(|_| allow_readonly(DRIVER_NUM, allow_num::TX, buf.as_ptr(), buf.len()))
+            .and_then(|_| subscribe(DRIVER_NUM, subscribe_num::TX, tx_callback as *const _, 0))
+            .and_then(|_| command(DRIVER_NUM, command_num::SEND, 0, 0))
+            .inspect_err(|_| {
+                finish_send();
+            })?;
+        }
+
+        UDP_TX_STATE.set(TxState::Ongoing);
+
+        Ok(UdpSend {
+            done: false,
+            _buffer: PhantomData,
+        })
+    }
+
+    // Wait for the next datagram for this socket and copy it into `buf`. The
+    // part that does not fit is discarded. Resolves to the number of bytes
+    // copied and the sender.
+    pub fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> impl Future<Output = Result<(usize, SocketAddr)>> + 'a {
+        UdpRecv {
+            index: self.index,
+            buf,
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let last = UDP_SOCKETS.with(|sockets| {
+            sockets[self.index] = None;
+            sockets.iter().all(|slot| slot.is_none())
+        });
+        UDP_RX_WAKERS.with(|wakers| wakers[self.index] = None);
+
+        if last {
+            unsafe { stop_rx() };
+        }
+    }
+}
+
+// The capsule holds a single binding per app, and a new `BIND` replaces it. So
+// the app is bound once, to every port, when the first socket is bound, and
+// `rx_callback` hands each datagram to the socket for its destination port.
+unsafe fn start_rx() -> Result<()> {
+    let any = SocketAddr::new(Ipv6Addr::UNSPECIFIED, 0);
+
+    // The capsule takes the address to bind from the second half of the
+    // receive config buffer
+    UDP_RX_CFG.with(|cfg| any.to_bytes(&mut cfg[SOCKET_ADDR_LEN..]));
+
+    let _ = allow(
+        DRIVER_NUM,
+        allow_num::RX,
+        UDP_RX_BUF.as_ptr() as *mut u8,
+        MAX_PAYLOAD_LEN,
+    )
+    .and_then(|_| {
+        allow(
+            DRIVER_NUM,
+            allow_num::RX_CFG,
+            UDP_RX_CFG.as_ptr() as *mut u8,
+            2 * SOCKET_ADDR_LEN,
+        )
+    })
+    .and_then(|_| subscribe(DRIVER_NUM, subscribe_num::RX, rx_callback as *const _, 0))
+    .and_then(|_| command(DRIVER_NUM, command_num::BIND, 0, 0))
+    .inspect_err(|_| {
+        stop_rx();
+    })?;
+
+    UDP_RX_READY.set(true);
+
+    Ok(())
+}
+
+// Once the last socket is gone, stop receiving and take the buffers back
+unsafe fn stop_rx() {
+    let _ = subscribe(DRIVER_NUM, subscribe_num::RX, ptr::null(), 0);
+    let _ = allow(DRIVER_NUM, allow_num::RX, ptr::null_mut(), 0);
+    let _ = allow(DRIVER_NUM, allow_num::RX_CFG, ptr::null_mut(), 0);
+
+    UDP_RX_READY.set(false);
+}
+
+// Take the caller's buffer and the config buffer back from the kernel
+fn finish_send() {
+    unsafe {
+        let _ = allow_readonly(DRIVER_NUM, allow_num::TX, ptr::null(), 0);
+        let _ = allow(DRIVER_NUM, allow_num::CFG, ptr::null_mut(), 0);
+    }
+
+    UDP_TX_STATE.set(TxState::Nothing);
+}
+
+// Future returned by UdpSocket::send_to. It borrows the caller's buffer for as
+// long as the kernel may access it.
+struct UdpSend<'a> {
+    done: bool,
+    _buffer: PhantomData<&'a [u8]>,
+}
+
+impl<'a> Future for UdpSend<'a> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(cb_data) = UDP_TX_DATA.take() {
+            finish_send();
+            self.done = true;
+
+            let x: UsizeError = cb_data.get_arg0().into();
+            Poll::Ready(match x.0 {
+                Some(e) => Err(e),
+                None => Ok(()),
+            })
+        } else {
+            UDP_TX_WAKER.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a> Drop for UdpSend<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            finish_send();
+        }
+    }
+}
+
+// Future returned by UdpSocket::recv_from
+struct UdpRecv<'a> {
+    index: usize,
+    buf: &'a mut [u8],
+}
+
+impl<'a> Future for UdpRecv<'a> {
+    type Output = Result<(usize, SocketAddr)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        let datagram = match UDP_SOCKETS.with(|sockets| {
+            sockets[this.index]
+                .as_mut()
+                .map(|slot| slot.datagram.take())
+        }) {
+            Some(datagram) => datagram,
+            None => return Poll::Ready(Err(Error::EINVAL)),
+        };
+
+        match datagram {
+            Some(datagram) => {
+                let len = core::cmp::min(datagram.len, this.buf.len());
+                this.buf[..len].copy_from_slice(&datagram.payload[..len]);
+
+                Poll::Ready(Ok((len, datagram.src)))
+            }
+            None => {
+                set_rx_waker(this.index, cx);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::host;
+
+    static BINDS: RacyCell<usize> = RacyCell::new(0);
+
+    // Loops every datagram back to the app, as the capsule does for a
+    // datagram to one of the app's own ports
+    struct Loopback;
+
+    impl host::Driver for Loopback {
+        fn command(&mut self, minor: usize, _arg1: usize, _arg2: usize) -> isize {
+            match minor {
+                command_num::BIND => {
+                    BINDS.with(|binds| *binds += 1);
+                    0
+                }
+                command_num::SEND => unsafe {
+                    let cfg = host::buffer(DRIVER_NUM, allow_num::CFG).unwrap();
+                    let payload = host::readonly_buffer(DRIVER_NUM, allow_num::TX).unwrap();
+
+                    if let (Some(rx), Some(rx_cfg)) = (
+                        host::buffer(DRIVER_NUM, allow_num::RX),
+                        host::buffer(DRIVER_NUM, allow_num::RX_CFG),
+                    ) {
+                        rx[..payload.len()].copy_from_slice(payload);
+                        rx_cfg.copy_from_slice(cfg);
+                        host::schedule(DRIVER_NUM, subscribe_num::RX, payload.len(), 0, 0);
+                    }
+
+                    host::schedule(DRIVER_NUM, subscribe_num::TX, 0, 0, 0);
+                    0
+                },
+                _ => Error::ENOSUPPORT as isize,
+            }
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED, port)
+    }
+
+    fn install() {
+        BINDS.set(0);
+        host::install(DRIVER_NUM, Box::leak(Box::new(Loopback)));
+    }
+
+    #[test]
+    fn datagrams_go_to_their_port() {
+        let _lock = host::lock();
+        install();
+
+        let a = UdpSocket::bind(addr(1000)).unwrap();
+        let b = UdpSocket::bind(addr(2000)).unwrap();
+        assert_eq!(BINDS.get(), 1);
+        assert_eq!(UdpSocket::bind(addr(1000)).err(), Some(Error::EALREADY));
+
+        let mut buf = [0; 8];
+
+        assert_eq!(host::run(a.send_to(b"to b", addr(2000)).unwrap()), Ok(()));
+        assert_eq!(host::run(b.recv_from(&mut buf)), Ok((4, addr(1000))));
+        assert_eq!(&buf[..4], b"to b");
+
+        assert_eq!(host::run(b.send_to(b"to a", addr(1000)).unwrap()), Ok(()));
+        assert_eq!(host::run(a.recv_from(&mut buf)), Ok((4, addr(2000))));
+        assert_eq!(&buf[..4], b"to a");
+    }
+
+    #[test]
+    fn dropped_socket_releases_port() {
+        let _lock = host::lock();
+        install();
+
+        let a = UdpSocket::bind(addr(1000)).unwrap();
+        let b = UdpSocket::bind(addr(2000)).unwrap();
+
+        drop(a);
+        let a = UdpSocket::bind(addr(1000)).unwrap();
+        assert_eq!(BINDS.get(), 1);
+
+        // Nothing is left bound, so the kernel gets its buffers back
+        drop(a);
+        drop(b);
+        assert!(unsafe { host::buffer(DRIVER_NUM, allow_num::RX) }.is_none());
+        assert!(!UDP_RX_READY.get());
+
+        let a = UdpSocket::bind(addr(1000)).unwrap();
+        assert_eq!(BINDS.get(), 2);
+        drop(a);
+    }
+}
@@ -0,0 +1,366 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_core::stream::Stream;
+
+use crate::alarm::{Alarm, Interval};
+use crate::cell::RacyCell;
+use crate::result::{Error, Result};
+use crate::syscalls::{command, subscribe, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = 0x60004;
+
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const DRIVER_EXISTS: usize = 0;
+    pub const READ_ACCELEROMETER: usize = 1;
+    pub const READ_MAGNETOMETER: usize = 100;
+    pub const READ_GYROSCOPE: usize = 200;
+}
+
+static NINEDOF_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
+
+static NINEDOF_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+extern "C" fn ninedof_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    // Reading whose future was dropped
+    if NINEDOF_STATE.get() == NinedofState::Abandoned {
+        NINEDOF_STATE.set(NinedofState::Nothing);
+        return;
+    }
+
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    NINEDOF_DATA.set(Some(cb_data));
+
+    NINEDOF_WAKER.wake();
+}
+
+// Indicates if a reading is in flight. The capsule has a single callback for
+// all three sensors, so only one reading can be outstanding at a time. The
+// capsule cannot cancel a reading, so one whose future was dropped is
+// `Abandoned` until its callback arrives, and that callback is thrown away
+// rather than taken for the next reading.
+#[derive(Copy, Clone, PartialEq)]
+enum NinedofState {
+    Ongoing,
+    Abandoned,
+    Nothing,
+}
+
+static NINEDOF_STATE: RacyCell<NinedofState> = RacyCell::new(NinedofState::Nothing);
+
+/// A reading along the three axes. Units depend on the sensor: milli-g for the
+/// accelerometer, and whatever the chip reports for the magnetometer and
+/// gyroscope.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Vector {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl Vector {
+    // The capsule passes signed values in the unsigned callback arguments
+    fn from_callback(cb_data: CallbackData) -> Vector {
+        Vector {
+            x: cb_data.get_arg0() as i32,
+            y: cb_data.get_arg1() as i32,
+            z: cb_data.get_arg2() as i32,
+        }
+    }
+
+    pub fn as_tuple(&self) -> (i32, i32, i32) {
+        (self.x, self.y, self.z)
+    }
+
+    /// Squared magnitude. Comparing this against a squared threshold avoids a
+    /// square root when looking for shakes.
+    pub fn magnitude_squared(&self) -> i64 {
+        let (x, y, z) = (self.x as i64, self.y as i64, self.z as i64);
+        x * x + y * y + z * z
+    }
+}
+
+impl From<Vector> for (i32, i32, i32) {
+    fn from(v: Vector) -> (i32, i32, i32) {
+        v.as_tuple()
+    }
+}
+
+/// One sample of all three sensors, as yielded by `Ninedof::motion`.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Motion {
+    pub accel: Vector,
+    pub mag: Vector,
+    pub gyro: Vector,
+}
+
+pub struct Ninedof;
+
+impl Ninedof {
+    pub fn new() -> Ninedof {
+        Ninedof
+    }
+
+    pub fn read_accel(&self) -> Result<impl Future<Output = Vector>> {
+        start_reading(command_num::READ_ACCELEROMETER)?;
+
+        Ok(NinedofReader { done: false })
+    }
+
+    pub fn read_mag(&self) -> Result<impl Future<Output = Vector>> {
+        start_reading(command_num::READ_MAGNETOMETER)?;
+
+        Ok(NinedofReader { done: false })
+    }
+
+    pub fn read_gyro(&self) -> Result<impl Future<Output = Vector>> {
+        start_reading(command_num::READ_GYROSCOPE)?;
+
+        Ok(NinedofReader { done: false })
+    }
+
+    // Read the accelerometer, magnetometer and gyroscope once every `period`.
+    // The three readings are taken back to back, so a sample is only as
+    // coherent as the sensor is fast.
+    pub fn motion(&self, period: Duration) -> Result<MotionStream> {
+        // Fail early with `ENODEVICE` when the board has no such sensor
+        unsafe {
+            command(DRIVER_NUM, command_num::DRIVER_EXISTS, 0, 0)?;
+        }
+
+        Ok(MotionStream {
+            interval: Alarm::new().interval(period)?,
+            step: MotionStep::Waiting,
+            motion: Motion::default(),
+        })
+    }
+}
+
+fn start_reading(cmd: usize) -> Result<()> {
+    if NINEDOF_STATE.get() != NinedofState::Nothing {
+        return Err(Error::EBUSY);
+    }
+
+    NINEDOF_DATA.set(None);
+
+    unsafe {
+        let _ = subscribe(
+            DRIVER_NUM,
+            subscribe_num::CALLBACK,
+            ninedof_callback as *const _,
+            0,
+        )
+        .and_then(|_| command(DRIVER_NUM, cmd, 0, 0))?;
+    }
+
+    NINEDOF_STATE.set(NinedofState::Ongoing);
+
+    Ok(())
+}
+
+// For a reading that is dropped before it was delivered. Unless its callback
+// has already arrived, the driver stays busy until it does.
+fn abandon_reading() {
+    if NINEDOF_DATA.take().is_some() {
+        NINEDOF_STATE.set(NinedofState::Nothing);
+    } else {
+        NINEDOF_STATE.set(NinedofState::Abandoned);
+    }
+}
+
+fn poll_reading(cx: &mut Context<'_>) -> Poll<Vector> {
+    if let Some(cb_data) = NINEDOF_DATA.take() {
+        NINEDOF_STATE.set(NinedofState::Nothing);
+        Poll::Ready(Vector::from_callback(cb_data))
+    } else {
+        NINEDOF_WAKER.register(cx.waker());
+        Poll::Pending
+    }
+}
+
+// Future returned by Ninedof::read_accel, Ninedof::read_mag and
+// Ninedof::read_gyro
+struct NinedofReader {
+    done: bool,
+}
+
+impl Future for NinedofReader {
+    type Output = Vector;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let reading = poll_reading(cx);
+        self.done = reading.is_ready();
+
+        reading
+    }
+}
+
+impl Drop for NinedofReader {
+    fn drop(&mut self) {
+        if !self.done {
+            abandon_reading();
+        }
+    }
+}
+
+// Which reading `MotionStream` is waiting for
+#[derive(Copy, Clone, PartialEq)]
+enum MotionStep {
+    Waiting,
+    Accel,
+    Mag,
+    Gyro,
+}
+
+/// Stream returned by `Ninedof::motion`. A new sample is only started when
+/// the previous one has been delivered.
+pub struct MotionStream {
+    interval: Interval,
+    step: MotionStep,
+    motion: Motion,
+}
+
+impl MotionStream {
+    fn start(&mut self, step: MotionStep, cmd: usize) -> Result<()> {
+        start_reading(cmd)?;
+        self.step = step;
+
+        Ok(())
+    }
+}
+
+impl Stream for MotionStream {
+    type Item = Result<Motion>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let res = match self.step {
+                MotionStep::Waiting => match Pin::new(&mut self.interval).poll_next(cx) {
+                    Poll::Ready(_) => {
+                        self.start(MotionStep::Accel, command_num::READ_ACCELEROMETER)
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                MotionStep::Accel => match poll_reading(cx) {
+                    Poll::Ready(v) => {
+                        self.motion.accel = v;
+                        self.start(MotionStep::Mag, command_num::READ_MAGNETOMETER)
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                MotionStep::Mag => match poll_reading(cx) {
+                    Poll::Ready(v) => {
+                        self.motion.mag = v;
+                        self.start(MotionStep::Gyro, command_num::READ_GYROSCOPE)
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                MotionStep::Gyro => match poll_reading(cx) {
+                    Poll::Ready(v) => {
+                        self.motion.gyro = v;
+                        self.step = MotionStep::Waiting;
+                        return Poll::Ready(Some(Ok(self.motion)));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+            };
+
+            if let Err(e) = res {
+                self.step = MotionStep::Waiting;
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
+
+// The alarm slot is released by the `Interval`
+impl Drop for MotionStream {
+    fn drop(&mut self) {
+        if self.step != MotionStep::Waiting {
+            abandon_reading();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarm;
+    use crate::syscalls::{self, host};
+    use core::task::Waker;
+
+    // Answers on the next yield with the command number along each axis, so
+    // that each sensor's readings can be told apart
+    struct FakeNinedof;
+
+    impl host::Driver for FakeNinedof {
+        fn command(&mut self, minor: usize, _arg1: usize, _arg2: usize) -> isize {
+            if minor != command_num::DRIVER_EXISTS {
+                host::schedule(DRIVER_NUM, subscribe_num::CALLBACK, minor, minor, minor);
+            }
+            0
+        }
+    }
+
+    // Ticks at 1 kHz, and the time is whatever the alarm upcall says
+    struct FakeAlarm;
+
+    impl host::Driver for FakeAlarm {
+        fn command(&mut self, minor: usize, _arg1: usize, _arg2: usize) -> isize {
+            match minor {
+                // Frequency
+                1 => 1000,
+                _ => 0,
+            }
+        }
+    }
+
+    // The late accelerometer reading must not become the magnetometer's
+    #[test]
+    fn late_reading_of_dropped_reader_is_discarded() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeNinedof)));
+
+        let ninedof = Ninedof::new();
+        drop(ninedof.read_accel().unwrap());
+        assert_eq!(ninedof.read_mag().err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+
+        let reader = ninedof.read_mag().unwrap();
+        assert_eq!(host::run(reader).as_tuple(), (100, 100, 100));
+        assert_eq!(host::scheduled(), 0);
+    }
+
+    #[test]
+    fn late_reading_of_dropped_motion_stream_is_discarded() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeNinedof)));
+        host::install(alarm::DRIVER_NUM, Box::leak(Box::new(FakeAlarm)));
+
+        let mut motion = Ninedof::new().motion(Duration::from_millis(10)).unwrap();
+
+        // The alarm upcall at tick 10 fires the interval, which starts reading
+        // the accelerometer
+        host::schedule(alarm::DRIVER_NUM, 0, 10, 0, 0);
+        syscalls::yieldk();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut motion).poll_next(&mut cx).is_pending());
+
+        drop(motion);
+        assert_eq!(Ninedof::new().read_gyro().err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+
+        let reader = Ninedof::new().read_gyro().unwrap();
+        assert_eq!(host::run(reader).as_tuple(), (200, 200, 200));
+        assert_eq!(host::scheduled(), 0);
+    }
+}
@@ -1,5 +1,7 @@
 use core::result;
 
+use allocator_api::AllocErr;
+
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -42,8 +44,22 @@ impl From<isize> for Error {
 }
 
 // `newtype` to take care of errors returned via `usize` with a `isize` value.
+//
+// Under the Tock 2.0 ABI, upcalls carry the (positive) error code instead.
 pub struct UsizeError(pub Option<Error>);
 
+#[cfg(feature = "tock2")]
+impl From<usize> for UsizeError {
+    fn from(u: usize) -> UsizeError {
+        match u {
+            0 => UsizeError(None),
+            code @ 1..=13 => UsizeError(Some(crate::syscalls::tock2::error_code(code))),
+            _ => UsizeError(None),
+        }
+    }
+}
+
+#[cfg(not(feature = "tock2"))]
 impl From<usize> for UsizeError {
     fn from(u: usize) -> UsizeError {
         match u {
@@ -70,3 +86,21 @@ impl From<usize> for UsizeError {
         }
     }
 }
+
+// A `FutureBox` arena ran out of memory
+impl From<AllocErr> for Error {
+    fn from(_: AllocErr) -> Error {
+        Error::ENOMEM
+    }
+}
+
+// `EBUSY` becomes `WouldBlock` for the `nb`-based `embedded-hal` traits, so
+// that callers retry instead of failing while an async operation is running.
+// Only the console implements those.
+#[cfg(feature = "console")]
+pub(crate) fn nb_error(e: Error) -> nb::Error<Error> {
+    match e {
+        Error::EBUSY => nb::Error::WouldBlock,
+        e => nb::Error::Other(e),
+    }
+}
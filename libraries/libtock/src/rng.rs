@@ -0,0 +1,241 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+
+use rand_core::{impls, CryptoRng, ErrorKind, RngCore};
+
+use crate::cell::RacyCell;
+use crate::futures::block_on;
+use crate::result::{Error, Result};
+use crate::syscalls::{allow, command, subscribe, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = 0x40001;
+
+mod allow_num {
+    pub const BUFFER: usize = 0;
+}
+
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const REQUEST_RNG: usize = 1;
+}
+
+const POOL_LEN: usize = 64;
+
+static RNG_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
+
+static RNG_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+extern "C" fn rng_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    RNG_DATA.set(Some(cb_data));
+
+    RNG_WAKER.wake();
+}
+
+// Entropy pool. The kernel fills the whole of `RNG_POOL`, and fills are served
+// from `RNG_POOL[..RNG_POOL_AVAILABLE]`, consuming from the end. A syscall
+// round trip is only made once the pool runs dry.
+static RNG_POOL: RacyCell<[u8; POOL_LEN]> = RacyCell::new([0; POOL_LEN]);
+
+static RNG_POOL_AVAILABLE: RacyCell<usize> = RacyCell::new(0);
+
+// Indicates if the kernel is filling `RNG_POOL`. This outlives the future that
+// made the request: if it is dropped, the next fill picks up the callback
+// instead of issuing a second request.
+static RNG_REQUESTING: RacyCell<bool> = RacyCell::new(false);
+
+// Indicates if there is an ongoing fill. A second fill while one is in flight
+// is rejected with `EBUSY`.
+#[derive(Copy, Clone, PartialEq)]
+enum RngState {
+    Ongoing,
+    Nothing,
+}
+
+static RNG_STATE: RacyCell<RngState> = RacyCell::new(RngState::Nothing);
+
+pub struct Rng;
+
+impl Rng {
+    pub fn new() -> Rng {
+        Rng
+    }
+
+    pub fn fill_bytes<'a>(
+        &self,
+        buf: &'a mut [u8],
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        if RNG_STATE.get() != RngState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        RNG_STATE.set(RngState::Ongoing);
+
+        Ok(RngFill { buf, filled: 0 })
+    }
+}
+
+// Move as many bytes as possible from the pool into `buf`, returning how many
+// were moved. Consumed bytes are cleared so they cannot be handed out twice.
+fn take_from_pool(buf: &mut [u8]) -> usize {
+    let available = RNG_POOL_AVAILABLE.get();
+    let n = core::cmp::min(buf.len(), available);
+    let start = available - n;
+
+    RNG_POOL.with(|pool| {
+        buf[..n].copy_from_slice(&pool[start..available]);
+        pool[start..available].iter_mut().for_each(|x| *x = 0);
+    });
+    RNG_POOL_AVAILABLE.set(start);
+
+    n
+}
+
+fn request_pool() -> Result<()> {
+    RNG_DATA.set(None);
+
+    unsafe {
+        let _ = allow(
+            DRIVER_NUM,
+            allow_num::BUFFER,
+            RNG_POOL.as_ptr() as *mut u8,
+            POOL_LEN,
+        )
+        .and_then(|_| {
+            subscribe(
+                DRIVER_NUM,
+                subscribe_num::CALLBACK,
+                rng_callback as *const _,
+                0,
+            )
+        })
+        .and_then(|_| command(DRIVER_NUM, command_num::REQUEST_RNG, POOL_LEN, 0))?;
+    }
+
+    RNG_REQUESTING.set(true);
+
+    Ok(())
+}
+
+// Future returned by Rng::fill_bytes
+struct RngFill<'a> {
+    buf: &'a mut [u8],
+    filled: usize,
+}
+
+impl<'a> Future for RngFill<'a> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        loop {
+            if RNG_REQUESTING.get() {
+                match RNG_DATA.take() {
+                    // `arg1` is the number of random bytes written
+                    Some(cb_data) => {
+                        // Take the pool back before reading from it
+                        unsafe {
+                            let _ = allow(DRIVER_NUM, allow_num::BUFFER, ptr::null_mut(), 0);
+                        }
+
+                        RNG_REQUESTING.set(false);
+                        RNG_POOL_AVAILABLE.set(core::cmp::min(cb_data.get_arg1(), POOL_LEN));
+                    }
+                    None => {
+                        RNG_WAKER.register(cx.waker());
+                        return Poll::Pending;
+                    }
+                }
+            }
+
+            this.filled += take_from_pool(&mut this.buf[this.filled..]);
+
+            if this.filled == this.buf.len() {
+                RNG_STATE.set(RngState::Nothing);
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Err(e) = request_pool() {
+                RNG_STATE.set(RngState::Nothing);
+                return Poll::Ready(Err(e));
+            }
+        }
+    }
+}
+
+impl<'a> Drop for RngFill<'a> {
+    fn drop(&mut self) {
+        RNG_STATE.set(RngState::Nothing);
+    }
+}
+
+// `rand_core` traits. These spin on `yieldk` until enough entropy has been
+// gathered, so that `rand` based code works unmodified.
+impl RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest).expect("rng: fill failed")
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> core::result::Result<(), rand_core::Error> {
+        Rng::fill_bytes(self, dest)
+            .and_then(block_on)
+            .map_err(|_| rand_core::Error::new(ErrorKind::Unavailable, "rng capsule failed"))
+    }
+}
+
+// Entropy comes from the hardware RNG, not from a seeded generator
+impl CryptoRng for Rng {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::host;
+
+    // Fills the whole buffer with 0x5A on the next yield
+    struct FakeRng;
+
+    impl host::Driver for FakeRng {
+        fn command(&mut self, _minor: usize, len: usize, _arg2: usize) -> isize {
+            let buf = unsafe { host::buffer(DRIVER_NUM, allow_num::BUFFER) }.unwrap();
+            buf.iter_mut().for_each(|x| *x = 0x5A);
+
+            host::schedule(DRIVER_NUM, subscribe_num::CALLBACK, 0, len, 0);
+            0
+        }
+    }
+
+    // `try_fill_bytes` goes through `block_on`, whose waker must not stick
+    #[test]
+    fn blocking_then_async() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeRng)));
+        RNG_POOL_AVAILABLE.set(0);
+
+        let mut rng = Rng::new();
+        let mut buf = [0; POOL_LEN];
+        assert!(rng.try_fill_bytes(&mut buf).is_ok());
+        assert_eq!(buf, [0x5A; POOL_LEN]);
+
+        let mut buf = [0; 8];
+        let fill = Rng::fill_bytes(&rng, &mut buf).unwrap();
+        assert_eq!(host::run(fill), Ok(()));
+        assert_eq!(buf, [0x5A; 8]);
+
+        assert!(unsafe { host::buffer(DRIVER_NUM, allow_num::BUFFER) }.is_none());
+    }
+}
@@ -0,0 +1,171 @@
+use core::cmp::{max, min};
+use core::future::Future;
+use core::marker::PhantomData;
+
+use embedded_graphics::drawable::Pixel;
+use embedded_graphics::geometry::Size;
+use embedded_graphics::pixelcolor::raw::{RawData, RawU16};
+use embedded_graphics::pixelcolor::{BinaryColor, PixelColor, Rgb565};
+use embedded_graphics::DrawTarget;
+
+use super::{Frame, PixelFormat, ScreenWrite};
+use crate::result::{Error, Result};
+
+/// Colors `Display` can store, along with how they are laid out in the
+/// framebuffer. The layout is what the capsule expects for `FORMAT`.
+pub trait ScreenColor: PixelColor {
+    const FORMAT: PixelFormat;
+
+    // Store the pixel at `index`, counting from the top left, row by row
+    fn set_pixel(buf: &mut [u8], index: usize, color: Self);
+}
+
+// One bit per pixel, most significant bit first
+impl ScreenColor for BinaryColor {
+    const FORMAT: PixelFormat = PixelFormat::Mono;
+
+    fn set_pixel(buf: &mut [u8], index: usize, color: BinaryColor) {
+        let mask = 0x80 >> (index % 8);
+
+        if color.is_on() {
+            buf[index / 8] |= mask;
+        } else {
+            buf[index / 8] &= !mask;
+        }
+    }
+}
+
+// Two bytes per pixel, big-endian
+impl ScreenColor for Rgb565 {
+    const FORMAT: PixelFormat = PixelFormat::Rgb565;
+
+    fn set_pixel(buf: &mut [u8], index: usize, color: Rgb565) {
+        let raw = RawU16::from(color).into_inner();
+
+        buf[index * 2] = (raw >> 8) as u8;
+        buf[index * 2 + 1] = raw as u8;
+    }
+}
+
+// Rows that changed since the last flush, as a half-open range. Only whole
+// rows are contiguous in the framebuffer, so the dirty rectangle is always
+// as wide as the screen.
+#[derive(Copy, Clone, PartialEq)]
+struct Dirty {
+    start: usize,
+    end: usize,
+}
+
+/// An `embedded-graphics` draw target backed by a framebuffer in app memory.
+/// Drawing only touches the framebuffer; `flush` then sends what changed to
+/// the screen. `C` has to match the screen's pixel format.
+pub struct Display<'a, C> {
+    buf: &'a mut [u8],
+    width: usize,
+    height: usize,
+    dirty: Option<Dirty>,
+    _color: PhantomData<C>,
+}
+
+impl<'a, C: ScreenColor> Display<'a, C> {
+    // `buf` has to hold `width` by `height` pixels, e.g. as reported by
+    // `Screen::get_resolution`. Each row starts on a byte boundary.
+    pub fn new(buf: &'a mut [u8], width: usize, height: usize) -> Result<Display<'a, C>> {
+        // `flush` has to be able to address any row as a frame
+        Frame::new(0, 0, width, height)?;
+
+        if buf.len() < Self::get_stride(width) * height {
+            return Err(Error::ESIZE);
+        }
+
+        Ok(Display {
+            buf,
+            width,
+            height,
+            dirty: None,
+            _color: PhantomData,
+        })
+    }
+
+    fn get_stride(width: usize) -> usize {
+        (width * C::FORMAT.get_bits_per_pixel()).div_ceil(8)
+    }
+
+    pub fn get_width(&self) -> usize {
+        self.width
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    // Mark the whole screen as changed, e.g. after a failed flush
+    pub fn invalidate(&mut self) {
+        self.dirty = Some(Dirty {
+            start: 0,
+            end: self.height,
+        });
+    }
+
+    fn mark_dirty(&mut self, y: usize) {
+        self.dirty = Some(match self.dirty {
+            Some(d) => Dirty {
+                start: min(d.start, y),
+                end: max(d.end, y + 1),
+            },
+            None => Dirty {
+                start: y,
+                end: y + 1,
+            },
+        });
+    }
+
+    // Send the rows that changed to the screen. Resolves straight away if
+    // nothing changed.
+    pub fn flush(&mut self) -> Result<impl Future<Output = Result<()>> + '_> {
+        let dirty = match self.dirty.take() {
+            Some(dirty) => dirty,
+            None => return Ok(ScreenWrite::done()),
+        };
+
+        let stride = Self::get_stride(self.width);
+        let rows = &self.buf[dirty.start * stride..dirty.end * stride];
+        let frame = Frame::new(0, dirty.start, self.width, dirty.end - dirty.start)?;
+
+        match ScreenWrite::new(Some(frame), rows) {
+            Ok(write) => Ok(write),
+            Err(e) => {
+                self.dirty = Some(dirty);
+                Err(e)
+            }
+        }
+    }
+}
+
+// Pixels outside the screen are clipped, so drawing never fails
+impl<'a, C: ScreenColor> DrawTarget<C> for Display<'a, C> {
+    type Error = Error;
+
+    fn draw_pixel(&mut self, pixel: Pixel<C>) -> Result<()> {
+        let Pixel(point, color) = pixel;
+
+        if point.x < 0 || point.y < 0 {
+            return Ok(());
+        }
+
+        let (x, y) = (point.x as usize, point.y as usize);
+        if x >= self.width || y >= self.height {
+            return Ok(());
+        }
+
+        let row_start = y * Self::get_stride(self.width) * 8 / C::FORMAT.get_bits_per_pixel();
+        C::set_pixel(self.buf, row_start + x, color);
+        self.mark_dirty(y);
+
+        Ok(())
+    }
+
+    fn size(&self) -> Size {
+        Size::new(self.width as u32, self.height as u32)
+    }
+}
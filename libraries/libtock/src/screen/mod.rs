@@ -0,0 +1,447 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+
+use crate::cell::RacyCell;
+use crate::result::{Error, Result, UsizeError};
+use crate::syscalls::{allow_readonly, command, subscribe, CallbackData};
+
+mod display;
+
+pub use self::display::{Display, ScreenColor};
+
+pub(crate) const DRIVER_NUM: usize = 0x90001;
+
+mod allow_num {
+    pub const BUFFER: usize = 0;
+}
+
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const SET_BRIGHTNESS: usize = 3;
+    pub const GET_ROTATION: usize = 21;
+    pub const SET_ROTATION: usize = 22;
+    pub const GET_RESOLUTION: usize = 23;
+    pub const GET_PIXEL_FORMAT: usize = 25;
+    pub const SET_FRAME: usize = 100;
+    pub const WRITE: usize = 200;
+}
+
+static SCREEN_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
+
+static SCREEN_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+extern "C" fn screen_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    // Nobody is waiting for the answer to a dropped command
+    if SCREEN_STATE.get() == ScreenState::Abandoned {
+        SCREEN_STATE.set(ScreenState::Nothing);
+        return;
+    }
+
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    SCREEN_DATA.set(Some(cb_data));
+
+    SCREEN_WAKER.wake();
+}
+
+// Indicates if a command is in flight. Every screen command completes through
+// the same callback, so only one can be outstanding at a time. A command whose
+// future was dropped is `Abandoned`: the capsule is still busy with it, and its
+// answer must not be taken for the one to the next command.
+#[derive(Copy, Clone, PartialEq)]
+enum ScreenState {
+    Ongoing,
+    Abandoned,
+    Nothing,
+}
+
+static SCREEN_STATE: RacyCell<ScreenState> = RacyCell::new(ScreenState::Nothing);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PixelFormat {
+    Mono = 0,
+    Rgb233 = 1,
+    Rgb565 = 2,
+    Rgb888 = 3,
+    Argb8888 = 4,
+}
+
+impl PixelFormat {
+    fn from_usize(format: usize) -> Option<PixelFormat> {
+        match format {
+            0 => Some(PixelFormat::Mono),
+            1 => Some(PixelFormat::Rgb233),
+            2 => Some(PixelFormat::Rgb565),
+            3 => Some(PixelFormat::Rgb888),
+            4 => Some(PixelFormat::Argb8888),
+            _ => None,
+        }
+    }
+
+    pub fn get_bits_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Mono => 1,
+            PixelFormat::Rgb233 => 8,
+            PixelFormat::Rgb565 => 16,
+            PixelFormat::Rgb888 => 24,
+            PixelFormat::Argb8888 => 32,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Rotation {
+    Normal = 0,
+    Rotated90 = 1,
+    Rotated180 = 2,
+    Rotated270 = 3,
+}
+
+impl Rotation {
+    fn from_usize(rotation: usize) -> Option<Rotation> {
+        match rotation {
+            0 => Some(Rotation::Normal),
+            1 => Some(Rotation::Rotated90),
+            2 => Some(Rotation::Rotated180),
+            3 => Some(Rotation::Rotated270),
+            _ => None,
+        }
+    }
+}
+
+/// Wraps the screen capsule. Each call returns a future that resolves once the
+/// capsule has answered; a second call while one is running fails with
+/// `EBUSY`.
+pub struct Screen;
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen
+    }
+
+    // Width and height in pixels, with the current rotation applied
+    pub fn get_resolution(&self) -> Result<impl Future<Output = Result<(usize, usize)>>> {
+        ScreenCommand::new(command_num::GET_RESOLUTION, 0, 0, |w, h| Ok((w, h)))
+    }
+
+    pub fn get_pixel_format(&self) -> Result<impl Future<Output = Result<PixelFormat>>> {
+        ScreenCommand::new(command_num::GET_PIXEL_FORMAT, 0, 0, |format, _| {
+            PixelFormat::from_usize(format).ok_or(Error::ENOSUPPORT)
+        })
+    }
+
+    pub fn get_rotation(&self) -> Result<impl Future<Output = Result<Rotation>>> {
+        ScreenCommand::new(command_num::GET_ROTATION, 0, 0, |rotation, _| {
+            Rotation::from_usize(rotation).ok_or(Error::ENOSUPPORT)
+        })
+    }
+
+    pub fn set_rotation(&self, rotation: Rotation) -> Result<impl Future<Output = Result<()>>> {
+        ScreenCommand::new(command_num::SET_ROTATION, rotation as usize, 0, |_, _| {
+            Ok(())
+        })
+    }
+
+    pub fn set_brightness(&self, brightness: usize) -> Result<impl Future<Output = Result<()>>> {
+        ScreenCommand::new(command_num::SET_BRIGHTNESS, brightness, 0, |_, _| Ok(()))
+    }
+
+    // Fill the whole screen from `buf`, which holds the rows one after the
+    // other in the screen's pixel format.
+    pub fn write_frame<'a>(&self, buf: &'a [u8]) -> Result<impl Future<Output = Result<()>> + 'a> {
+        ScreenWrite::new(None, buf)
+    }
+
+    // Same as `write_frame`, for the `width` by `height` rectangle whose top
+    // left corner is at `x`, `y`.
+    pub fn write_region<'a>(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        buf: &'a [u8],
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        ScreenWrite::new(Some(Frame::new(x, y, width, height)?), buf)
+    }
+}
+
+fn start(cmd: usize, arg1: usize, arg2: usize) -> Result<()> {
+    if SCREEN_STATE.get() != ScreenState::Nothing {
+        return Err(Error::EBUSY);
+    }
+
+    SCREEN_DATA.set(None);
+
+    unsafe {
+        let _ = subscribe(
+            DRIVER_NUM,
+            subscribe_num::CALLBACK,
+            screen_callback as *const _,
+            0,
+        )
+        .and_then(|_| command(DRIVER_NUM, cmd, arg1, arg2))?;
+    }
+
+    SCREEN_STATE.set(ScreenState::Ongoing);
+
+    Ok(())
+}
+
+// The callback passes the status in `arg0` and up to two values after it
+fn poll_done(cx: &mut Context<'_>) -> Poll<Result<(usize, usize)>> {
+    if let Some(cb_data) = SCREEN_DATA.take() {
+        SCREEN_STATE.set(ScreenState::Nothing);
+
+        let x: UsizeError = cb_data.get_arg0().into();
+        Poll::Ready(match x.0 {
+            Some(e) => Err(e),
+            None => Ok((cb_data.get_arg1(), cb_data.get_arg2())),
+        })
+    } else {
+        SCREEN_WAKER.register(cx.waker());
+        Poll::Pending
+    }
+}
+
+fn abandon() {
+    SCREEN_DATA.set(None);
+
+    if SCREEN_STATE.get() == ScreenState::Ongoing {
+        SCREEN_STATE.set(ScreenState::Abandoned);
+    }
+}
+
+// Future returned by the `Screen` getters and setters
+struct ScreenCommand<T> {
+    convert: fn(usize, usize) -> Result<T>,
+    done: bool,
+}
+
+impl<T> ScreenCommand<T> {
+    fn new(
+        cmd: usize,
+        arg1: usize,
+        arg2: usize,
+        convert: fn(usize, usize) -> Result<T>,
+    ) -> Result<ScreenCommand<T>> {
+        start(cmd, arg1, arg2)?;
+
+        Ok(ScreenCommand {
+            convert,
+            done: false,
+        })
+    }
+}
+
+impl<T> Future for ScreenCommand<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match poll_done(cx) {
+            Poll::Ready(r) => {
+                self.done = true;
+                Poll::Ready(r.and_then(|(arg1, arg2)| (self.convert)(arg1, arg2)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Dropped before the capsule answered. The next command can start once the
+// answer to this one has come in.
+impl<T> Drop for ScreenCommand<T> {
+    fn drop(&mut self) {
+        if !self.done {
+            abandon();
+        }
+    }
+}
+
+// A rectangle in the form `SET_FRAME` takes it
+#[derive(Copy, Clone, PartialEq)]
+struct Frame {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Frame {
+    fn new(x: usize, y: usize, width: usize, height: usize) -> Result<Frame> {
+        // Each coordinate is packed into 16 bits
+        if [x, y, width, height].iter().any(|v| *v > 0xffff) {
+            return Err(Error::EINVAL);
+        }
+
+        Ok(Frame {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    fn start(&self) -> Result<()> {
+        start(
+            command_num::SET_FRAME,
+            (self.x << 16) | self.y,
+            (self.width << 16) | self.height,
+        )
+    }
+}
+
+// Where `ScreenWrite` is at. Writing the whole frame first asks for the
+// resolution, then sets the frame, then sends the pixels.
+#[derive(Copy, Clone, PartialEq)]
+enum WriteStep {
+    Resolution,
+    Frame,
+    Write,
+    Done,
+}
+
+// Future returned by Screen::write_frame and Screen::write_region. It borrows
+// the caller's buffer for as long as the kernel may access it.
+struct ScreenWrite<'a> {
+    buf: &'a [u8],
+    step: WriteStep,
+}
+
+impl<'a> ScreenWrite<'a> {
+    fn new(frame: Option<Frame>, buf: &'a [u8]) -> Result<ScreenWrite<'a>> {
+        if buf.is_empty() {
+            return Err(Error::EINVAL);
+        }
+
+        let step = match frame {
+            Some(frame) => {
+                frame.start()?;
+                WriteStep::Frame
+            }
+            None => {
+                start(command_num::GET_RESOLUTION, 0, 0)?;
+                WriteStep::Resolution
+            }
+        };
+
+        Ok(ScreenWrite { buf, step })
+    }
+
+    // A future that is ready straight away, for when there is nothing to write
+    fn done() -> ScreenWrite<'static> {
+        ScreenWrite {
+            buf: &[],
+            step: WriteStep::Done,
+        }
+    }
+
+    fn next(&mut self, arg1: usize, arg2: usize) -> Result<()> {
+        match self.step {
+            WriteStep::Resolution => {
+                Frame::new(0, 0, arg1, arg2)?.start()?;
+                self.step = WriteStep::Frame;
+            }
+            WriteStep::Frame => {
+                unsafe {
+                    allow_readonly(
+                        DRIVER_NUM,
+                        allow_num::BUFFER,
+                        self.buf.as_ptr(),
+                        self.buf.len(),
+                    )
+                }
+                .and_then(|_| start(command_num::WRITE, self.buf.len(), 0))?;
+                self.step = WriteStep::Write;
+            }
+            WriteStep::Write | WriteStep::Done => self.finish(),
+        }
+
+        Ok(())
+    }
+
+    // Take the caller's buffer back from the kernel
+    fn finish(&mut self) {
+        if self.step == WriteStep::Write {
+            unsafe {
+                let _ = allow_readonly(DRIVER_NUM, allow_num::BUFFER, ptr::null(), 0);
+            }
+        }
+
+        self.step = WriteStep::Done;
+    }
+}
+
+impl<'a> Future for ScreenWrite<'a> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        loop {
+            if this.step == WriteStep::Done {
+                return Poll::Ready(Ok(()));
+            }
+
+            let res = match poll_done(cx) {
+                Poll::Ready(Ok((arg1, arg2))) => this.next(arg1, arg2),
+                Poll::Ready(Err(e)) => Err(e),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if let Err(e) = res {
+                this.finish();
+                return Poll::Ready(Err(e));
+            }
+        }
+    }
+}
+
+impl<'a> Drop for ScreenWrite<'a> {
+    fn drop(&mut self) {
+        if self.step != WriteStep::Done {
+            self.finish();
+
+            abandon();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{self, host};
+
+    // A 10 by 20 screen that answers every command on the next yield
+    struct FakeScreen;
+
+    impl host::Driver for FakeScreen {
+        fn command(&mut self, _minor: usize, _arg1: usize, _arg2: usize) -> isize {
+            host::schedule(DRIVER_NUM, subscribe_num::CALLBACK, 0, 10, 20);
+            0
+        }
+    }
+
+    #[test]
+    fn dropped_command_answer_is_discarded() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeScreen)));
+
+        let screen = Screen::new();
+        drop(screen.set_brightness(5).unwrap());
+
+        // The capsule has not answered yet
+        assert_eq!(screen.get_resolution().err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+        assert_eq!(host::scheduled(), 0);
+
+        let resolution = screen.get_resolution().unwrap();
+        assert_eq!(host::run(resolution), Ok((10, 20)));
+    }
+}
@@ -0,0 +1,435 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_core::stream::Stream;
+
+use crate::alarm::{Alarm, Interval};
+use crate::cell::{register_waker, RacyCell};
+use crate::result::{Error, Result};
+use crate::syscalls::{command, subscribe, CallbackData};
+
+// The temperature, humidity and ambient light capsules share the same
+// interface: command 1 starts a reading and the reading is passed in `arg0` of
+// the callback. A board without the sensor answers `ENODEVICE`.
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const DRIVER_EXISTS: usize = 0;
+    pub const READ: usize = 1;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum SensorKind {
+    Temperature = 0,
+    Humidity = 1,
+    AmbientLight = 2,
+}
+
+const NUM_SENSORS: usize = 3;
+
+impl SensorKind {
+    fn get_driver_num(&self) -> usize {
+        match self {
+            SensorKind::Temperature => TEMPERATURE_DRIVER_NUM,
+            SensorKind::Humidity => HUMIDITY_DRIVER_NUM,
+            SensorKind::AmbientLight => AMBIENT_LIGHT_DRIVER_NUM,
+        }
+    }
+
+    fn get_callback(&self) -> extern "C" fn(usize, usize, usize, usize) {
+        match self {
+            SensorKind::Temperature => temperature_callback,
+            SensorKind::Humidity => humidity_callback,
+            SensorKind::AmbientLight => ambient_light_callback,
+        }
+    }
+}
+
+pub(crate) const TEMPERATURE_DRIVER_NUM: usize = 0x60000;
+pub(crate) const HUMIDITY_DRIVER_NUM: usize = 0x60001;
+pub(crate) const AMBIENT_LIGHT_DRIVER_NUM: usize = 0x60002;
+
+// Indexed by `SensorKind`
+static SENSOR_DATA: RacyCell<[Option<CallbackData>; NUM_SENSORS]> =
+    RacyCell::new([None, None, None]);
+
+static SENSOR_WAKERS: RacyCell<[Option<Waker>; NUM_SENSORS]> = RacyCell::new([None, None, None]);
+
+// Indicates if a reading has been started and its callback has not arrived
+// yet. A second read of the same sensor while one is in flight is rejected
+// with `EBUSY`. The capsule cannot cancel a reading, so one whose future was
+// dropped is `Abandoned` until its callback arrives, and that callback is
+// thrown away.
+#[derive(Copy, Clone, PartialEq)]
+enum SensorState {
+    Reading,
+    Abandoned,
+    Idle,
+}
+
+// Indexed by `SensorKind`
+static SENSOR_STATE: RacyCell<[SensorState; NUM_SENSORS]> =
+    RacyCell::new([SensorState::Idle; NUM_SENSORS]);
+
+fn sensor_state(kind: SensorKind) -> SensorState {
+    SENSOR_STATE.with(|state| state[kind as usize])
+}
+
+fn set_sensor_state(kind: SensorKind, s: SensorState) {
+    SENSOR_STATE.with(|state| state[kind as usize] = s);
+}
+
+fn sensor_callback(kind: SensorKind, cb_data: CallbackData) {
+    if sensor_state(kind) == SensorState::Abandoned {
+        set_sensor_state(kind, SensorState::Idle);
+        return;
+    }
+
+    SENSOR_DATA.with(|data| data[kind as usize] = Some(cb_data));
+
+    if let Some(waker) = SENSOR_WAKERS.with(|wakers| wakers[kind as usize].clone()) {
+        waker.wake();
+    }
+}
+
+extern "C" fn temperature_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    sensor_callback(
+        SensorKind::Temperature,
+        CallbackData::new(arg0, arg1, arg2, userdata),
+    );
+}
+
+extern "C" fn humidity_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    sensor_callback(
+        SensorKind::Humidity,
+        CallbackData::new(arg0, arg1, arg2, userdata),
+    );
+}
+
+extern "C" fn ambient_light_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    sensor_callback(
+        SensorKind::AmbientLight,
+        CallbackData::new(arg0, arg1, arg2, userdata),
+    );
+}
+
+fn set_sensor_waker(kind: SensorKind, cx: &mut Context<'_>) {
+    SENSOR_WAKERS.with(|wakers| register_waker(&mut wakers[kind as usize], cx.waker()));
+}
+
+fn start_reading(kind: SensorKind) -> Result<()> {
+    if sensor_state(kind) != SensorState::Idle {
+        return Err(Error::EBUSY);
+    }
+
+    SENSOR_DATA.with(|data| data[kind as usize] = None);
+
+    unsafe {
+        let _ = subscribe(
+            kind.get_driver_num(),
+            subscribe_num::CALLBACK,
+            kind.get_callback() as *const _,
+            0,
+        )
+        .and_then(|_| command(kind.get_driver_num(), command_num::READ, 0, 0))?;
+    }
+
+    set_sensor_state(kind, SensorState::Reading);
+
+    Ok(())
+}
+
+// For a reading that is dropped before it was delivered. Unless its callback
+// has already arrived, the sensor stays busy until it does.
+fn abandon_reading(kind: SensorKind) {
+    if SENSOR_DATA
+        .with(|data| data[kind as usize].take())
+        .is_some()
+    {
+        set_sensor_state(kind, SensorState::Idle);
+    } else {
+        set_sensor_state(kind, SensorState::Abandoned);
+    }
+}
+
+fn poll_reading(kind: SensorKind, cx: &mut Context<'_>) -> Poll<usize> {
+    if let Some(cb_data) = SENSOR_DATA.with(|data| data[kind as usize].take()) {
+        set_sensor_state(kind, SensorState::Idle);
+        Poll::Ready(cb_data.get_arg0())
+    } else {
+        set_sensor_waker(kind, cx);
+        Poll::Pending
+    }
+}
+
+/// Temperature in hundredths of a degree Celsius.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CentiCelsius(pub i32);
+
+/// Relative humidity in hundredths of a percent.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CentiPercent(pub u32);
+
+/// Illuminance in lux.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Lux(pub u32);
+
+pub struct Temperature;
+
+impl Temperature {
+    pub fn new() -> Temperature {
+        Temperature
+    }
+
+    pub fn read(&self) -> Result<impl Future<Output = CentiCelsius>> {
+        start_reading(SensorKind::Temperature)?;
+
+        Ok(SensorReader {
+            kind: SensorKind::Temperature,
+            convert: |r| CentiCelsius(r as i32),
+            done: false,
+        })
+    }
+
+    pub fn periodic(&self, period: Duration) -> Result<Periodic<CentiCelsius>> {
+        Periodic::new(SensorKind::Temperature, period, |r| CentiCelsius(r as i32))
+    }
+}
+
+pub struct Humidity;
+
+impl Humidity {
+    pub fn new() -> Humidity {
+        Humidity
+    }
+
+    pub fn read(&self) -> Result<impl Future<Output = CentiPercent>> {
+        start_reading(SensorKind::Humidity)?;
+
+        Ok(SensorReader {
+            kind: SensorKind::Humidity,
+            convert: |r| CentiPercent(r as u32),
+            done: false,
+        })
+    }
+
+    pub fn periodic(&self, period: Duration) -> Result<Periodic<CentiPercent>> {
+        Periodic::new(SensorKind::Humidity, period, |r| CentiPercent(r as u32))
+    }
+}
+
+pub struct AmbientLight;
+
+impl AmbientLight {
+    pub fn new() -> AmbientLight {
+        AmbientLight
+    }
+
+    pub fn read(&self) -> Result<impl Future<Output = Lux>> {
+        start_reading(SensorKind::AmbientLight)?;
+
+        Ok(SensorReader {
+            kind: SensorKind::AmbientLight,
+            convert: |r| Lux(r as u32),
+            done: false,
+        })
+    }
+
+    pub fn periodic(&self, period: Duration) -> Result<Periodic<Lux>> {
+        Periodic::new(SensorKind::AmbientLight, period, |r| Lux(r as u32))
+    }
+}
+
+// Future returned by Temperature::read, Humidity::read and AmbientLight::read
+struct SensorReader<T> {
+    kind: SensorKind,
+    convert: fn(usize) -> T,
+    done: bool,
+}
+
+impl<T> Future for SensorReader<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let reading = poll_reading(self.kind, cx);
+        self.done = reading.is_ready();
+
+        reading.map(self.convert)
+    }
+}
+
+impl<T> Drop for SensorReader<T> {
+    fn drop(&mut self) {
+        if !self.done {
+            abandon_reading(self.kind);
+        }
+    }
+}
+
+/// Stream of readings taken once every period, returned by the sensors'
+/// `periodic`. A reading is only started when the previous one has been
+/// delivered.
+pub struct Periodic<T> {
+    kind: SensorKind,
+    convert: fn(usize) -> T,
+    interval: Interval,
+    reading: bool,
+}
+
+impl<T> Periodic<T> {
+    fn new(kind: SensorKind, period: Duration, convert: fn(usize) -> T) -> Result<Periodic<T>> {
+        // Fail early with `ENODEVICE` when the board has no such sensor
+        unsafe {
+            command(kind.get_driver_num(), command_num::DRIVER_EXISTS, 0, 0)?;
+        }
+
+        Ok(Periodic {
+            kind,
+            convert,
+            interval: Alarm::new().interval(period)?,
+            reading: false,
+        })
+    }
+}
+
+impl<T> Stream for Periodic<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if !self.reading {
+            match Pin::new(&mut self.interval).poll_next(cx) {
+                Poll::Ready(_) => {
+                    if let Err(e) = start_reading(self.kind) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+
+                    self.reading = true;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        match poll_reading(self.kind, cx) {
+            Poll::Ready(r) => {
+                self.reading = false;
+                Poll::Ready(Some(Ok((self.convert)(r))))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// The alarm slot is released by the `Interval`
+impl<T> Drop for Periodic<T> {
+    fn drop(&mut self) {
+        if self.reading {
+            abandon_reading(self.kind);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarm;
+    use crate::syscalls::{self, host};
+    use core::task::Waker;
+    use std::iter;
+
+    // Each reading is 1000 more than the last, and arrives on the next yield
+    struct FakeSensor {
+        reading: usize,
+    }
+
+    impl host::Driver for FakeSensor {
+        fn command(&mut self, minor: usize, _arg1: usize, _arg2: usize) -> isize {
+            if minor == command_num::READ {
+                self.reading += 1000;
+                host::schedule(
+                    TEMPERATURE_DRIVER_NUM,
+                    subscribe_num::CALLBACK,
+                    self.reading,
+                    0,
+                    0,
+                );
+            }
+            0
+        }
+    }
+
+    // Ticks at 1 kHz, and the time is whatever the alarm upcall says
+    struct FakeAlarm;
+
+    impl host::Driver for FakeAlarm {
+        fn command(&mut self, minor: usize, _arg1: usize, _arg2: usize) -> isize {
+            match minor {
+                // Frequency
+                1 => 1000,
+                _ => 0,
+            }
+        }
+    }
+
+    fn free_alarm_slots() -> usize {
+        let alarm = Alarm::new();
+        let claimed: Vec<_> = iter::from_fn(|| alarm.sleep(Duration::from_secs(1)).ok()).collect();
+
+        claimed.len()
+    }
+
+    fn install_sensor() {
+        let sensor = FakeSensor { reading: 0 };
+        host::install(TEMPERATURE_DRIVER_NUM, Box::leak(Box::new(sensor)));
+    }
+
+    // The sensor stays busy until the dropped reading arrives, and that
+    // reading is not handed to the next one
+    #[test]
+    fn late_reading_of_dropped_reader_is_discarded() {
+        let _lock = host::lock();
+        install_sensor();
+
+        let temperature = Temperature::new();
+        drop(temperature.read().unwrap());
+        assert_eq!(temperature.read().err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+
+        let reader = temperature.read().unwrap();
+        assert_eq!(host::run(reader), CentiCelsius(2000));
+        assert_eq!(host::scheduled(), 0);
+    }
+
+    #[test]
+    fn late_reading_of_dropped_periodic_is_discarded() {
+        let _lock = host::lock();
+        install_sensor();
+        host::install(alarm::DRIVER_NUM, Box::leak(Box::new(FakeAlarm)));
+
+        let free = free_alarm_slots();
+        let mut periodic = Temperature::new()
+            .periodic(Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(free_alarm_slots(), free - 1);
+
+        // The alarm upcall at tick 10 fires the interval, which starts a reading
+        host::schedule(alarm::DRIVER_NUM, 0, 10, 0, 0);
+        syscalls::yieldk();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut periodic).poll_next(&mut cx).is_pending());
+
+        drop(periodic);
+        assert_eq!(free_alarm_slots(), free);
+        assert_eq!(Temperature::new().read().err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+
+        let reader = Temperature::new().read().unwrap();
+        assert_eq!(host::run(reader), CentiCelsius(2000));
+        assert_eq!(host::scheduled(), 0);
+    }
+}
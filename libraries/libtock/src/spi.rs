@@ -0,0 +1,349 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+
+use crate::cell::RacyCell;
+use crate::result::{Error, Result};
+use crate::syscalls::{allow, allow_readonly, command, subscribe, CallbackData};
+
+pub(crate) const DRIVER_NUM: usize = 0x20001;
+
+mod allow_num {
+    pub const READ: usize = 0;
+    pub const WRITE: usize = 1;
+}
+
+mod subscribe_num {
+    pub const CALLBACK: usize = 0;
+}
+
+mod command_num {
+    pub const READ_WRITE: usize = 2;
+    pub const SET_CHIP_SELECT: usize = 3;
+    pub const GET_CHIP_SELECT: usize = 4;
+    pub const SET_RATE: usize = 5;
+    pub const GET_RATE: usize = 6;
+    pub const SET_PHASE: usize = 7;
+    pub const GET_PHASE: usize = 8;
+    pub const SET_POLARITY: usize = 9;
+    pub const GET_POLARITY: usize = 10;
+}
+
+static SPI_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
+
+static SPI_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+extern "C" fn spi_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    // Completion of a transfer whose future was dropped
+    if SPI_STATE.get() == SpiState::Abandoned {
+        SPI_STATE.set(SpiState::Nothing);
+        return;
+    }
+
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    SPI_DATA.set(Some(cb_data));
+
+    SPI_WAKER.wake();
+}
+
+// Indicates if there is an ongoing transfer, and how many bytes are to be read
+// back once it completes. A second transfer while one is in flight is rejected
+// with `EBUSY`. A transfer whose future was dropped is `Abandoned` until the
+// capsule completes it, so that its completion is not taken for that of the
+// next transfer.
+#[derive(Copy, Clone, PartialEq)]
+enum SpiState {
+    Ongoing { read_len: usize },
+    Abandoned,
+    Nothing,
+}
+
+static SPI_STATE: RacyCell<SpiState> = RacyCell::new(SpiState::Nothing);
+
+// Correspond to the kernel transfer buffers. `tx` is copied in before the
+// transfer starts, and `rx` is copied out once it completes. The caller's
+// buffers are never allowed directly, as the kernel would keep writing to them
+// if the future were forgotten rather than dropped.
+static SPI_TX_BUF: RacyCell<[u8; 64]> = RacyCell::new([0; 64]);
+
+static SPI_RX_BUF: RacyCell<[u8; 64]> = RacyCell::new([0; 64]);
+
+pub struct Spi;
+
+impl Spi {
+    pub fn new() -> Spi {
+        Spi
+    }
+
+    pub fn set_chip_select(&self, cs: usize) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::SET_CHIP_SELECT, cs, 0).map(|_| ()) }
+    }
+
+    pub fn get_chip_select(&self) -> Result<usize> {
+        unsafe { command(DRIVER_NUM, command_num::GET_CHIP_SELECT, 0, 0) }
+    }
+
+    // Returns the rate actually chosen by the hardware, which may be lower
+    // than the one requested.
+    pub fn set_rate(&self, rate: usize) -> Result<usize> {
+        unsafe {
+            command(DRIVER_NUM, command_num::SET_RATE, rate, 0)
+                .and_then(|_| command(DRIVER_NUM, command_num::GET_RATE, 0, 0))
+        }
+    }
+
+    pub fn get_rate(&self) -> Result<usize> {
+        unsafe { command(DRIVER_NUM, command_num::GET_RATE, 0, 0) }
+    }
+
+    pub fn set_phase(&self, phase: Phase) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::SET_PHASE, phase as usize, 0).map(|_| ()) }
+    }
+
+    pub fn get_phase(&self) -> Result<Phase> {
+        unsafe {
+            command(DRIVER_NUM, command_num::GET_PHASE, 0, 0).map(|r| {
+                if r == 0 {
+                    Phase::SampleLeading
+                } else {
+                    Phase::SampleTrailing
+                }
+            })
+        }
+    }
+
+    pub fn set_polarity(&self, polarity: Polarity) -> Result<()> {
+        unsafe { command(DRIVER_NUM, command_num::SET_POLARITY, polarity as usize, 0).map(|_| ()) }
+    }
+
+    pub fn get_polarity(&self) -> Result<Polarity> {
+        unsafe {
+            command(DRIVER_NUM, command_num::GET_POLARITY, 0, 0).map(|r| {
+                if r == 0 {
+                    Polarity::IdleLow
+                } else {
+                    Polarity::IdleHigh
+                }
+            })
+        }
+    }
+
+    // Full-duplex transfer. `rx` must be at least as long as `tx`; `tx.len()`
+    // bytes are clocked out and the same number read back into `rx`.
+    pub fn transfer<'a>(
+        &self,
+        tx: &'a [u8],
+        rx: &'a mut [u8],
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        if rx.len() < tx.len() {
+            return Err(Error::EINVAL);
+        }
+
+        self.start(tx, true)?;
+
+        Ok(SpiTransfer { rx })
+    }
+
+    pub fn write(&self, tx: &[u8]) -> Result<impl Future<Output = Result<()>>> {
+        self.start(tx, false)?;
+
+        Ok(SpiTransfer { rx: &mut [] })
+    }
+
+    fn start(&self, tx: &[u8], read: bool) -> Result<()> {
+        if SPI_STATE.get() != SpiState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        if tx.is_empty() {
+            return Err(Error::EINVAL);
+        }
+
+        SPI_TX_BUF.with(|buf| {
+            if tx.len() > buf.len() {
+                return Err(Error::ESIZE);
+            }
+
+            buf[..tx.len()].copy_from_slice(tx);
+
+            Ok(())
+        })?;
+
+        SPI_DATA.set(None);
+
+        unsafe {
+            let _ = allow_readonly(
+                DRIVER_NUM,
+                allow_num::WRITE,
+                SPI_TX_BUF.as_ptr() as *const u8,
+                tx.len(),
+            )
+            .and_then(|_| {
+                if read {
+                    allow(
+                        DRIVER_NUM,
+                        allow_num::READ,
+                        SPI_RX_BUF.as_ptr() as *mut u8,
+                        tx.len(),
+                    )
+                } else {
+                    allow(DRIVER_NUM, allow_num::READ, ptr::null_mut(), 0)
+                }
+            })
+            .and_then(|_| {
+                subscribe(
+                    DRIVER_NUM,
+                    subscribe_num::CALLBACK,
+                    spi_callback as *const _,
+                    0,
+                )
+            })
+            .and_then(|_| command(DRIVER_NUM, command_num::READ_WRITE, tx.len(), 0))
+            .inspect_err(|_| {
+                unallow_buffers();
+            })?;
+        }
+
+        let read_len = if read { tx.len() } else { 0 };
+        SPI_STATE.set(SpiState::Ongoing { read_len });
+
+        Ok(())
+    }
+}
+
+// Take the transfer buffers back from the kernel
+fn unallow_buffers() {
+    unsafe {
+        let _ = allow_readonly(DRIVER_NUM, allow_num::WRITE, ptr::null(), 0);
+        let _ = allow(DRIVER_NUM, allow_num::READ, ptr::null_mut(), 0);
+    }
+}
+
+// Future returned by Spi::transfer and Spi::write. It borrows the caller's
+// `rx`, to copy the bytes read into once the transfer completes.
+struct SpiTransfer<'a> {
+    rx: &'a mut [u8],
+}
+
+impl<'a> Future for SpiTransfer<'a> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if SPI_DATA.take().is_some() {
+            let read_len = match SPI_STATE.get() {
+                SpiState::Ongoing { read_len } => read_len,
+                // Polled again after it completed
+                SpiState::Abandoned | SpiState::Nothing => return Poll::Ready(Err(Error::FAIL)),
+            };
+
+            unallow_buffers();
+            SPI_STATE.set(SpiState::Nothing);
+
+            SPI_RX_BUF.with(|buf| self.rx[..read_len].copy_from_slice(&buf[..read_len]));
+
+            Poll::Ready(Ok(()))
+        } else {
+            SPI_WAKER.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+// Dropped before it was resolved. The buffers are taken back, and the next
+// transfer can start once the capsule has completed this one.
+impl<'a> Drop for SpiTransfer<'a> {
+    fn drop(&mut self) {
+        if let SpiState::Ongoing { .. } = SPI_STATE.get() {
+            unallow_buffers();
+
+            if SPI_DATA.take().is_some() {
+                SPI_STATE.set(SpiState::Nothing);
+            } else {
+                SPI_STATE.set(SpiState::Abandoned);
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Phase {
+    SampleLeading = 0,
+    SampleTrailing = 1,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Polarity {
+    IdleLow = 0,
+    IdleHigh = 1,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{self, host};
+
+    // Clocks back each byte written plus `offset`, completing on the next
+    // yield. `offset` goes up by one with every transfer.
+    struct FakeSpi {
+        offset: u8,
+    }
+
+    impl host::Driver for FakeSpi {
+        fn command(&mut self, minor: usize, len: usize, _arg2: usize) -> isize {
+            if minor == command_num::READ_WRITE {
+                let tx = unsafe { host::readonly_buffer(DRIVER_NUM, allow_num::WRITE) }.unwrap();
+
+                if let Some(rx) = unsafe { host::buffer(DRIVER_NUM, allow_num::READ) } {
+                    for (r, t) in rx.iter_mut().zip(tx.iter()).take(len) {
+                        *r = t.wrapping_add(self.offset);
+                    }
+                }
+
+                self.offset += 1;
+                host::schedule(DRIVER_NUM, subscribe_num::CALLBACK, len, 0, 0);
+            }
+            0
+        }
+    }
+
+    // The kernel only ever sees the driver's own buffers, so forgetting the
+    // future cannot leave it writing into the caller's memory
+    #[test]
+    fn transfer_goes_through_driver_buffers() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeSpi { offset: 0 })));
+
+        let spi = Spi::new();
+        let mut rx = [0; 3];
+        let transfer = spi.transfer(&[1, 2, 3], &mut rx).unwrap();
+
+        let allowed = unsafe { host::buffer(DRIVER_NUM, allow_num::READ) }.unwrap();
+        assert_eq!(allowed.as_ptr(), SPI_RX_BUF.as_ptr() as *const u8);
+
+        assert_eq!(host::run(transfer), Ok(()));
+        assert_eq!(rx, [1, 2, 3]);
+    }
+
+    // The completion of the dropped transfer must not resolve the next one
+    // before its own bytes have been read
+    #[test]
+    fn late_completion_of_dropped_transfer_is_discarded() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeSpi { offset: 0 })));
+
+        let spi = Spi::new();
+        let mut rx = [0; 2];
+        drop(spi.transfer(&[1, 2], &mut rx).unwrap());
+
+        assert_eq!(spi.write(&[3]).err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+
+        let transfer = spi.transfer(&[5, 6], &mut rx).unwrap();
+        assert_eq!(host::run(transfer), Ok(()));
+        assert_eq!(rx, [6, 7]);
+        assert_eq!(host::scheduled(), 0);
+    }
+}
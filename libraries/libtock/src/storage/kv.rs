@@ -0,0 +1,610 @@
+//! A small log-structured key-value store.
+//!
+//! The backend is split into two regions of equal size. One of them is active
+//! and holds an append-only log of records; the other is the target of the
+//! next compaction. A region starts with a header:
+//!
+//! ```text
+//! magic: u32 | generation: u32 | crc: u32
+//! ```
+//!
+//! and is followed by records:
+//!
+//! ```text
+//! key_len: u8 | flags: u8 | value_len: u16 | crc: u32 | key | value
+//! ```
+//!
+//! All integers are little endian. A record's CRC covers the region generation,
+//! the first four header bytes, the key and the value. Scanning stops at the
+//! first record that does not check out, which is also where the next record
+//! is appended. So a record torn by a power loss is simply never seen, and
+//! records left over from an older use of the region fail the check because
+//! their generation differs.
+//!
+//! Compaction erases the other region, copies the latest record of every live
+//! key into it and only then writes its header with the next generation. Until
+//! the header is written, mounting still picks the old region. An interrupted
+//! compaction leaves records of the next generation behind, which the erase
+//! keeps from being picked up by the one that eventually completes.
+
+use heapless::{consts, ArrayLength, String, Vec};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::Backend;
+use crate::crypto::crc::{self, Algorithm};
+use crate::result::{Error, Result};
+
+pub type MaxKeyLen = consts::U32;
+
+pub type Key = String<MaxKeyLen>;
+
+pub const MAX_KEY_LEN: usize = 32;
+
+pub const MAX_VALUE_LEN: usize = 128;
+
+const MAGIC: u32 = 0x3156_4b54; // "TKV1"
+
+const REGION_HEADER_LEN: usize = 12;
+
+const RECORD_HEADER_LEN: usize = 8;
+
+const RECORD_BUF_LEN: usize = RECORD_HEADER_LEN + MAX_KEY_LEN + MAX_VALUE_LEN;
+
+mod flags {
+    pub const TOMBSTONE: u8 = 0x01;
+}
+
+#[derive(Copy, Clone)]
+struct RecordHeader {
+    key_len: usize,
+    value_len: usize,
+    tombstone: bool,
+}
+
+impl RecordHeader {
+    fn len(&self) -> usize {
+        RECORD_HEADER_LEN + self.key_len + self.value_len
+    }
+}
+
+// A full record, header included, as read from or written to the backend
+struct RecordBuf {
+    buf: [u8; RECORD_BUF_LEN],
+}
+
+impl RecordBuf {
+    fn new() -> RecordBuf {
+        RecordBuf {
+            buf: [0; RECORD_BUF_LEN],
+        }
+    }
+
+    fn key<'a>(&'a self, header: &RecordHeader) -> &'a [u8] {
+        &self.buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + header.key_len]
+    }
+
+    fn value<'a>(&'a self, header: &RecordHeader) -> &'a [u8] {
+        let start = RECORD_HEADER_LEN + header.key_len;
+        &self.buf[start..start + header.value_len]
+    }
+
+    // Fill in the header and the CRC, assuming the key and value are already
+    // in place.
+    fn seal(&mut self, generation: u32, header: &RecordHeader) {
+        self.buf[0] = header.key_len as u8;
+        self.buf[1] = if header.tombstone {
+            flags::TOMBSTONE
+        } else {
+            0
+        };
+        self.buf[2..4].copy_from_slice(&(header.value_len as u16).to_le_bytes());
+
+        let crc = record_crc(generation, &self.buf[..header.len()]);
+        self.buf[4..8].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+fn record_crc(generation: u32, record: &[u8]) -> u32 {
+    let crc = crc32(!0, &generation.to_le_bytes());
+    let crc = crc32(crc, &record[..4]);
+    !crc32(crc, &record[RECORD_HEADER_LEN..])
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    crc::update_raw(Algorithm::Crc32, crc, data)
+}
+
+// `a` is newer than `b`, allowing for the generation counter to wrap
+fn is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+pub struct KvStore<B: Backend> {
+    backend: B,
+    region_len: usize,
+    active: usize,
+    generation: u32,
+    // Offset in the active region where the next record goes
+    end: usize,
+}
+
+impl<B: Backend> KvStore<B> {
+    /// Open the store on `backend`, formatting it if neither region holds a
+    /// valid header.
+    pub fn mount(backend: B) -> Result<KvStore<B>> {
+        let region_len = backend.size()? / 2;
+
+        if region_len < REGION_HEADER_LEN + RECORD_BUF_LEN {
+            return Err(Error::ESIZE);
+        }
+
+        let mut store = KvStore {
+            backend,
+            region_len,
+            active: 0,
+            generation: 0,
+            end: REGION_HEADER_LEN,
+        };
+
+        let found = match (store.read_region_header(0)?, store.read_region_header(1)?) {
+            (Some(g0), Some(g1)) if is_newer(g1, g0) => Some((1, g1)),
+            (Some(g0), _) => Some((0, g0)),
+            (None, Some(g1)) => Some((1, g1)),
+            (None, None) => None,
+        };
+
+        match found {
+            Some((active, generation)) => {
+                store.active = active;
+                store.generation = generation;
+                store.end = store.find_end()?;
+            }
+            None => store.format()?,
+        }
+
+        Ok(store)
+    }
+
+    /// Erase every key.
+    pub fn format(&mut self) -> Result<()> {
+        let target = 1 - self.active;
+        let generation = self.generation.wrapping_add(1);
+
+        self.erase_region(target)?;
+        self.write_region_header(target, generation)?;
+
+        self.active = target;
+        self.generation = generation;
+        self.end = REGION_HEADER_LEN;
+
+        Ok(())
+    }
+
+    pub fn get<V: DeserializeOwned>(&mut self, key: &str) -> Result<Option<V>> {
+        let mut record = RecordBuf::new();
+
+        match self.find_latest(key.as_bytes(), &mut record)? {
+            Some((_, header)) if !header.tombstone => postcard::from_bytes(record.value(&header))
+                .map(Some)
+                .map_err(|_| Error::FAIL),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn set<V: Serialize>(&mut self, key: &str, value: &V) -> Result<()> {
+        let mut record = RecordBuf::new();
+
+        let key_len = self.put_key(&mut record, key)?;
+        let value_len = postcard::to_slice(
+            value,
+            &mut record.buf
+                [RECORD_HEADER_LEN + key_len..RECORD_HEADER_LEN + key_len + MAX_VALUE_LEN],
+        )
+        .map_err(|_| Error::ESIZE)?
+        .len();
+
+        self.append(
+            &mut record,
+            RecordHeader {
+                key_len,
+                value_len,
+                tombstone: false,
+            },
+        )
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        let mut record = RecordBuf::new();
+
+        match self.find_latest(key.as_bytes(), &mut record)? {
+            Some((_, header)) if !header.tombstone => (),
+            _ => return Ok(()),
+        }
+
+        let key_len = self.put_key(&mut record, key)?;
+
+        self.append(
+            &mut record,
+            RecordHeader {
+                key_len,
+                value_len: 0,
+                tombstone: true,
+            },
+        )
+    }
+
+    pub fn contains_key(&mut self, key: &str) -> Result<bool> {
+        let mut record = RecordBuf::new();
+
+        Ok(match self.find_latest(key.as_bytes(), &mut record)? {
+            Some((_, header)) => !header.tombstone,
+            None => false,
+        })
+    }
+
+    /// Collect the live keys. Fails with `ENOMEM` when there are more than
+    /// `N`.
+    pub fn keys<N: ArrayLength<Key>>(&mut self) -> Result<Vec<Key, N>> {
+        let mut keys = Vec::new();
+        let mut record = RecordBuf::new();
+        let mut latest = RecordBuf::new();
+        let mut offset = REGION_HEADER_LEN;
+
+        while let Some(header) = self.read_record(offset, &mut record)? {
+            if self.is_live(offset, &header, &record, &mut latest)? {
+                let key = core::str::from_utf8(record.key(&header)).map_err(|_| Error::FAIL)?;
+
+                let mut k = Key::new();
+                k.push_str(key).map_err(|_| Error::FAIL)?;
+                keys.push(k).map_err(|_| Error::ENOMEM)?;
+            }
+
+            offset += header.len();
+        }
+
+        Ok(keys)
+    }
+
+    /// Rewrite the live records into the other region, dropping overwritten
+    /// values and tombstones. This runs on its own when an append does not
+    /// fit, but can be called ahead of time to keep writes predictable.
+    pub fn compact(&mut self) -> Result<()> {
+        let target = 1 - self.active;
+        let generation = self.generation.wrapping_add(1);
+
+        let mut record = RecordBuf::new();
+        let mut latest = RecordBuf::new();
+        let mut offset = REGION_HEADER_LEN;
+        let mut target_end = REGION_HEADER_LEN;
+
+        self.erase_region(target)?;
+
+        // Quadratic in the number of records, in exchange for not needing any
+        // memory beyond two record buffers.
+        while let Some(header) = self.read_record(offset, &mut record)? {
+            if self.is_live(offset, &header, &record, &mut latest)? {
+                record.seal(generation, &header);
+
+                let base = target * self.region_len;
+                self.backend
+                    .write(base + target_end, &record.buf[..header.len()])?;
+
+                target_end += header.len();
+            }
+
+            offset += header.len();
+        }
+
+        // Commit point
+        self.write_region_header(target, generation)?;
+
+        self.active = target;
+        self.generation = generation;
+        self.end = target_end;
+
+        Ok(())
+    }
+
+    fn put_key(&self, record: &mut RecordBuf, key: &str) -> Result<usize> {
+        let key = key.as_bytes();
+
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Err(Error::EINVAL);
+        }
+
+        record.buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + key.len()].copy_from_slice(key);
+
+        Ok(key.len())
+    }
+
+    fn append(&mut self, record: &mut RecordBuf, header: RecordHeader) -> Result<()> {
+        if self.end + header.len() > self.region_len {
+            self.compact()?;
+
+            if self.end + header.len() > self.region_len {
+                return Err(Error::ENOMEM);
+            }
+        }
+
+        record.seal(self.generation, &header);
+
+        let base = self.active * self.region_len;
+        self.backend
+            .write(base + self.end, &record.buf[..header.len()])?;
+
+        self.end += header.len();
+
+        Ok(())
+    }
+
+    // Whether the record at `offset` is the latest one for its key and is not
+    // a tombstone.
+    fn is_live(
+        &mut self,
+        offset: usize,
+        header: &RecordHeader,
+        record: &RecordBuf,
+        latest: &mut RecordBuf,
+    ) -> Result<bool> {
+        if header.tombstone {
+            return Ok(false);
+        }
+
+        Ok(match self.find_latest(record.key(header), latest)? {
+            Some((latest_offset, _)) => latest_offset == offset,
+            None => false,
+        })
+    }
+
+    // Leaves the latest record for `key` in `record`
+    fn find_latest(
+        &mut self,
+        key: &[u8],
+        record: &mut RecordBuf,
+    ) -> Result<Option<(usize, RecordHeader)>> {
+        let mut found = None;
+        let mut offset = REGION_HEADER_LEN;
+
+        while let Some(header) = self.read_record(offset, record)? {
+            if record.key(&header) == key {
+                found = Some((offset, header));
+            }
+
+            offset += header.len();
+        }
+
+        // The scan went past the match, so read it back in
+        if let Some((offset, _)) = found {
+            self.read_record(offset, record)?;
+        }
+
+        Ok(found)
+    }
+
+    fn find_end(&mut self) -> Result<usize> {
+        let mut record = RecordBuf::new();
+        let mut offset = REGION_HEADER_LEN;
+
+        while let Some(header) = self.read_record(offset, &mut record)? {
+            offset += header.len();
+        }
+
+        Ok(offset)
+    }
+
+    // Read the record at `offset` in the active region. Returns `None` at the
+    // end of the log, that is when the record is torn, stale or missing.
+    fn read_record(
+        &mut self,
+        offset: usize,
+        record: &mut RecordBuf,
+    ) -> Result<Option<RecordHeader>> {
+        if offset + RECORD_HEADER_LEN > self.region_len {
+            return Ok(None);
+        }
+
+        let base = self.active * self.region_len;
+        self.backend
+            .read(base + offset, &mut record.buf[..RECORD_HEADER_LEN])?;
+
+        let header = RecordHeader {
+            key_len: record.buf[0] as usize,
+            value_len: u16::from_le_bytes([record.buf[2], record.buf[3]]) as usize,
+            tombstone: record.buf[1] & flags::TOMBSTONE != 0,
+        };
+
+        if header.key_len == 0
+            || header.key_len > MAX_KEY_LEN
+            || header.value_len > MAX_VALUE_LEN
+            || offset + header.len() > self.region_len
+        {
+            return Ok(None);
+        }
+
+        self.backend.read(
+            base + offset + RECORD_HEADER_LEN,
+            &mut record.buf[RECORD_HEADER_LEN..header.len()],
+        )?;
+
+        if read_u32(&record.buf[4..8]) != record_crc(self.generation, &record.buf[..header.len()]) {
+            return Ok(None);
+        }
+
+        Ok(Some(header))
+    }
+
+    // Zero a whole region, header included. A zeroed record header ends the
+    // log, as its key is empty.
+    fn erase_region(&mut self, region: usize) -> Result<()> {
+        let zeros = [0; RECORD_BUF_LEN];
+        let base = region * self.region_len;
+        let mut offset = 0;
+
+        while offset < self.region_len {
+            let len = core::cmp::min(zeros.len(), self.region_len - offset);
+            self.backend.write(base + offset, &zeros[..len])?;
+
+            offset += len;
+        }
+
+        Ok(())
+    }
+
+    fn read_region_header(&mut self, region: usize) -> Result<Option<u32>> {
+        let mut buf = [0; REGION_HEADER_LEN];
+        self.backend.read(region * self.region_len, &mut buf)?;
+
+        let generation = read_u32(&buf[4..8]);
+
+        if read_u32(&buf[0..4]) != MAGIC || read_u32(&buf[8..12]) != !crc32(!0, &buf[..8]) {
+            return Ok(None);
+        }
+
+        Ok(Some(generation))
+    }
+
+    fn write_region_header(&mut self, region: usize, generation: u32) -> Result<()> {
+        let mut buf = [0; REGION_HEADER_LEN];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&generation.to_le_bytes());
+
+        let crc = !crc32(!0, &buf[..8]);
+        buf[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        self.backend.write(region * self.region_len, &buf)
+    }
+
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RamStorage;
+
+    const STORAGE_LEN: usize = 512;
+
+    // Fails the write of `len` bytes at `offset` given by `fail_at`, as a
+    // power loss would cut it off
+    struct Flaky<'a> {
+        ram: RamStorage<'a>,
+        fail_at: Option<(usize, usize)>,
+    }
+
+    impl<'a> Backend for Flaky<'a> {
+        fn size(&self) -> Result<usize> {
+            self.ram.size()
+        }
+
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+            self.ram.read(offset, buf)
+        }
+
+        fn write(&mut self, offset: usize, buf: &[u8]) -> Result<()> {
+            if self.fail_at == Some((offset, buf.len())) {
+                return Err(Error::FAIL);
+            }
+
+            self.ram.write(offset, buf)
+        }
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut mem = [0; STORAGE_LEN];
+        let mut store = KvStore::mount(RamStorage::new(&mut mem)).unwrap();
+
+        store.set("a", &1u32).unwrap();
+        store.set("b", &2u32).unwrap();
+        store.set("a", &3u32).unwrap();
+        store.remove("b").unwrap();
+
+        assert_eq!(store.get::<u32>("a"), Ok(Some(3)));
+        assert_eq!(store.get::<u32>("b"), Ok(None));
+        assert_eq!(store.contains_key("b"), Ok(false));
+
+        let mut store = KvStore::mount(store.into_backend()).unwrap();
+        assert_eq!(store.get::<u32>("a"), Ok(Some(3)));
+        assert_eq!(store.keys::<consts::U4>().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn compaction_keeps_live_keys() {
+        let mut mem = [0; STORAGE_LEN];
+        let mut store = KvStore::mount(RamStorage::new(&mut mem)).unwrap();
+
+        // Many times over what a region holds
+        for i in 0..100u32 {
+            store.set("counter", &i).unwrap();
+            store.set("other", &(i * 2)).unwrap();
+        }
+        store.remove("other").unwrap();
+        store.compact().unwrap();
+
+        let mut store = KvStore::mount(store.into_backend()).unwrap();
+        assert_eq!(store.get::<u32>("counter"), Ok(Some(99)));
+        assert_eq!(store.get::<u32>("other"), Ok(None));
+    }
+
+    #[test]
+    fn compaction_commits_to_the_other_region() {
+        let mut mem = [0; STORAGE_LEN];
+        let mut store = KvStore::mount(RamStorage::new(&mut mem)).unwrap();
+        let (active, generation) = (store.active, store.generation);
+
+        store.set("a", &1u32).unwrap();
+        store.compact().unwrap();
+
+        let mut store = KvStore::mount(store.into_backend()).unwrap();
+        assert_eq!(store.active, 1 - active);
+        assert_eq!(store.generation, generation.wrapping_add(1));
+        assert_eq!(store.get::<u32>("a"), Ok(Some(1)));
+    }
+
+    #[test]
+    fn interrupted_compaction_leaves_nothing_behind() {
+        let mut mem = [0; STORAGE_LEN];
+        let region_len = STORAGE_LEN / 2;
+        let ram = RamStorage::new(&mut mem);
+
+        let mut store = KvStore::mount(Flaky { ram, fail_at: None }).unwrap();
+        let (active, generation) = (store.active, store.generation);
+
+        store.set("a", &1u32).unwrap();
+        store.set("b", &2u32).unwrap();
+
+        // Cut off at the commit point, after the records were copied
+        let target_header = (1 - active) * region_len;
+        store.backend.fail_at = Some((target_header, REGION_HEADER_LEN));
+        assert_eq!(store.compact(), Err(Error::FAIL));
+
+        let mut backend = store.into_backend();
+        backend.fail_at = None;
+
+        // The old generation is still the one in use
+        let mut store = KvStore::mount(backend).unwrap();
+        assert_eq!(store.active, active);
+        assert_eq!(store.generation, generation);
+        assert_eq!(store.get::<u32>("b"), Ok(Some(2)));
+
+        // The copy of `b` from the interrupted compaction carries the same
+        // generation as this one, and lies past the end of what it writes
+        store.remove("b").unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.get::<u32>("b"), Ok(None));
+
+        let mut store = KvStore::mount(store.into_backend()).unwrap();
+        assert_eq!(store.active, 1 - active);
+        assert_eq!(store.get::<u32>("a"), Ok(Some(1)));
+        assert_eq!(store.get::<u32>("b"), Ok(None));
+    }
+}
@@ -0,0 +1,319 @@
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+
+use crate::cell::RacyCell;
+use crate::futures::block_on;
+use crate::result::{Error, Result};
+use crate::syscalls::{allow, command, subscribe, CallbackData};
+
+pub mod kv;
+
+pub(crate) const DRIVER_NUM: usize = 0x50001;
+
+mod allow_num {
+    pub const READ: usize = 0;
+    pub const WRITE: usize = 1;
+}
+
+mod subscribe_num {
+    pub const READ_DONE: usize = 0;
+    pub const WRITE_DONE: usize = 1;
+}
+
+mod command_num {
+    pub const GET_SIZE: usize = 1;
+    pub const READ: usize = 2;
+    pub const WRITE: usize = 3;
+}
+
+static STORAGE_DATA: RacyCell<Option<CallbackData>> = RacyCell::new(None);
+
+static STORAGE_WAKER: RacyCell<Option<Waker>> = RacyCell::new(None);
+
+// Both the read and the write completion land here. Only one operation can be
+// in flight, so there is no need to tell them apart.
+extern "C" fn storage_callback(arg0: usize, arg1: usize, arg2: usize, userdata: usize) {
+    // Completion of an operation whose future was dropped
+    if STORAGE_STATE.get() == StorageState::Abandoned {
+        STORAGE_STATE.set(StorageState::Nothing);
+        return;
+    }
+
+    let cb_data = CallbackData::new(arg0, arg1, arg2, userdata);
+
+    STORAGE_DATA.set(Some(cb_data));
+
+    STORAGE_WAKER.wake();
+}
+
+// Indicates if there is an ongoing read or write, and which allow slot holds
+// the caller's buffer. A second operation while one is in flight is rejected
+// with `EBUSY`. An operation whose future was dropped is `Abandoned` until the
+// capsule completes it, so that its completion is not taken for that of the
+// next operation.
+#[derive(Copy, Clone, PartialEq)]
+enum StorageState {
+    Ongoing { allow_slot: usize },
+    Abandoned,
+    Nothing,
+}
+
+static STORAGE_STATE: RacyCell<StorageState> = RacyCell::new(StorageState::Nothing);
+
+/// Wraps the nonvolatile storage capsule. Offsets are relative to the region
+/// the kernel set aside for this app.
+pub struct Storage;
+
+impl Storage {
+    pub fn new() -> Storage {
+        Storage
+    }
+
+    pub fn size(&self) -> Result<usize> {
+        unsafe { command(DRIVER_NUM, command_num::GET_SIZE, 0, 0) }
+    }
+
+    pub fn read<'a>(
+        &self,
+        offset: usize,
+        buf: &'a mut [u8],
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        unsafe {
+            self.start(
+                allow_num::READ,
+                subscribe_num::READ_DONE,
+                command_num::READ,
+                offset,
+                buf.as_mut_ptr(),
+                buf.len(),
+            )?;
+        }
+
+        Ok(StorageOperation {
+            _buffer: PhantomData,
+        })
+    }
+
+    pub fn write<'a>(
+        &self,
+        offset: usize,
+        buf: &'a [u8],
+    ) -> Result<impl Future<Output = Result<()>> + 'a> {
+        // The capsule only reads from the write buffer, but under Tock 1.x
+        // there is no read-only allow.
+        unsafe {
+            self.start(
+                allow_num::WRITE,
+                subscribe_num::WRITE_DONE,
+                command_num::WRITE,
+                offset,
+                buf.as_ptr() as *mut u8,
+                buf.len(),
+            )?;
+        }
+
+        Ok(StorageOperation {
+            _buffer: PhantomData,
+        })
+    }
+
+    unsafe fn start(
+        &self,
+        allow_slot: usize,
+        subscribe_slot: usize,
+        cmd: usize,
+        offset: usize,
+        ptr: *mut u8,
+        len: usize,
+    ) -> Result<()> {
+        if STORAGE_STATE.get() != StorageState::Nothing {
+            return Err(Error::EBUSY);
+        }
+
+        if len == 0 {
+            return Err(Error::EINVAL);
+        }
+
+        STORAGE_DATA.set(None);
+
+        let _ = allow(DRIVER_NUM, allow_slot, ptr, len)
+            .and_then(|_| subscribe(DRIVER_NUM, subscribe_slot, storage_callback as *const _, 0))
+            .and_then(|_| command(DRIVER_NUM, cmd, len, offset))
+            .inspect_err(|_| {
+                let _ = allow(DRIVER_NUM, allow_slot, ptr::null_mut(), 0);
+            })?;
+
+        STORAGE_STATE.set(StorageState::Ongoing { allow_slot });
+
+        Ok(())
+    }
+}
+
+// Take the caller's buffer back from the kernel. `done` tells whether the
+// capsule has completed the operation; if not, the driver stays busy until it
+// does.
+fn finish(done: bool) {
+    if let StorageState::Ongoing { allow_slot } = STORAGE_STATE.get() {
+        unsafe {
+            let _ = allow(DRIVER_NUM, allow_slot, ptr::null_mut(), 0);
+        }
+
+        if done {
+            STORAGE_STATE.set(StorageState::Nothing);
+        } else {
+            STORAGE_STATE.set(StorageState::Abandoned);
+        }
+    }
+}
+
+// Future returned by Storage::read and Storage::write. It borrows the caller's
+// buffer for as long as the kernel may access it.
+struct StorageOperation<'a> {
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> Future for StorageOperation<'a> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if STORAGE_DATA.take().is_some() {
+            finish(true);
+            Poll::Ready(Ok(()))
+        } else {
+            STORAGE_WAKER.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+// Does nothing once the future has resolved, as the state is then `Nothing`
+impl<'a> Drop for StorageOperation<'a> {
+    fn drop(&mut self) {
+        finish(STORAGE_DATA.take().is_some());
+    }
+}
+
+/// Byte-addressable storage that `kv::KvStore` can be built on. The blocking
+/// shape keeps the store independent of the executor, so it can run against
+/// `RamStorage` on the host.
+pub trait Backend {
+    fn size(&self) -> Result<usize>;
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<()>;
+
+    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<()>;
+}
+
+// Spins on `yieldk` until each operation completes
+impl Backend for Storage {
+    fn size(&self) -> Result<usize> {
+        Storage::size(self)
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        block_on(Storage::read(self, offset, buf)?)
+    }
+
+    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<()> {
+        block_on(Storage::write(self, offset, buf)?)
+    }
+}
+
+/// RAM-backed stand-in for `Storage`.
+pub struct RamStorage<'a> {
+    mem: &'a mut [u8],
+}
+
+impl<'a> RamStorage<'a> {
+    pub fn new(mem: &'a mut [u8]) -> RamStorage<'a> {
+        RamStorage { mem }
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.mem.len() => Ok(()),
+            _ => Err(Error::EINVAL),
+        }
+    }
+}
+
+impl<'a> Backend for RamStorage<'a> {
+    fn size(&self) -> Result<usize> {
+        Ok(self.mem.len())
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.check_range(offset, buf.len())?;
+        buf.copy_from_slice(&self.mem[offset..offset + buf.len()]);
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<()> {
+        self.check_range(offset, buf.len())?;
+        self.mem[offset..offset + buf.len()].copy_from_slice(buf);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{self, host};
+
+    const FLASH_LEN: usize = 16;
+
+    // Flash that only changes once the operation completes, on the next yield
+    struct FakeFlash {
+        mem: [u8; FLASH_LEN],
+    }
+
+    impl host::Driver for FakeFlash {
+        fn command(&mut self, minor: usize, len: usize, offset: usize) -> isize {
+            let range = offset..offset + len;
+
+            match minor {
+                command_num::GET_SIZE => return FLASH_LEN as isize,
+                command_num::READ => {
+                    let buf = unsafe { host::buffer(DRIVER_NUM, allow_num::READ) }.unwrap();
+                    buf.copy_from_slice(&self.mem[range]);
+                    host::schedule(DRIVER_NUM, subscribe_num::READ_DONE, 0, 0, 0);
+                }
+                command_num::WRITE => {
+                    let buf = unsafe { host::buffer(DRIVER_NUM, allow_num::WRITE) }.unwrap();
+                    self.mem[range].copy_from_slice(buf);
+                    host::schedule(DRIVER_NUM, subscribe_num::WRITE_DONE, 0, 0, 0);
+                }
+                _ => {}
+            }
+            0
+        }
+    }
+
+    // The dropped write's completion must not resolve the read after it
+    #[test]
+    fn late_completion_of_dropped_operation_is_discarded() {
+        let _lock = host::lock();
+        let flash = FakeFlash {
+            mem: [0xFF; FLASH_LEN],
+        };
+        host::install(DRIVER_NUM, Box::leak(Box::new(flash)));
+
+        let storage = Storage::new();
+        drop(storage.write(0, &[1, 2]).unwrap());
+
+        let mut buf = [0; 2];
+        assert_eq!(storage.read(0, &mut buf).err(), Some(Error::EBUSY));
+
+        syscalls::yieldk();
+
+        let read = storage.read(0, &mut buf).unwrap();
+        assert_eq!(host::run(read), Ok(()));
+        assert_eq!(buf, [1, 2]);
+        assert_eq!(host::scheduled(), 0);
+    }
+}
@@ -0,0 +1,334 @@
+// Host syscall backend, so that the drivers can be run by `cargo test`. There
+// is no kernel: `command` goes to a fake driver installed with `install`, and
+// the upcalls it schedules run on a later yield, one per yield as on a device.
+// A yield with nothing scheduled would hang the app, so it panics instead.
+//
+// The kernel state is shared by the whole process. Tests take `lock()`, which
+// also starts them off with no drivers installed.
+
+use core::mem;
+
+use crate::cell::RacyCell;
+use crate::result::Error;
+
+/// A fake driver. `command` returns what the syscall would, and may schedule
+/// upcalls or look at the buffers allowed to the driver.
+pub trait Driver {
+    fn command(&mut self, minor: usize, arg1: usize, arg2: usize) -> isize;
+}
+
+const MAX_DRIVERS: usize = 8;
+const MAX_SLOTS: usize = 32;
+
+// An upcall or a buffer shared with a driver
+#[derive(Clone, Copy)]
+struct Slot {
+    major: usize,
+    minor: usize,
+    readonly: bool,
+    values: (usize, usize),
+}
+
+#[derive(Clone, Copy)]
+struct Scheduled {
+    major: usize,
+    minor: usize,
+    args: [usize; 3],
+}
+
+struct Kernel {
+    drivers: [Option<(usize, *mut dyn Driver)>; MAX_DRIVERS],
+    upcalls: [Option<Slot>; MAX_SLOTS],
+    buffers: [Option<Slot>; MAX_SLOTS],
+    scheduled: [Option<Scheduled>; MAX_SLOTS],
+}
+
+static KERNEL: RacyCell<Kernel> = RacyCell::new(Kernel {
+    drivers: [None; MAX_DRIVERS],
+    upcalls: [None; MAX_SLOTS],
+    buffers: [None; MAX_SLOTS],
+    scheduled: [None; MAX_SLOTS],
+});
+
+// Replace the slot for `major`/`minor`, returning the values it held before
+fn swap(
+    slots: &mut [Option<Slot>],
+    major: usize,
+    minor: usize,
+    readonly: bool,
+    values: (usize, usize),
+) -> (usize, usize) {
+    let new = Slot {
+        major,
+        minor,
+        readonly,
+        values,
+    };
+
+    for s in slots.iter_mut().flatten() {
+        if s.major == major && s.minor == minor && s.readonly == readonly {
+            return mem::replace(s, new).values;
+        }
+    }
+
+    let free = slots
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("host kernel out of slots");
+    *free = Some(new);
+
+    (0, 0)
+}
+
+fn find(slots: &[Option<Slot>], major: usize, minor: usize, readonly: bool) -> Option<Slot> {
+    slots
+        .iter()
+        .flatten()
+        .find(|s| s.major == major && s.minor == minor && s.readonly == readonly)
+        .copied()
+}
+
+fn is_installed(major: usize) -> bool {
+    KERNEL.with(|k| k.drivers.iter().flatten().any(|&(m, _)| m == major))
+}
+
+/// Make `driver` answer the commands to `major`, replacing any driver that was
+/// installed there before.
+pub fn install(major: usize, driver: &'static mut dyn Driver) {
+    KERNEL.with(|k| {
+        let slot = match k.drivers.iter().position(|d| match d {
+            Some((m, _)) => *m == major,
+            None => false,
+        }) {
+            Some(i) => i,
+            None => k
+                .drivers
+                .iter()
+                .position(|d| d.is_none())
+                .expect("host kernel out of driver slots"),
+        };
+
+        k.drivers[slot] = Some((major, driver));
+    })
+}
+
+/// Forget every driver, upcall, buffer and scheduled upcall.
+pub fn reset() {
+    KERNEL.with(|k| {
+        k.drivers = [None; MAX_DRIVERS];
+        k.upcalls = [None; MAX_SLOTS];
+        k.buffers = [None; MAX_SLOTS];
+        k.scheduled = [None; MAX_SLOTS];
+    })
+}
+
+/// Run the upcall subscribed to `major`/`minor` on a later yield. Upcalls run
+/// in the order they were scheduled, and are dropped if nothing is subscribed
+/// by then.
+pub fn schedule(major: usize, minor: usize, arg0: usize, arg1: usize, arg2: usize) {
+    KERNEL.with(|k| {
+        let free = k
+            .scheduled
+            .iter_mut()
+            .find(|s| s.is_none())
+            .expect("host kernel out of scheduled upcalls");
+
+        *free = Some(Scheduled {
+            major,
+            minor,
+            args: [arg0, arg1, arg2],
+        });
+    })
+}
+
+/// Number of upcalls scheduled and not run yet.
+pub fn scheduled() -> usize {
+    KERNEL.with(|k| k.scheduled.iter().flatten().count())
+}
+
+/// The read-write buffer allowed to `major`/`minor`, if any.
+///
+/// # Safety
+///
+/// The app owns the memory, so the slice must not be kept past the point where
+/// the app takes the buffer back.
+pub unsafe fn buffer(major: usize, minor: usize) -> Option<&'static mut [u8]> {
+    allowed(major, minor, false)
+}
+
+/// The read-only buffer allowed to `major`/`minor`, if any. Under the Tock 1.x
+/// ABI every buffer is read-write, so this is the one `buffer` returns.
+///
+/// # Safety
+///
+/// As for `buffer`.
+pub unsafe fn readonly_buffer(major: usize, minor: usize) -> Option<&'static [u8]> {
+    allowed(major, minor, cfg!(feature = "tock2")).map(|b| &*b)
+}
+
+unsafe fn allowed(major: usize, minor: usize, readonly: bool) -> Option<&'static mut [u8]> {
+    match KERNEL.with(|k| find(&k.buffers, major, minor, readonly)) {
+        Some(Slot {
+            values: (ptr, len), ..
+        }) if ptr != 0 => Some(core::slice::from_raw_parts_mut(ptr as *mut u8, len)),
+        _ => None,
+    }
+}
+
+// Run the oldest scheduled upcall that something is subscribed to. Returns
+// `false` if there was none.
+pub(crate) fn run_upcall() -> bool {
+    loop {
+        let next = KERNEL.with(|k| {
+            let scheduled = k.scheduled[0].take()?;
+            k.scheduled.rotate_left(1);
+
+            Some((
+                find(&k.upcalls, scheduled.major, scheduled.minor, false),
+                scheduled.args,
+            ))
+        });
+
+        match next {
+            None => return false,
+            Some((Some(upcall), args)) if upcall.values.0 != 0 => {
+                let (callback, userdata) = upcall.values;
+                let callback: unsafe extern "C" fn(usize, usize, usize, usize) =
+                    unsafe { mem::transmute(callback) };
+
+                unsafe { callback(args[0], args[1], args[2], userdata) };
+                return true;
+            }
+            Some(_) => continue,
+        }
+    }
+}
+
+pub(crate) fn swap_upcall(
+    major: usize,
+    minor: usize,
+    callback: usize,
+    userdata: usize,
+) -> Result<(usize, usize), Error> {
+    if !is_installed(major) {
+        return Err(Error::ENODEVICE);
+    }
+
+    Ok(KERNEL.with(|k| swap(&mut k.upcalls, major, minor, false, (callback, userdata))))
+}
+
+pub(crate) fn swap_buffer(
+    major: usize,
+    minor: usize,
+    readonly: bool,
+    ptr: usize,
+    len: usize,
+) -> Result<(usize, usize), Error> {
+    if !is_installed(major) {
+        return Err(Error::ENODEVICE);
+    }
+
+    Ok(KERNEL.with(|k| swap(&mut k.buffers, major, minor, readonly, (ptr, len))))
+}
+
+pub(crate) fn call_command(major: usize, minor: usize, arg1: usize, arg2: usize) -> isize {
+    let driver = KERNEL.with(|k| {
+        k.drivers
+            .iter()
+            .flatten()
+            .find(|&&(m, _)| m == major)
+            .map(|&(_, d)| d)
+    });
+
+    match driver {
+        // The driver may schedule upcalls, so the kernel is not borrowed here
+        Some(driver) => unsafe { (*driver).command(minor, arg1, arg2) },
+        None => Error::ENODEVICE as isize,
+    }
+}
+
+// Tock 1.x shaped syscalls, see `syscalls::arm`. `tock2::host` has the Tock
+// 2.0 ones.
+
+pub(crate) fn yieldk() {
+    if !run_upcall() {
+        panic!("yield with no upcall scheduled, the app would hang");
+    }
+}
+
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn subscribe(
+    major: usize,
+    minor: usize,
+    callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
+    userdata: usize,
+) -> isize {
+    match swap_upcall(major, minor, callback as usize, userdata) {
+        Ok(_) => 0,
+        Err(e) => e as isize,
+    }
+}
+
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn command(major: usize, minor: usize, arg1: usize, arg2: usize) -> isize {
+    call_command(major, minor, arg1, arg2)
+}
+
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn allow(major: usize, minor: usize, ptr: *mut u8, len: usize) -> isize {
+    match swap_buffer(major, minor, false, ptr as usize, len) {
+        Ok(_) => 0,
+        Err(e) => e as isize,
+    }
+}
+
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn memop(_major: u32, _arg1: usize) -> isize {
+    0
+}
+
+// Serializes the tests that go through the host kernel or the driver statics,
+// and resets the kernel for them.
+#[cfg(test)]
+pub(crate) fn lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    reset();
+
+    guard
+}
+
+// A minimal executor for tests: polls `future` whenever it has been woken, and
+// yields otherwise. A future that is never woken ends up yielding with nothing
+// scheduled, which panics.
+#[cfg(test)]
+pub(crate) fn run<F: core::future::Future>(future: F) -> F::Output {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = Arc::new(Flag(AtomicBool::new(true)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+
+    pin_utils::pin_mut!(future);
+
+    loop {
+        if flag.0.swap(false, Ordering::SeqCst) {
+            if let Poll::Ready(val) = future.as_mut().poll(&mut context) {
+                return val;
+            }
+        } else {
+            crate::syscalls::yieldk();
+        }
+    }
+}
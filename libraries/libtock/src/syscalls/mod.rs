@@ -0,0 +1,359 @@
+use crate::result::Result;
+
+#[cfg(all(target_arch = "arm", not(feature = "tock2")))]
+mod arm;
+#[cfg(all(target_arch = "arm", not(feature = "tock2")))]
+use self::arm as platform;
+
+#[cfg(all(target_arch = "riscv32", not(feature = "tock2")))]
+mod riscv32;
+#[cfg(all(target_arch = "riscv32", not(feature = "tock2")))]
+use self::riscv32 as platform;
+
+// Fake kernel for running the drivers on the host, see `host`
+#[cfg(not(any(target_arch = "arm", target_arch = "riscv32")))]
+pub mod host;
+#[cfg(all(
+    not(any(target_arch = "arm", target_arch = "riscv32")),
+    not(feature = "tock2")
+))]
+use self::host as platform;
+
+// Tock 2.0 ABI, selected by the `tock2` feature. The functions below keep the
+// Tock 1.x shape for the drivers; `tock2` exposes the typed returns, upcall
+// and buffer swapping, and `exit`.
+#[cfg(feature = "tock2")]
+pub mod tock2;
+
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(feature = "trace")]
+use self::trace::Event;
+
+// Some drivers might pass error via a callback in `arg0`. If the driver wants
+// to be cheeky, it can also use `arg1` or `arg2`. So even though its `usize` at
+// type level, but in reality it would be carrying a negative `isize` value. In
+// such a scenario use `result::UsizeError`
+//
+// We get around a simliar issue in `subscribe`, `command`, `allow` and `memop`
+// by doing implicit type conversion from usize to size in the platform `asm!`
+// blocks.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CallbackData {
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    userdata: usize,
+}
+
+#[allow(dead_code)]
+impl CallbackData {
+    pub fn new(arg0: usize, arg1: usize, arg2: usize, userdata: usize) -> CallbackData {
+        CallbackData {
+            arg0,
+            arg1,
+            arg2,
+            userdata,
+        }
+    }
+
+    pub fn get_arg0(&self) -> usize {
+        self.arg0
+    }
+
+    pub fn get_arg1(&self) -> usize {
+        self.arg1
+    }
+
+    pub fn get_arg2(&self) -> usize {
+        self.arg2
+    }
+
+    pub fn get_userdata(&self) -> usize {
+        self.userdata
+    }
+}
+
+#[cfg(not(feature = "tock2"))]
+pub fn yieldk() {
+    #[cfg(debug_assertions)]
+    crate::cell::check_yield();
+
+    platform::yieldk();
+
+    #[cfg(feature = "trace")]
+    trace::record(Event::Yield { count: 1 });
+}
+
+#[cfg(feature = "tock2")]
+pub fn yieldk() {
+    #[cfg(debug_assertions)]
+    crate::cell::check_yield();
+
+    tock2::yield_wait();
+
+    #[cfg(feature = "trace")]
+    trace::record(Event::Yield { count: 1 });
+}
+
+// Tock 1.x has no exit syscall, so we just keep yielding to the kernel.
+#[cfg(not(feature = "tock2"))]
+pub fn exit(_completion_code: usize) -> ! {
+    loop {
+        yieldk();
+    }
+}
+
+#[cfg(feature = "tock2")]
+pub fn exit(completion_code: usize) -> ! {
+    tock2::exit_terminate(completion_code)
+}
+
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn subscribe(
+    major: usize,
+    minor: usize,
+    callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
+    userdata: usize,
+) -> Result<usize> {
+    #[cfg(feature = "trace")]
+    let (traced_callback, traced_userdata) = (callback, userdata);
+    #[cfg(feature = "trace")]
+    let wrapped = trace::wrap_upcall(major, minor, callback, userdata);
+    #[cfg(feature = "trace")]
+    let (callback, userdata) = (wrapped.callback, wrapped.userdata);
+
+    let res = into_result(platform::subscribe(major, minor, callback, userdata));
+
+    #[cfg(feature = "trace")]
+    {
+        if res.is_ok() {
+            trace::commit_upcall(major, minor, traced_callback, traced_userdata, &wrapped);
+        }
+
+        trace::record(Event::Subscribe {
+            major,
+            minor,
+            callback: traced_callback as usize,
+            userdata: traced_userdata,
+            result: res,
+        });
+    }
+
+    res
+}
+
+// Returns the previously registered callback
+#[cfg(feature = "tock2")]
+pub(crate) unsafe fn subscribe(
+    major: usize,
+    minor: usize,
+    callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
+    userdata: usize,
+) -> Result<usize> {
+    #[cfg(feature = "trace")]
+    let (traced_callback, traced_userdata) = (callback, userdata);
+    #[cfg(feature = "trace")]
+    let wrapped = trace::wrap_upcall(major, minor, callback, userdata);
+    #[cfg(feature = "trace")]
+    let (callback, userdata) = (wrapped.callback, wrapped.userdata);
+
+    let res = tock2::subscribe(major, minor, callback, userdata);
+
+    // Looked up before the commit, which may reuse the slot of the previous
+    // subscription
+    #[cfg(feature = "trace")]
+    let res = res.map(|prev| {
+        let (callback, userdata) = trace::unwrap_upcall(prev.callback, prev.userdata);
+        tock2::Upcall { callback, userdata }
+    });
+
+    let res = res.map(|prev| prev.callback);
+
+    #[cfg(feature = "trace")]
+    {
+        if res.is_ok() {
+            trace::commit_upcall(major, minor, traced_callback, traced_userdata, &wrapped);
+        }
+
+        trace::record(Event::Subscribe {
+            major,
+            minor,
+            callback: traced_callback as usize,
+            userdata: traced_userdata,
+            result: res,
+        });
+    }
+
+    res
+}
+
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn command(
+    major: usize,
+    minor: usize,
+    arg1: usize,
+    arg2: usize,
+) -> Result<usize> {
+    let res = into_result(platform::command(major, minor, arg1, arg2));
+
+    #[cfg(feature = "trace")]
+    trace::record(Event::Command {
+        major,
+        minor,
+        arg1,
+        arg2,
+        result: res,
+    });
+
+    res
+}
+
+#[cfg(feature = "tock2")]
+pub(crate) unsafe fn command(
+    major: usize,
+    minor: usize,
+    arg1: usize,
+    arg2: usize,
+) -> Result<usize> {
+    let res = tock2::command(major, minor, arg1, arg2).into_result();
+
+    #[cfg(feature = "trace")]
+    trace::record(Event::Command {
+        major,
+        minor,
+        arg1,
+        arg2,
+        result: res,
+    });
+
+    res
+}
+
+// Buffer the kernel writes into. This is a read-write allow under Tock 2.0.
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn allow(major: usize, minor: usize, ptr: *mut u8, len: usize) -> Result<usize> {
+    let res = into_result(platform::allow(major, minor, ptr, len));
+
+    #[cfg(feature = "trace")]
+    trace::record(Event::Allow {
+        major,
+        minor,
+        ptr: ptr as usize,
+        len,
+        readonly: false,
+        result: res,
+    });
+
+    res
+}
+
+#[cfg(feature = "tock2")]
+pub(crate) unsafe fn allow(major: usize, minor: usize, ptr: *mut u8, len: usize) -> Result<usize> {
+    let res = tock2::allow_readwrite(major, minor, ptr, len).map(|prev| prev.len);
+
+    #[cfg(feature = "trace")]
+    trace::record(Event::Allow {
+        major,
+        minor,
+        ptr: ptr as usize,
+        len,
+        readonly: false,
+        result: res,
+    });
+
+    res
+}
+
+// Run `f` on a buffer that stays allowed while the kernel keeps filling it,
+// typically from the upcall that says it has. Under Tock 2.0 the app must not
+// touch a buffer the kernel holds, so it is taken back for `f` and allowed
+// again after.
+pub(crate) unsafe fn with_reallowed<R>(
+    major: usize,
+    minor: usize,
+    ptr: *mut u8,
+    len: usize,
+    f: impl FnOnce() -> R,
+) -> R {
+    let _ = allow(major, minor, core::ptr::null_mut(), 0);
+    let res = f();
+    let _ = allow(major, minor, ptr, len);
+
+    res
+}
+
+// Buffer the kernel only reads from. Tock 1.x has a single allow, so this is
+// the same as `allow` there.
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn allow_readonly(
+    major: usize,
+    minor: usize,
+    ptr: *const u8,
+    len: usize,
+) -> Result<usize> {
+    let res = into_result(platform::allow(major, minor, ptr as *mut u8, len));
+
+    #[cfg(feature = "trace")]
+    trace::record(Event::Allow {
+        major,
+        minor,
+        ptr: ptr as usize,
+        len,
+        readonly: true,
+        result: res,
+    });
+
+    res
+}
+
+#[cfg(feature = "tock2")]
+pub(crate) unsafe fn allow_readonly(
+    major: usize,
+    minor: usize,
+    ptr: *const u8,
+    len: usize,
+) -> Result<usize> {
+    let res = tock2::allow_readonly(major, minor, ptr, len).map(|prev| prev.len);
+
+    #[cfg(feature = "trace")]
+    trace::record(Event::Allow {
+        major,
+        minor,
+        ptr: ptr as usize,
+        len,
+        readonly: true,
+        result: res,
+    });
+
+    res
+}
+
+// Only the entry point uses memop, and there is none on the host
+#[cfg_attr(
+    not(any(target_arch = "arm", target_arch = "riscv32")),
+    allow(dead_code)
+)]
+#[cfg(not(feature = "tock2"))]
+pub(crate) unsafe fn memop(major: u32, arg1: usize) -> Result<usize> {
+    into_result(platform::memop(major, arg1))
+}
+
+// Only the entry point uses memop, and there is none on the host
+#[cfg_attr(
+    not(any(target_arch = "arm", target_arch = "riscv32")),
+    allow(dead_code)
+)]
+#[cfg(feature = "tock2")]
+pub(crate) unsafe fn memop(major: u32, arg1: usize) -> Result<usize> {
+    tock2::memop(major, arg1).into_result()
+}
+
+#[cfg(not(feature = "tock2"))]
+fn into_result(res: isize) -> Result<usize> {
+    if res < 0 {
+        Err(res.into())
+    } else {
+        Ok(res as usize)
+    }
+}
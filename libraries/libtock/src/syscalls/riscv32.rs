@@ -0,0 +1,103 @@
+// RISC-V (rv32imac) syscall backend. Syscalls are made using `ecall`, with the
+// syscall class in a0, arguments in a1-a4 and the return value in a0.
+
+use core::arch::asm;
+
+pub fn yieldk() {
+    // As on ARM, the kernel runs the callback in place of returning from
+    // `ecall`, and the callback is free to clobber any caller-saved register.
+    // So mark all of them as clobbered and let the compiler save whatever is
+    // live across the yield.
+    //
+    // According to the RISC-V calling convention, the caller-saved registers
+    // are ra (x1), t0-t6 (x5-x7, x28-x31) and a0-a7 (x10-x17).
+    unsafe {
+        asm!(
+            "li a0, 0",
+            "ecall",
+            out("ra") _,
+            out("t0") _,
+            out("t1") _,
+            out("t2") _,
+            out("a0") _,
+            out("a1") _,
+            out("a2") _,
+            out("a3") _,
+            out("a4") _,
+            out("a5") _,
+            out("a6") _,
+            out("a7") _,
+            out("t3") _,
+            out("t4") _,
+            out("t5") _,
+            out("t6") _,
+        );
+    }
+}
+
+pub unsafe fn subscribe(
+    major: usize,
+    minor: usize,
+    callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
+    userdata: usize,
+) -> isize {
+    let res: isize;
+
+    asm!(
+        "li a0, 1",
+        "ecall",
+        out("a0") res,
+        in("a1") major,
+        in("a2") minor,
+        in("a3") callback,
+        in("a4") userdata,
+    );
+
+    res
+}
+
+pub unsafe fn command(major: usize, minor: usize, arg1: usize, arg2: usize) -> isize {
+    let res: isize;
+
+    asm!(
+        "li a0, 2",
+        "ecall",
+        out("a0") res,
+        in("a1") major,
+        in("a2") minor,
+        in("a3") arg1,
+        in("a4") arg2,
+    );
+
+    res
+}
+
+pub unsafe fn allow(major: usize, minor: usize, ptr: *mut u8, len: usize) -> isize {
+    let res: isize;
+
+    asm!(
+        "li a0, 3",
+        "ecall",
+        out("a0") res,
+        in("a1") major,
+        in("a2") minor,
+        in("a3") ptr,
+        in("a4") len,
+    );
+
+    res
+}
+
+pub unsafe fn memop(major: u32, arg1: usize) -> isize {
+    let res: isize;
+
+    asm!(
+        "li a0, 4",
+        "ecall",
+        out("a0") res,
+        in("a1") major,
+        in("a2") arg1,
+    );
+
+    res
+}
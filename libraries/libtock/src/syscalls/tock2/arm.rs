@@ -0,0 +1,119 @@
+// ARM (Cortex-M) Tock 2.0 syscall backend. The syscall class is in the `svc`
+// immediate, arguments are in r0-r3 and the kernel returns up to four values in
+// r0-r3.
+
+use core::arch::asm;
+
+pub fn yield_wait() {
+    // See `syscalls::arm::yieldk` for why all caller-saved registers are
+    // clobbered.
+    unsafe {
+        asm!(
+            "svc 0",
+            inlateout("r0") 1usize => _,
+            out("r1") _,
+            out("r2") _,
+            out("r3") _,
+            out("r12") _,
+            out("lr") _,
+        );
+    }
+}
+
+pub fn yield_no_wait(flag: *mut u8) {
+    unsafe {
+        asm!(
+            "svc 0",
+            inlateout("r0") 0usize => _,
+            inlateout("r1") flag => _,
+            out("r2") _,
+            out("r3") _,
+            out("r12") _,
+            out("lr") _,
+        );
+    }
+}
+
+pub unsafe fn subscribe(r0: usize, r1: usize, r2: usize, r3: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "svc 1",
+        inlateout("r0") r0 => o0,
+        inlateout("r1") r1 => o1,
+        inlateout("r2") r2 => o2,
+        inlateout("r3") r3 => o3,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn command(r0: usize, r1: usize, r2: usize, r3: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "svc 2",
+        inlateout("r0") r0 => o0,
+        inlateout("r1") r1 => o1,
+        inlateout("r2") r2 => o2,
+        inlateout("r3") r3 => o3,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn allow_readwrite(r0: usize, r1: usize, r2: usize, r3: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "svc 3",
+        inlateout("r0") r0 => o0,
+        inlateout("r1") r1 => o1,
+        inlateout("r2") r2 => o2,
+        inlateout("r3") r3 => o3,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn allow_readonly(r0: usize, r1: usize, r2: usize, r3: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "svc 4",
+        inlateout("r0") r0 => o0,
+        inlateout("r1") r1 => o1,
+        inlateout("r2") r2 => o2,
+        inlateout("r3") r3 => o3,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn memop(r0: usize, r1: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "svc 5",
+        inlateout("r0") r0 => o0,
+        inlateout("r1") r1 => o1,
+        lateout("r2") o2,
+        lateout("r3") o3,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn exit(r0: usize, r1: usize) -> ! {
+    asm!(
+        "svc 6",
+        in("r0") r0,
+        in("r1") r1,
+    );
+
+    // `exit-terminate` never returns. `exit-restart` does not either, unless
+    // the kernel refused it, in which case there is nothing left to do.
+    loop {
+        yield_wait();
+    }
+}
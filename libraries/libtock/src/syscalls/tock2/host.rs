@@ -0,0 +1,55 @@
+// Tock 2.0 shaped syscalls on top of the host kernel in `syscalls::host`. The
+// fake drivers answer in the Tock 1.x shape, which is turned into a return
+// variant here.
+
+use super::return_variant;
+use crate::result::Error;
+use crate::syscalls::host;
+
+fn failure(e: Error) -> [usize; 4] {
+    [return_variant::FAILURE, -(e as isize) as usize, 0, 0]
+}
+
+fn swapped(res: Result<(usize, usize), Error>) -> [usize; 4] {
+    match res {
+        Ok((a, b)) => [return_variant::SUCCESS_U32_U32, a, b, 0],
+        Err(e) => failure(e),
+    }
+}
+
+pub fn yield_wait() {
+    host::yieldk()
+}
+
+pub fn yield_no_wait(flag: *mut u8) {
+    let ran = host::run_upcall();
+
+    unsafe { *flag = ran as u8 };
+}
+
+pub unsafe fn subscribe(r0: usize, r1: usize, r2: usize, r3: usize) -> [usize; 4] {
+    swapped(host::swap_upcall(r0, r1, r2, r3))
+}
+
+pub unsafe fn command(r0: usize, r1: usize, r2: usize, r3: usize) -> [usize; 4] {
+    match host::call_command(r0, r1, r2, r3) {
+        res if res < 0 => failure(Error::from(res)),
+        res => [return_variant::SUCCESS_U32, res as usize, 0, 0],
+    }
+}
+
+pub unsafe fn allow_readwrite(r0: usize, r1: usize, r2: usize, r3: usize) -> [usize; 4] {
+    swapped(host::swap_buffer(r0, r1, false, r2, r3))
+}
+
+pub unsafe fn allow_readonly(r0: usize, r1: usize, r2: usize, r3: usize) -> [usize; 4] {
+    swapped(host::swap_buffer(r0, r1, true, r2, r3))
+}
+
+pub unsafe fn memop(_r0: usize, _r1: usize) -> [usize; 4] {
+    [return_variant::SUCCESS, 0, 0, 0]
+}
+
+pub unsafe fn exit(r0: usize, r1: usize) -> ! {
+    panic!("exit {} with completion code {}", r0, r1)
+}
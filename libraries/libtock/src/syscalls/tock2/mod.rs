@@ -0,0 +1,249 @@
+use crate::result::{Error, Result};
+
+#[cfg(target_arch = "arm")]
+mod arm;
+#[cfg(target_arch = "arm")]
+use self::arm as platform;
+
+#[cfg(target_arch = "riscv32")]
+mod riscv32;
+#[cfg(target_arch = "riscv32")]
+use self::riscv32 as platform;
+
+#[cfg(not(any(target_arch = "arm", target_arch = "riscv32")))]
+mod host;
+#[cfg(not(any(target_arch = "arm", target_arch = "riscv32")))]
+use self::host as platform;
+
+// In Tock 2.0, every syscall other than `yield` and `exit` returns a variant
+// identifier in r0 (a0 on RISC-V), followed by up to three values. Failure
+// variants carry an error code in the first value.
+mod return_variant {
+    pub const FAILURE: usize = 0;
+    pub const FAILURE_U32: usize = 1;
+    pub const FAILURE_U32_U32: usize = 2;
+    pub const FAILURE_U64: usize = 3;
+    pub const SUCCESS: usize = 128;
+    pub const SUCCESS_U32: usize = 129;
+    pub const SUCCESS_U32_U32: usize = 130;
+    pub const SUCCESS_U64: usize = 131;
+    pub const SUCCESS_U32_U32_U32: usize = 132;
+    pub const SUCCESS_U64_U32: usize = 133;
+}
+
+mod exit_num {
+    pub const TERMINATE: usize = 0;
+    pub const RESTART: usize = 1;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SyscallReturn {
+    Failure(Error),
+    FailureU32(Error, u32),
+    FailureU32U32(Error, u32, u32),
+    FailureU64(Error, u64),
+    Success,
+    SuccessU32(u32),
+    SuccessU32U32(u32, u32),
+    SuccessU64(u64),
+    SuccessU32U32U32(u32, u32, u32),
+    SuccessU64U32(u64, u32),
+}
+
+impl SyscallReturn {
+    fn from_registers(r: [usize; 4]) -> SyscallReturn {
+        let u64_from = |lo: usize, hi: usize| (lo as u64) | ((hi as u64) << 32);
+
+        match r[0] {
+            return_variant::FAILURE => SyscallReturn::Failure(error_code(r[1])),
+            return_variant::FAILURE_U32 => SyscallReturn::FailureU32(error_code(r[1]), r[2] as u32),
+            return_variant::FAILURE_U32_U32 => {
+                SyscallReturn::FailureU32U32(error_code(r[1]), r[2] as u32, r[3] as u32)
+            }
+            return_variant::FAILURE_U64 => {
+                SyscallReturn::FailureU64(error_code(r[1]), u64_from(r[2], r[3]))
+            }
+            return_variant::SUCCESS => SyscallReturn::Success,
+            return_variant::SUCCESS_U32 => SyscallReturn::SuccessU32(r[1] as u32),
+            return_variant::SUCCESS_U32_U32 => {
+                SyscallReturn::SuccessU32U32(r[1] as u32, r[2] as u32)
+            }
+            return_variant::SUCCESS_U64 => SyscallReturn::SuccessU64(u64_from(r[1], r[2])),
+            return_variant::SUCCESS_U32_U32_U32 => {
+                SyscallReturn::SuccessU32U32U32(r[1] as u32, r[2] as u32, r[3] as u32)
+            }
+            return_variant::SUCCESS_U64_U32 => {
+                SyscallReturn::SuccessU64U32(u64_from(r[1], r[2]), r[3] as u32)
+            }
+            _ => SyscallReturn::Failure(Error::FAIL),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            SyscallReturn::Success
+                | SyscallReturn::SuccessU32(_)
+                | SyscallReturn::SuccessU32U32(_, _)
+                | SyscallReturn::SuccessU64(_)
+                | SyscallReturn::SuccessU32U32U32(_, _, _)
+                | SyscallReturn::SuccessU64U32(_, _)
+        )
+    }
+
+    // Collapses the return into the Tock 1.x shape used by the drivers: the
+    // error on failure, otherwise the first value (or zero when there is none).
+    pub fn into_result(self) -> Result<usize> {
+        match self {
+            SyscallReturn::Failure(e)
+            | SyscallReturn::FailureU32(e, _)
+            | SyscallReturn::FailureU32U32(e, _, _)
+            | SyscallReturn::FailureU64(e, _) => Err(e),
+            SyscallReturn::Success => Ok(0),
+            SyscallReturn::SuccessU32(v)
+            | SyscallReturn::SuccessU32U32(v, _)
+            | SyscallReturn::SuccessU32U32U32(v, _, _) => Ok(v as usize),
+            SyscallReturn::SuccessU64(v) | SyscallReturn::SuccessU64U32(v, _) => Ok(v as usize),
+        }
+    }
+}
+
+// Tock 2.0 error codes are the positive counterparts of the Tock 1.x return
+// codes.
+pub(crate) fn error_code(code: usize) -> Error {
+    match code {
+        1..=13 => Error::from(-(code as isize)),
+        _ => Error::FAIL,
+    }
+}
+
+/// The upcall that was registered before a `subscribe`. Tock 2.0 swaps upcalls
+/// rather than overwriting them, and hands the previous one back.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Upcall {
+    pub callback: usize,
+    pub userdata: usize,
+}
+
+/// The buffer that was shared with the kernel before an `allow`. Tock 2.0
+/// swaps buffers, and the previous buffer is only safe to touch again once it
+/// has been handed back.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AllowedBuffer {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+pub fn yield_wait() {
+    platform::yield_wait()
+}
+
+// Returns `true` if an upcall was run.
+pub fn yield_no_wait() -> bool {
+    let mut flag: u8 = 0;
+    platform::yield_no_wait(&mut flag as *mut u8);
+    flag != 0
+}
+
+/// Registers `callback` for `driver`/`subscribe_num`.
+///
+/// # Safety
+///
+/// The kernel calls `callback` with `userdata`, so both must stay valid until
+/// they are swapped out again.
+pub unsafe fn subscribe(
+    driver: usize,
+    subscribe_num: usize,
+    callback: *const unsafe extern "C" fn(usize, usize, usize, usize),
+    userdata: usize,
+) -> Result<Upcall> {
+    let r = platform::subscribe(driver, subscribe_num, callback as usize, userdata);
+
+    swapped(r).map(|(callback, userdata)| Upcall { callback, userdata })
+}
+
+/// Issues command `command_num` to `driver`.
+///
+/// # Safety
+///
+/// Commands can make the kernel act on buffers the app has allowed, so these
+/// must still be valid.
+pub unsafe fn command(
+    driver: usize,
+    command_num: usize,
+    arg1: usize,
+    arg2: usize,
+) -> SyscallReturn {
+    SyscallReturn::from_registers(platform::command(driver, command_num, arg1, arg2))
+}
+
+/// Shares `len` bytes at `ptr` with `driver`, which may write to them.
+///
+/// # Safety
+///
+/// The memory must stay valid, and must not be touched by the app, until the
+/// kernel hands it back from a later `allow_readwrite`.
+pub unsafe fn allow_readwrite(
+    driver: usize,
+    allow_num: usize,
+    ptr: *mut u8,
+    len: usize,
+) -> Result<AllowedBuffer> {
+    let r = platform::allow_readwrite(driver, allow_num, ptr as usize, len);
+
+    swapped(r).map(|(ptr, len)| AllowedBuffer {
+        ptr: ptr as *mut u8,
+        len,
+    })
+}
+
+/// Shares `len` bytes at `ptr` with `driver`, which may only read them.
+///
+/// # Safety
+///
+/// The memory must stay valid, and must not be written by the app, until the
+/// kernel hands it back from a later `allow_readonly`.
+pub unsafe fn allow_readonly(
+    driver: usize,
+    allow_num: usize,
+    ptr: *const u8,
+    len: usize,
+) -> Result<AllowedBuffer> {
+    let r = platform::allow_readonly(driver, allow_num, ptr as usize, len);
+
+    swapped(r).map(|(ptr, len)| AllowedBuffer {
+        ptr: ptr as *mut u8,
+        len,
+    })
+}
+
+/// Issues memory operation `op`.
+///
+/// # Safety
+///
+/// Operations such as moving the program break change which memory the app
+/// may use.
+pub unsafe fn memop(op: u32, arg1: usize) -> SyscallReturn {
+    SyscallReturn::from_registers(platform::memop(op as usize, arg1))
+}
+
+pub fn exit_terminate(completion_code: usize) -> ! {
+    unsafe { platform::exit(exit_num::TERMINATE, completion_code) }
+}
+
+pub fn exit_restart(completion_code: usize) -> ! {
+    unsafe { platform::exit(exit_num::RESTART, completion_code) }
+}
+
+// The two values handed back by `subscribe` and `allow`, zero if there are
+// none. They are pointers, so they are taken straight from the registers
+// rather than through the `u32` fields of `SyscallReturn`, which would cut them
+// short on the host.
+fn swapped(r: [usize; 4]) -> Result<(usize, usize)> {
+    match r[0] {
+        return_variant::SUCCESS_U32_U32 => Ok((r[1], r[2])),
+        _ => SyscallReturn::from_registers(r)
+            .into_result()
+            .map(|_| (0, 0)),
+    }
+}
@@ -0,0 +1,152 @@
+// RISC-V (rv32imac) Tock 2.0 syscall backend. The syscall class is in a4,
+// arguments are in a0-a3 and the kernel returns up to four values in a0-a3.
+
+use core::arch::asm;
+
+pub fn yield_wait() {
+    // See `syscalls::riscv32::yieldk` for why all caller-saved registers are
+    // clobbered.
+    unsafe {
+        asm!(
+            "li a4, 0",
+            "ecall",
+            inlateout("a0") 1usize => _,
+            out("a1") _,
+            out("ra") _,
+            out("t0") _,
+            out("t1") _,
+            out("t2") _,
+            out("a2") _,
+            out("a3") _,
+            out("a4") _,
+            out("a5") _,
+            out("a6") _,
+            out("a7") _,
+            out("t3") _,
+            out("t4") _,
+            out("t5") _,
+            out("t6") _,
+        );
+    }
+}
+
+pub fn yield_no_wait(flag: *mut u8) {
+    unsafe {
+        asm!(
+            "li a4, 0",
+            "ecall",
+            inlateout("a0") 0usize => _,
+            inlateout("a1") flag => _,
+            out("ra") _,
+            out("t0") _,
+            out("t1") _,
+            out("t2") _,
+            out("a2") _,
+            out("a3") _,
+            out("a4") _,
+            out("a5") _,
+            out("a6") _,
+            out("a7") _,
+            out("t3") _,
+            out("t4") _,
+            out("t5") _,
+            out("t6") _,
+        );
+    }
+}
+
+pub unsafe fn subscribe(a0: usize, a1: usize, a2: usize, a3: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "li a4, 1",
+        "ecall",
+        inlateout("a0") a0 => o0,
+        inlateout("a1") a1 => o1,
+        inlateout("a2") a2 => o2,
+        inlateout("a3") a3 => o3,
+        out("a4") _,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn command(a0: usize, a1: usize, a2: usize, a3: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "li a4, 2",
+        "ecall",
+        inlateout("a0") a0 => o0,
+        inlateout("a1") a1 => o1,
+        inlateout("a2") a2 => o2,
+        inlateout("a3") a3 => o3,
+        out("a4") _,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn allow_readwrite(a0: usize, a1: usize, a2: usize, a3: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "li a4, 3",
+        "ecall",
+        inlateout("a0") a0 => o0,
+        inlateout("a1") a1 => o1,
+        inlateout("a2") a2 => o2,
+        inlateout("a3") a3 => o3,
+        out("a4") _,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn allow_readonly(a0: usize, a1: usize, a2: usize, a3: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "li a4, 4",
+        "ecall",
+        inlateout("a0") a0 => o0,
+        inlateout("a1") a1 => o1,
+        inlateout("a2") a2 => o2,
+        inlateout("a3") a3 => o3,
+        out("a4") _,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn memop(a0: usize, a1: usize) -> [usize; 4] {
+    let (o0, o1, o2, o3): (usize, usize, usize, usize);
+
+    asm!(
+        "li a4, 5",
+        "ecall",
+        inlateout("a0") a0 => o0,
+        inlateout("a1") a1 => o1,
+        lateout("a2") o2,
+        lateout("a3") o3,
+        out("a4") _,
+    );
+
+    [o0, o1, o2, o3]
+}
+
+pub unsafe fn exit(a0: usize, a1: usize) -> ! {
+    asm!(
+        "li a4, 6",
+        "ecall",
+        in("a0") a0,
+        in("a1") a1,
+        out("a4") _,
+    );
+
+    // `exit-terminate` never returns. `exit-restart` does not either, unless
+    // the kernel refused it, in which case there is nothing left to do.
+    loop {
+        yield_wait();
+    }
+}
@@ -0,0 +1,387 @@
+// Syscall tracing, enabled by the `trace` feature. Every `subscribe`,
+// `command`, `allow` and `yield` made through this module is recorded along
+// with its arguments and result, and so is every upcall, into a ring that
+// keeps the last `TRACE_LEN` events. Runs of yields are folded into one event.
+//
+// `memop` is left out: the entry point calls it before .bss is zeroed, when
+// the ring cannot be touched yet.
+//
+// Upcalls are caught by subscribing a trampoline in place of the driver's
+// callback. The trampoline records the upcall and then calls the driver's
+// callback with its own userdata.
+
+use core::fmt::{self, Write};
+use core::mem;
+
+use super::CallbackData;
+use crate::cell::RacyCell;
+use crate::console_write::ConsoleWrite;
+use crate::result::Result;
+
+pub const TRACE_LEN: usize = 32;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Event {
+    Yield {
+        count: usize,
+    },
+    Subscribe {
+        major: usize,
+        minor: usize,
+        callback: usize,
+        userdata: usize,
+        result: Result<usize>,
+    },
+    Command {
+        major: usize,
+        minor: usize,
+        arg1: usize,
+        arg2: usize,
+        result: Result<usize>,
+    },
+    Allow {
+        major: usize,
+        minor: usize,
+        ptr: usize,
+        len: usize,
+        readonly: bool,
+        result: Result<usize>,
+    },
+    Upcall {
+        major: usize,
+        minor: usize,
+        data: CallbackData,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Yield { count } => write!(f, "yield x{}", count),
+            Event::Subscribe {
+                major,
+                minor,
+                callback,
+                userdata,
+                result,
+            } => write!(
+                f,
+                "subscribe({:#x}, {}, {:#x}, {:#x}) = {:?}",
+                major, minor, callback, userdata, result
+            ),
+            Event::Command {
+                major,
+                minor,
+                arg1,
+                arg2,
+                result,
+            } => write!(
+                f,
+                "command({:#x}, {}, {:#x}, {:#x}) = {:?}",
+                major, minor, arg1, arg2, result
+            ),
+            Event::Allow {
+                major,
+                minor,
+                ptr,
+                len,
+                readonly,
+                result,
+            } => write!(
+                f,
+                "{}({:#x}, {}, {:#x}, {}) = {:?}",
+                if *readonly { "allow_readonly" } else { "allow" },
+                major,
+                minor,
+                ptr,
+                len,
+                result
+            ),
+            Event::Upcall { major, minor, data } => write!(
+                f,
+                "upcall({:#x}, {}) <- ({:#x}, {:#x}, {:#x})",
+                major,
+                minor,
+                data.get_arg0(),
+                data.get_arg1(),
+                data.get_arg2()
+            ),
+        }
+    }
+}
+
+/// An event along with its position in the trace, counting from the first
+/// event recorded. Gaps in the numbering show where the ring overflowed.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Record {
+    seq: usize,
+    event: Event,
+}
+
+impl Record {
+    pub fn get_seq(&self) -> usize {
+        self.seq
+    }
+
+    pub fn get_event(&self) -> Event {
+        self.event
+    }
+}
+
+static TRACE_RING: RacyCell<[Option<Record>; TRACE_LEN]> = RacyCell::new([None; TRACE_LEN]);
+
+// Sequence number of the next event. The ring slot is `seq % TRACE_LEN`.
+static TRACE_SEQ: RacyCell<usize> = RacyCell::new(0);
+
+pub(super) fn record(event: Event) {
+    let seq = TRACE_SEQ.get();
+
+    TRACE_RING.with(|ring| {
+        // Fold into the previous yield
+        if let Event::Yield { .. } = event {
+            if seq > 0 {
+                if let Some(Record {
+                    event: Event::Yield { count },
+                    ..
+                }) = &mut ring[(seq - 1) % TRACE_LEN]
+                {
+                    *count += 1;
+                    return;
+                }
+            }
+        }
+
+        ring[seq % TRACE_LEN] = Some(Record { seq, event });
+        TRACE_SEQ.set(seq + 1);
+    });
+}
+
+type Callback = unsafe extern "C" fn(usize, usize, usize, usize);
+
+// The driver callback an upcall has to be forwarded to
+#[derive(Copy, Clone)]
+struct Subscription {
+    major: usize,
+    minor: usize,
+    callback: *const Callback,
+    userdata: usize,
+}
+
+// Subscriptions beyond this many are passed through untraced
+const MAX_SUBSCRIPTIONS: usize = 32;
+
+static TRACE_SUBSCRIPTIONS: RacyCell<[Option<Subscription>; MAX_SUBSCRIPTIONS]> =
+    RacyCell::new([None; MAX_SUBSCRIPTIONS]);
+
+/// What to subscribe with in place of the driver's callback
+pub(super) struct Wrapped {
+    pub(super) callback: *const Callback,
+    pub(super) userdata: usize,
+    // Slot the subscription goes into, `None` if it is not traced
+    index: Option<usize>,
+}
+
+// Pick the trampoline and a slot for the subscription. The table is left
+// alone until `commit_upcall`, as the kernel may still refuse it.
+pub(super) fn wrap_upcall(
+    major: usize,
+    minor: usize,
+    callback: *const Callback,
+    userdata: usize,
+) -> Wrapped {
+    let index = if callback.is_null() {
+        None
+    } else {
+        TRACE_SUBSCRIPTIONS.with(|subscriptions| {
+            find_subscription(subscriptions, major, minor)
+                .or_else(|| subscriptions.iter().position(|s| s.is_none()))
+        })
+    };
+
+    match index {
+        Some(index) => Wrapped {
+            callback: trace_upcall as *const Callback,
+            userdata: index,
+            index: Some(index),
+        },
+        // Unsubscribing, or out of slots
+        None => Wrapped {
+            callback,
+            userdata,
+            index: None,
+        },
+    }
+}
+
+// Called once the kernel has accepted `wrapped` in place of `callback`
+pub(super) fn commit_upcall(
+    major: usize,
+    minor: usize,
+    callback: *const Callback,
+    userdata: usize,
+    wrapped: &Wrapped,
+) {
+    TRACE_SUBSCRIPTIONS.with(|subscriptions| match wrapped.index {
+        Some(index) => {
+            subscriptions[index] = Some(Subscription {
+                major,
+                minor,
+                callback,
+                userdata,
+            })
+        }
+        None => {
+            if let Some(index) = find_subscription(subscriptions, major, minor) {
+                subscriptions[index] = None;
+            }
+        }
+    })
+}
+
+// The kernel hands the trampoline back as the previous callback. Turn it back
+// into the driver's callback and userdata.
+#[cfg(feature = "tock2")]
+pub(super) fn unwrap_upcall(callback: usize, userdata: usize) -> (usize, usize) {
+    if callback != trace_upcall as *const () as usize {
+        return (callback, userdata);
+    }
+
+    match TRACE_SUBSCRIPTIONS.with(|subscriptions| subscriptions.get(userdata).copied()) {
+        Some(Some(s)) => (s.callback as usize, s.userdata),
+        _ => (callback, userdata),
+    }
+}
+
+fn find_subscription(
+    subscriptions: &[Option<Subscription>],
+    major: usize,
+    minor: usize,
+) -> Option<usize> {
+    subscriptions.iter().position(|s| match s {
+        Some(s) => s.major == major && s.minor == minor,
+        None => false,
+    })
+}
+
+extern "C" fn trace_upcall(arg0: usize, arg1: usize, arg2: usize, index: usize) {
+    let s = match TRACE_SUBSCRIPTIONS.with(|subscriptions| subscriptions[index]) {
+        Some(s) => s,
+        None => return,
+    };
+
+    record(Event::Upcall {
+        major: s.major,
+        minor: s.minor,
+        data: CallbackData::new(arg0, arg1, arg2, s.userdata),
+    });
+
+    // Drivers pass their callback as a pointer to the function itself
+    unsafe {
+        let callback: Callback = mem::transmute(s.callback as usize);
+        callback(arg0, arg1, arg2, s.userdata);
+    }
+}
+
+/// Copy of the ring, oldest event first.
+pub struct Snapshot {
+    records: [Option<Record>; TRACE_LEN],
+}
+
+impl Snapshot {
+    pub fn iter(&self) -> impl Iterator<Item = &Record> {
+        self.records.iter().filter_map(|r| r.as_ref())
+    }
+}
+
+// One line per event, `strace` style
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for r in self.iter() {
+            writeln!(f, "{:>5} {}", r.seq, r.event)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Take a copy of the ring. Syscalls made after this, for instance to print the
+// snapshot, do not show up in it.
+pub fn snapshot() -> Snapshot {
+    let mut records = [None; TRACE_LEN];
+
+    let seq = TRACE_SEQ.get();
+    TRACE_RING.with(|ring| {
+        for (i, record) in records.iter_mut().enumerate() {
+            *record = ring[(seq + i) % TRACE_LEN];
+        }
+    });
+
+    Snapshot { records }
+}
+
+pub fn clear() {
+    TRACE_RING.set([None; TRACE_LEN]);
+    TRACE_SEQ.set(0);
+}
+
+// Print a snapshot of the ring over the console. Blocks until it is written.
+pub fn dump() -> fmt::Result {
+    write!(ConsoleWrite::new(), "{}", snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{self, host};
+
+    const DRIVER_NUM: usize = 0x99;
+
+    struct FakeDriver;
+
+    impl host::Driver for FakeDriver {
+        fn command(&mut self, _minor: usize, _arg1: usize, _arg2: usize) -> isize {
+            0
+        }
+    }
+
+    extern "C" fn callback(_arg0: usize, _arg1: usize, _arg2: usize, _userdata: usize) {}
+
+    fn traced(major: usize) -> bool {
+        TRACE_SUBSCRIPTIONS
+            .with(|subscriptions| find_subscription(subscriptions, major, 0))
+            .is_some()
+    }
+
+    #[test]
+    fn refused_subscribe_is_not_traced() {
+        let _lock = host::lock();
+
+        let res = unsafe { syscalls::subscribe(DRIVER_NUM, 0, callback as *const _, 1) };
+        assert!(res.is_err());
+        assert!(!traced(DRIVER_NUM));
+
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeDriver)));
+
+        let res = unsafe { syscalls::subscribe(DRIVER_NUM, 0, callback as *const _, 1) };
+        assert!(res.is_ok());
+        assert!(traced(DRIVER_NUM));
+
+        let _ = unsafe { syscalls::subscribe(DRIVER_NUM, 0, core::ptr::null(), 0) };
+        assert!(!traced(DRIVER_NUM));
+    }
+
+    // The kernel only ever sees the trampoline
+    #[cfg(feature = "tock2")]
+    #[test]
+    fn previous_callback_is_the_drivers() {
+        let _lock = host::lock();
+        host::install(DRIVER_NUM, Box::leak(Box::new(FakeDriver)));
+
+        unsafe {
+            let _ = syscalls::subscribe(DRIVER_NUM, 0, callback as *const _, 1);
+            let prev = syscalls::subscribe(DRIVER_NUM, 0, core::ptr::null(), 0);
+
+            assert_eq!(prev, Ok(callback as *const () as usize));
+        }
+    }
+}
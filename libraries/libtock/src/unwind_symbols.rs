@@ -6,6 +6,8 @@
 // functions; for example, the Linux Kernel does so in arch/arm/kernel/unwind.c.
 // We do so here as well. The addition of these symbols to libtock-rs was
 // discussed at https://groups.google.com/forum/#!topic/tock-dev/eov8fJmskLk.
+//
+// RISC-V does not use EABI, so this module is only built for ARM.
 #[no_mangle]
 pub extern "C" fn __aeabi_unwind_cpp_pr0() {}
 
@@ -1,4 +1,5 @@
-use alloc::alloc::{AllocErr, Layout};
+use allocator_api::AllocErr;
+use core::alloc::Layout;
 use core::mem::size_of;
 use core::ptr::NonNull;
 
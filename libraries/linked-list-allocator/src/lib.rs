@@ -1,5 +1,3 @@
-#![feature(const_fn)]
-#![feature(allocator_api)]
 #![no_std]
 
 #[cfg(test)]
@@ -13,8 +11,8 @@ extern crate alloc;
 
 extern crate allocator_api;
 
-use alloc::alloc::{Alloc, AllocErr, Layout};
-use core::alloc::GlobalAlloc;
+use allocator_api::AllocErr;
+use core::alloc::{GlobalAlloc, Layout};
 use core::mem;
 #[cfg(feature = "use_spin")]
 use core::ops::Deref;
@@ -145,16 +143,6 @@ impl Heap {
     }
 }
 
-unsafe impl Alloc for Heap {
-    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        self.allocate_first_fit(layout)
-    }
-
-    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        self.deallocate(ptr, layout)
-    }
-}
-
 unsafe impl allocator_api::Alloc for Heap {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, allocator_api::AllocErr> {
         self.allocator_api_allocate_first_fit(layout)